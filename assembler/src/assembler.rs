@@ -0,0 +1,230 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use super::code::{encode_c_command, SymbolTable};
+use super::command::{Command, Directive, Jump};
+use super::format::Format;
+use super::object::{Object, Relocation};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub words: Vec<u16>,
+    pub symbols: SymbolTable,
+}
+
+impl Image {
+    pub fn write(&self, format: Format) -> Vec<u8> {
+        format.write(&self.words)
+    }
+}
+
+/// Assembles commands one at a time.
+///
+/// Every `@symbol` is emitted as a placeholder word and patched in `finish`,
+/// once all labels are known. Symbols that are never defined become variables,
+/// allocated in order of first use just like `code::assemble` does.
+#[derive(Debug, Clone, Default)]
+pub struct Assembler {
+    words: Vec<u16>,
+    definitions: HashMap<String, u16>,
+    labels: Vec<String>,
+    reserved: HashSet<u16>,
+    references: Vec<String>,
+    referenced: HashSet<String>,
+    fixups: Vec<(usize, String)>,
+    jump_targets: HashSet<String>,
+    memory_operands: HashSet<String>,
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Address of the next instruction.
+    pub fn pc(&self) -> u16 {
+        self.words.len() as u16
+    }
+
+    fn define(&mut self, name: &str, value: u16) -> anyhow::Result<()> {
+        if self.definitions.insert(name.to_string(), value).is_some() {
+            anyhow::bail!("Duplicate symbol: {}", name);
+        }
+        Ok(())
+    }
+
+    pub fn define_label(&mut self, name: &str) -> anyhow::Result<()> {
+        self.define(name, self.pc())?;
+        self.labels.push(name.to_string());
+        Ok(())
+    }
+
+    pub fn push_command(&mut self, command: Command) -> anyhow::Result<()> {
+        let uses_memory = command.uses_memory();
+        match command {
+            Command::AImm(imm) => self.words.push(imm),
+            Command::ASymbol(symbol) => {
+                if self.referenced.insert(symbol.clone()) {
+                    self.references.push(symbol.clone());
+                }
+                self.fixups.push((self.words.len(), symbol));
+                self.words.push(0);
+            }
+            Command::C(dest, comp, jump) => {
+                if let Some((index, symbol)) = self.fixups.last() {
+                    if index + 1 == self.words.len() {
+                        if jump != Jump::None {
+                            self.jump_targets.insert(symbol.clone());
+                        }
+                        if uses_memory {
+                            self.memory_operands.insert(symbol.clone());
+                        }
+                    }
+                }
+                self.words.push(encode_c_command(dest, comp, jump))
+            }
+            Command::L(label) => self.define_label(&label)?,
+            Command::Directive(Directive::Equ(name, value)) => self.define(&name, value)?,
+            Command::Directive(Directive::Ram(name, address)) => {
+                self.define(&name, address)?;
+                self.reserved.insert(address);
+            }
+            Command::Directive(Directive::Org(address)) => {
+                if (address as usize) < self.words.len() {
+                    anyhow::bail!(
+                        ".org {} is behind the current address {}",
+                        address,
+                        self.words.len()
+                    );
+                }
+                self.words.resize(address as usize, 0);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> anyhow::Result<Image> {
+        let mut table = SymbolTable::predefined();
+        table.extend(self.definitions);
+
+        let mut variables = Vec::new();
+        let mut address = 0x10;
+        for symbol in self.references {
+            if table.contains_key(&symbol) {
+                continue;
+            }
+            while self.reserved.contains(&address) {
+                address += 1;
+            }
+            if address >= 0x4000 {
+                anyhow::bail!("Out of variable memory at {}", symbol);
+            }
+            table.insert(symbol.clone(), address);
+            variables.push(symbol);
+            address += 1;
+        }
+
+        let mut words = self.words;
+        for (index, symbol) in self.fixups {
+            words[index] = table[&symbol];
+        }
+        Ok(Image {
+            words,
+            symbols: SymbolTable::from_parts(table, self.labels, variables),
+        })
+    }
+
+    /// Finishes a relocatable object instead of an image.
+    ///
+    /// Labels are exported. Undefined symbols used as jump targets are
+    /// imported, `name.`-prefixed ones get a slot in the object's static
+    /// segment, and ones used to read or write `M` are common variables
+    /// shared by name. Any other undefined symbol only ever serves as an
+    /// address, as in `@Sys.halt; D=A`, so it is imported too and `link`
+    /// reports it if no object exports it.
+    pub fn finish_object(self, name: &str) -> anyhow::Result<Object> {
+        let labels = self.labels.iter().collect::<HashSet<_>>();
+        let predefined = SymbolTable::predefined();
+        let prefix = format!("{}.", name);
+
+        let mut words = self.words;
+        let mut relocations = Vec::new();
+        let mut statics: Vec<String> = Vec::new();
+        for (index, symbol) in self.fixups {
+            let relocation = if let Some(value) = self.definitions.get(&symbol) {
+                words[index] = *value;
+                if !labels.contains(&symbol) {
+                    continue;
+                }
+                Relocation::Code
+            } else if let Some(value) = predefined.get(&symbol) {
+                words[index] = *value;
+                continue;
+            } else if self.jump_targets.contains(&symbol) {
+                Relocation::Import(symbol)
+            } else if symbol.starts_with(&prefix) {
+                words[index] = match statics.iter().position(|s| *s == symbol) {
+                    Some(slot) => slot as u16,
+                    None => {
+                        statics.push(symbol);
+                        statics.len() as u16 - 1
+                    }
+                };
+                Relocation::Static
+            } else if self.memory_operands.contains(&symbol) {
+                Relocation::Common(symbol)
+            } else {
+                Relocation::Import(symbol)
+            };
+            relocations.push((index as u16, relocation));
+        }
+
+        let exports = self
+            .labels
+            .iter()
+            .map(|label| (label.clone(), self.definitions[label]))
+            .collect::<BTreeMap<_, _>>();
+        Ok(Object {
+            name: name.to_string(),
+            code: words,
+            relocations,
+            exports,
+            statics,
+            reserved: self.reserved.into_iter().collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::code::{assemble_with_symbols, parse};
+
+    #[test]
+    fn test_matches_batch_assembler() {
+        let asm = ".ram buffer 17\n@i\nM=1\n(LOOP)\n@i\nD=M\n@END\nD;JGT\n@j\nM=D\n@LOOP\n0;JMP\n.org 16\n(END)\n@END\n0;JMP";
+        let lines = asm.lines().map(|s| s.to_string()).collect::<Vec<_>>();
+        let program = parse(&lines).unwrap();
+
+        let mut assembler = Assembler::new();
+        for command in program.iter() {
+            assembler.push_command(command.clone()).unwrap();
+        }
+        let image = assembler.finish().unwrap();
+        let (binary, symbols) = assemble_with_symbols(&program).unwrap();
+        assert_eq!(image.words, binary);
+        assert_eq!(image.symbols, symbols);
+        assert_eq!(image.symbols.get("j"), Some(18));
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut assembler = Assembler::new();
+        assembler.define_label("LOOP").unwrap();
+        assert!(assembler.define_label("LOOP").is_err());
+
+        assembler.push_command(Command::AImm(1)).unwrap();
+        assert!(assembler
+            .push_command(Command::Directive(Directive::Org(0)))
+            .is_err());
+    }
+}
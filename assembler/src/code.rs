@@ -1,14 +1,21 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 
-use super::command::{Command, Comp, Dest, Jump};
+use super::command::{Command, Comp, Dest, Dialect, Directive, Jump};
+use super::diagnostic::{Diagnostic, Diagnostics, Located, Span};
+use super::macros::{expand, is_builtin, SourceLine};
+use super::symbol_map::SymbolMap;
 
-struct SymbolTable {
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SymbolTable {
     table: HashMap<String, u16>,
+    labels: Vec<String>,
+    variables: Vec<String>,
 }
 
 impl SymbolTable {
-    pub fn new(program: &[Command]) -> Self {
-        let mut table = vec![
+    pub(crate) fn predefined() -> HashMap<String, u16> {
+        vec![
             ("SP".to_string(), 0),
             ("LCL".to_string(), 1),
             ("ARG".to_string(), 2),
@@ -32,31 +39,144 @@ impl SymbolTable {
             ("R15".to_string(), 15),
             ("SCREEN".to_string(), 16384),
             ("KBD".to_string(), 24576),
-        ].into_iter().collect::<HashMap::<_, _>>();
+        ].into_iter().collect::<HashMap::<_, _>>()
+    }
+
+    pub(crate) fn from_parts(
+        table: HashMap<String, u16>,
+        labels: Vec<String>,
+        variables: Vec<String>,
+    ) -> Self {
+        Self { table, labels, variables }
+    }
+
+    /// Resolves the symbols of a program, failing on the same problems as
+    /// `Assembler::finish`: a symbol defined twice, or more variables than
+    /// fit below the screen.
+    pub fn new(program: &[Command]) -> anyhow::Result<Self> {
+        let (table, errors) = Self::resolve(program);
+        match errors.into_iter().next() {
+            Some(error) => Err(anyhow::anyhow!("{}", error)),
+            None => Ok(table),
+        }
+    }
+
+    /// Resolves what it can, along with every problem found.
+    pub(crate) fn resolve(program: &[Command]) -> (Self, Vec<SymbolError>) {
+        let mut table = Self::predefined();
+        let mut definitions = HashMap::new();
+        let mut errors = Vec::new();
+        let mut labels = Vec::new();
+        let mut reserved = HashSet::new();
         let mut pc = 0;
-        for command in program.iter() {
-            match command {
+        for (index, command) in program.iter().enumerate() {
+            let (name, value) = match command {
                 Command::L(label) => {
-                    table.insert(label.clone(), pc);
-                },
-                _ => pc += 1,
+                    labels.push(label.clone());
+                    (label, pc)
+                }
+                Command::Directive(Directive::Equ(name, value)) => (name, *value),
+                Command::Directive(Directive::Ram(name, address)) => {
+                    reserved.insert(*address);
+                    (name, *address)
+                }
+                Command::Directive(Directive::Org(address)) => {
+                    pc = pc.max(*address);
+                    continue;
+                }
+                _ => {
+                    pc += 1;
+                    continue;
+                }
+            };
+            table.insert(name.clone(), value);
+            if let Some(first) = definitions.insert(name.clone(), index) {
+                errors.push(SymbolError::Duplicate {
+                    name: name.clone(),
+                    index,
+                    first,
+                });
             }
         }
+        let mut variables = Vec::new();
         let mut address = 0x10;
-        for command in program.iter() {
+        let mut overflowed = false;
+        for (index, command) in program.iter().enumerate() {
             if let Command::ASymbol(label) = command {
                 if !table.contains_key(label) {
+                    while reserved.contains(&address) {
+                        address += 1;
+                    }
+                    if address >= 0x4000 && !overflowed {
+                        overflowed = true;
+                        errors.push(SymbolError::OutOfMemory {
+                            name: label.clone(),
+                            index,
+                        });
+                    }
                     table.insert(label.clone(), address);
+                    variables.push(label.clone());
                     address += 1;
                 }
             }
         }
-        Self { table }
+        errors.sort_by_key(|error| error.index());
+        (Self { table, labels, variables }, errors)
     }
 
     pub fn get(&self, label: &str) -> Option<u16> {
         self.table.get(label).copied()
     }
+
+    pub fn labels(&self) -> impl Iterator<Item = (&str, u16)> {
+        self.labels.iter().map(|name| (name.as_str(), self.table[name]))
+    }
+
+    pub fn variables(&self) -> impl Iterator<Item = (&str, u16)> {
+        self.variables.iter().map(|name| (name.as_str(), self.table[name]))
+    }
+
+    pub fn to_symbol_map(&self) -> SymbolMap {
+        let mut map = SymbolMap::new();
+        for (name, address) in self.labels() {
+            map.insert_label(address, name.to_string());
+        }
+        map.variables
+            .extend(self.variables().map(|(name, address)| (address, name.to_string())));
+        map
+    }
+}
+
+/// A problem with the symbols of a program, at the command `index`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum SymbolError {
+    /// A symbol defined again after its definition at `first`.
+    Duplicate { name: String, index: usize, first: usize },
+    /// A variable past the last address below the screen.
+    OutOfMemory { name: String, index: usize },
+}
+
+impl SymbolError {
+    fn index(&self) -> usize {
+        match self {
+            SymbolError::Duplicate { index, .. } | SymbolError::OutOfMemory { index, .. } => *index,
+        }
+    }
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SymbolError::Duplicate { name, .. } => write!(f, "Duplicate symbol: {}", name),
+            SymbolError::OutOfMemory { name, .. } => {
+                write!(f, "Out of variable memory at {}", name)
+            }
+        }
+    }
+}
+
+pub(crate) fn encode_c_command(dest: Dest, comp: Comp, jump: Jump) -> u16 {
+    (comp.prefix() << 13) | (comp.assemble() << 6) | (dest.assemble() << 3) | jump.assemble()
 }
 
 fn assemble_command(command: &Command, symbol_table: &SymbolTable) -> anyhow::Result<Option<u16>> {
@@ -69,32 +189,390 @@ fn assemble_command(command: &Command, symbol_table: &SymbolTable) -> anyhow::Re
                 Err(anyhow::anyhow!("Undefined symbol: {}", symbol))
             }
         }
-        Command::C(dest, comp, jump) => {
-            let bits = 0xe000 | (comp.assemble() << 6) | (dest.assemble() << 3) | jump.assemble();
-            Ok(Some(bits))
-        }
+        Command::C(dest, comp, jump) => Ok(Some(encode_c_command(*dest, *comp, *jump))),
         _ => Ok(None),
     }
 }
 
+fn assemble_into(
+    command: &Command,
+    symbol_table: &SymbolTable,
+    result: &mut Vec<u16>,
+) -> anyhow::Result<()> {
+    if let Command::Directive(Directive::Org(address)) = command {
+        let address = *address as usize;
+        if address < result.len() {
+            anyhow::bail!(
+                ".org {} is behind the current address {}",
+                address,
+                result.len()
+            );
+        }
+        result.resize(address, 0);
+    } else if let Some(bits) = assemble_command(command, symbol_table)? {
+        result.push(bits);
+    }
+    Ok(())
+}
+
+pub fn parse_line(
+    line_no: usize,
+    line: &str,
+    dialect: Dialect,
+) -> Result<Option<Located<Command>>, Diagnostic> {
+    let code = line.find("//").map(|end| &line[..end]).unwrap_or(line);
+    let text = code.trim();
+    if text.is_empty() {
+        return Ok(None);
+    }
+    let col = code.len() - code.trim_start().len() + 1;
+    let span = Span::new(line_no, col, text.len());
+
+    if dialect == Dialect::Strict {
+        let name = text.split_whitespace().next().unwrap_or(text);
+        if text.starts_with('.') || is_builtin(name) {
+            return Err(Diagnostic::new(
+                format!("{} is not allowed in strict mode", name),
+                Span::new(line_no, col, name.len()),
+                line,
+            ));
+        }
+    }
+
+    let command = Command::parse_spanned(text, dialect).map_err(|e| {
+        Diagnostic::new(e.message, Span::new(line_no, col + e.offset, e.len), line)
+    })?;
+    Ok(Some(Located::new(command, span)))
+}
+
+/// Parses every line it can, giving the commands that parsed along with a
+/// diagnostic for each line that did not.
+fn parse_source_lines_partial(
+    source_lines: &[SourceLine],
+    lines: &[String],
+    dialect: Dialect,
+) -> (Vec<Located<Command>>, Vec<Diagnostic>) {
+    let mut commands = Vec::new();
+    let mut diagnostics = Vec::new();
+    for source_line in source_lines.iter() {
+        let parsed = parse_line(source_line.line, &source_line.text, dialect);
+        match (parsed, source_line.expansion) {
+            (Ok(Some(command)), None) => commands.push(command),
+            (Ok(Some(command)), Some(span)) => commands.push(Located::new(command.item, span)),
+            (Ok(None), _) => {}
+            (Err(diagnostic), None) => diagnostics.push(diagnostic),
+            (Err(diagnostic), Some(span)) => diagnostics.push(Diagnostic::new(
+                format!("{} (in expansion of `{}`)", diagnostic.message, source_line.text.trim()),
+                span,
+                &lines[span.line - 1],
+            )),
+        }
+    }
+    (commands, diagnostics)
+}
+
+pub fn parse_source_lines(
+    source_lines: &[SourceLine],
+    lines: &[String],
+    dialect: Dialect,
+) -> Result<Vec<Located<Command>>, Vec<Diagnostic>> {
+    match parse_source_lines_partial(source_lines, lines, dialect) {
+        (commands, diagnostics) if diagnostics.is_empty() => Ok(commands),
+        (_, diagnostics) => Err(diagnostics),
+    }
+}
+
+pub fn parse_located(lines: &[String]) -> Result<Vec<Located<Command>>, Vec<Diagnostic>> {
+    parse_located_with(lines, Dialect::default())
+}
+
+/// Like `parse_located`, but accepts the given dialect. Macros are not
+/// expanded in strict mode.
+pub fn parse_located_with(
+    lines: &[String],
+    dialect: Dialect,
+) -> Result<Vec<Located<Command>>, Vec<Diagnostic>> {
+    match parse_located_partial(lines, dialect) {
+        (commands, diagnostics) if diagnostics.is_empty() => Ok(commands),
+        (_, diagnostics) => Err(diagnostics),
+    }
+}
+
+/// Like `parse_located_with`, but keeps the commands that parsed when some
+/// lines do not, so that later checks can still run over them.
+pub fn parse_located_partial(
+    lines: &[String],
+    dialect: Dialect,
+) -> (Vec<Located<Command>>, Vec<Diagnostic>) {
+    let source_lines = match dialect {
+        Dialect::Strict => lines
+            .iter()
+            .enumerate()
+            .map(|(i, text)| SourceLine {
+                text: text.clone(),
+                line: i + 1,
+                expansion: None,
+            })
+            .collect(),
+        _ => match expand(lines) {
+            Ok(source_lines) => source_lines,
+            Err(diagnostics) => return (Vec::new(), diagnostics),
+        },
+    };
+    parse_source_lines_partial(&source_lines, lines, dialect)
+}
+
 pub fn parse(lines: &[String]) -> anyhow::Result<Vec<Command>> {
-    lines.iter()
-        .filter(|line| {
-            let line = line.trim();
-            !line.is_empty() && !line.starts_with("//")
-        })
-        .map(|s| Command::parse(&s))
-        .collect::<anyhow::Result<Vec<_>>>()
+    parse_located(lines)
+        .map(|commands| commands.into_iter().map(|c| c.item).collect())
+        .map_err(|diagnostics| Diagnostics::new("<input>", diagnostics).into())
 }
 
 pub fn assemble(program: &[Command]) -> anyhow::Result<Vec<u16>> {
-    let symbol_table = SymbolTable::new(program);
+    assemble_with_symbols(program).map(|(binary, _)| binary)
+}
+
+pub fn assemble_with_symbols(program: &[Command]) -> anyhow::Result<(Vec<u16>, SymbolTable)> {
+    let symbol_table = SymbolTable::new(program)?;
 
     let mut result = Vec::new();
     for command in program {
-        if let Some(bits) = assemble_command(command, &symbol_table)? {
-            result.push(bits);
+        assemble_into(command, &symbol_table, &mut result)?;
+    }
+    Ok((result, symbol_table))
+}
+
+pub fn assemble_located(
+    program: &[Located<Command>],
+    lines: &[String],
+) -> Result<(Vec<u16>, SymbolTable), Vec<Diagnostic>> {
+    let source = |span: &Span| lines.get(span.line - 1).map(|s| s.as_str()).unwrap_or("");
+    let commands = program.iter().map(|c| c.item.clone()).collect::<Vec<_>>();
+    let (symbol_table, errors) = SymbolTable::resolve(&commands);
+
+    let mut diagnostics = errors
+        .iter()
+        .map(|error| {
+            let span = program[error.index()].span;
+            let message = match error {
+                SymbolError::Duplicate { first, .. } => format!(
+                    "{} (first defined at line {})",
+                    error,
+                    program[*first].span.line
+                ),
+                _ => error.to_string(),
+            };
+            Diagnostic::new(message, span, source(&span))
+        })
+        .collect::<Vec<_>>();
+
+    let mut result = Vec::new();
+    for command in program.iter() {
+        match assemble_into(&command.item, &symbol_table, &mut result) {
+            Ok(()) => {}
+            Err(e) => diagnostics.push(Diagnostic::new(
+                e.to_string(),
+                command.span,
+                source(&command.span),
+            )),
+        }
+    }
+
+    if diagnostics.is_empty() {
+        Ok((result, symbol_table))
+    } else {
+        Err(diagnostics)
+    }
+}
+
+/// Parses and assembles a file, reporting the syntax errors together with
+/// the errors found in the lines that did parse, such as duplicate labels.
+pub fn assemble_source(filename: &str, source: &str) -> Result<Vec<u16>, Diagnostics> {
+    let lines = source.lines().map(|s| s.to_string()).collect::<Vec<_>>();
+    let (program, mut diagnostics) = parse_located_partial(&lines, Dialect::default());
+    match assemble_located(&program, &lines) {
+        Ok((binary, _)) if diagnostics.is_empty() => return Ok(binary),
+        Ok(_) => {}
+        Err(errors) => diagnostics.extend(errors),
+    }
+    diagnostics.sort_by_key(|diagnostic| (diagnostic.span.line, diagnostic.span.col));
+    Err(Diagnostics::new(filename, diagnostics))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_line() {
+        let command = parse_line(3, "  D=M+1;JGT // foo", Dialect::Standard).unwrap().unwrap();
+        assert_eq!(command.item, Command::C(Dest::D, Comp::M_PLUS_ONE, Jump::JGT));
+        assert_eq!(command.span, Span::new(3, 3, 9));
+
+        let command = parse_line(4, "@i // counter", Dialect::Standard).unwrap().unwrap();
+        assert_eq!(command.item, Command::ASymbol("i".to_string()));
+
+        assert_eq!(parse_line(5, "   // comment", Dialect::Standard).unwrap(), None);
+    }
+
+    #[test]
+    fn test_parse_line_errors() {
+        let err = parse_line(1, "  D=D+X", Dialect::Standard).unwrap_err();
+        assert_eq!(err.message, "invalid comp: D+X");
+        assert_eq!(err.span, Span::new(1, 5, 3));
+
+        let err = parse_line(2, "X=1", Dialect::Standard).unwrap_err();
+        assert_eq!(err.span, Span::new(2, 1, 1));
+
+        let err = parse_line(3, "0;JMPP", Dialect::Standard).unwrap_err();
+        assert_eq!(err.span, Span::new(3, 3, 4));
+
+        let err = parse_line(4, "@40000", Dialect::Standard).unwrap_err();
+        assert_eq!(err.span, Span::new(4, 2, 5));
+    }
+
+    #[test]
+    fn test_assemble_source_reports_all_errors() {
+        let source = "@1\nD=D+X\n(LOOP)\n(LOOP)\nA=Q";
+        let err = assemble_source("Test.asm", source).unwrap_err();
+        assert_eq!(err.items.len(), 3);
+        assert_eq!(err.items[0].span.line, 2);
+        assert_eq!(err.items[1].span.line, 4);
+        assert!(err.items[1].message.starts_with("Duplicate symbol: LOOP"));
+        assert_eq!(err.items[2].span.line, 5);
+
+        let source = "(LOOP)\n@LOOP\n(LOOP)";
+        let err = assemble_source("Test.asm", source).unwrap_err();
+        assert_eq!(err.items.len(), 1);
+        assert_eq!(err.items[0].span.line, 3);
+    }
+
+    #[test]
+    fn test_matches_streaming_assembler() {
+        let streamed = |program: &[Command]| {
+            let mut assembler = crate::assembler::Assembler::new();
+            for command in program.iter() {
+                assembler.push_command(command.clone())?;
+            }
+            assembler.finish().map(|image| image.words)
+        };
+        let lines = ["(LOOP)", "@LOOP", ".equ LOOP 3"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+        let duplicate = parse(&lines).unwrap();
+        let mut many = (0..0x3ff0)
+            .map(|i| Command::ASymbol(format!("v{}", i)))
+            .collect::<Vec<_>>();
+        assert_eq!(assemble(&many).unwrap(), streamed(&many).unwrap());
+        many.push(Command::ASymbol("last".to_string()));
+
+        for program in [&duplicate, &many] {
+            let batch = assemble(program).unwrap_err().to_string();
+            assert_eq!(batch, streamed(program).unwrap_err().to_string());
         }
+        let err = assemble_located(&parse_located(&lines).unwrap(), &lines).unwrap_err();
+        assert_eq!(err[0].message, "Duplicate symbol: LOOP (first defined at line 1)");
+    }
+
+    #[test]
+    fn test_assemble_with_symbols() {
+        let lines = ["@i", "M=0", "(LOOP)", "@LOOP", "0;JMP", "@SP", "@j"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+        let (_, symbols) = assemble_with_symbols(&parse(&lines).unwrap()).unwrap();
+        assert_eq!(symbols.labels().collect::<Vec<_>>(), vec![("LOOP", 2)]);
+        assert_eq!(
+            symbols.variables().collect::<Vec<_>>(),
+            vec![("i", 16), ("j", 17)]
+        );
+        assert_eq!(symbols.to_symbol_map().label(2), Some("LOOP"));
+
+        let lines = ["(Main.main)", "(Main.main$LOOP)", "@Main.main$LOOP", "0;JMP"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+        let (_, symbols) = assemble_with_symbols(&parse(&lines).unwrap()).unwrap();
+        let map = symbols.to_symbol_map();
+        assert_eq!(map.labels_at(0), ["Main.main", "Main.main$LOOP"]);
+        assert_eq!(SymbolMap::parse(&map.dump()).unwrap(), map);
     }
-    Ok(result)
-}
\ No newline at end of file
+
+    #[test]
+    fn test_parse_located_with_macros() {
+        let lines = [".macro BAD", "D=D+X", ".endm", "@1", "  BAD", "SET R13, 7"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+        let err = parse_located(&lines).unwrap_err();
+        assert_eq!(err.len(), 1);
+        assert_eq!(err[0].span, Span::new(5, 3, 3));
+        assert_eq!(err[0].source, "  BAD");
+
+        let program = parse_located(&lines[5..]).unwrap();
+        assert_eq!(program.len(), 4);
+        assert!(program.iter().all(|c| c.span == Span::new(1, 1, 10)));
+    }
+
+    #[test]
+    fn test_directives() {
+        let lines = [
+            ".equ WIDTH 32",
+            ".ram cursor 16",
+            ".data 0x100 7, -1, 0",
+            "@WIDTH",
+            "D=A",
+            "@cursor",
+            "M=D",
+            "@i",
+            "M=0",
+            ".org 20",
+            "(END)",
+            "@END",
+            "0;JMP",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect::<Vec<_>>();
+        let program = parse_located(&lines).unwrap();
+        let (binary, symbols) = assemble_located(&program, &lines).unwrap();
+        assert_eq!(&binary[..4], &[7, 0xec10, 0x100, 0xe308]);
+        assert_eq!(binary[8], 32);
+        assert_eq!(binary[10], 16);
+        assert_eq!(binary[12], 17);
+        assert_eq!(symbols.get("i"), Some(17));
+        assert_eq!(binary.len(), 22);
+        assert_eq!(binary[19], 0);
+        assert_eq!(binary[20], 20);
+
+        let lines = ["@1", "@2", ".org 1", ".equ 9x 1"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+        let err = parse_located(&lines).unwrap_err();
+        assert_eq!(err[0].span.line, 4);
+        let err = assemble_located(&parse_located(&lines[..3]).unwrap(), &lines).unwrap_err();
+        assert_eq!(err[0].span.line, 3);
+    }
+
+    #[test]
+    fn test_dialects() {
+        let lines = ["D=D<<", "M=!(!D+!M);JGT", "A=0b0000001", ".equ X 1"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+        let err = parse_located(&lines).unwrap_err();
+        assert_eq!(err.len(), 3);
+
+        let program = parse_located_with(&lines, Dialect::Extended).unwrap();
+        let (binary, _) = assemble_located(&program, &lines).unwrap();
+        assert_eq!(binary, vec![0xac10, 0xf5c9, 0xe060]);
+
+        let err = parse_located_with(&lines[3..], Dialect::Strict).unwrap_err();
+        assert_eq!(err[0].message, ".equ is not allowed in strict mode");
+        let lines = ["  PUSHD".to_string()];
+        let err = parse_located_with(&lines, Dialect::Strict).unwrap_err();
+        assert_eq!(err[0].span, Span::new(1, 3, 5));
+    }
+}
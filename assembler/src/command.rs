@@ -1,4 +1,5 @@
 use std::any;
+use std::fmt;
 
 use anyhow;
 
@@ -51,43 +52,177 @@ impl Dest {
     pub fn assemble(self) -> u16 {
         self as u16
     }
+
+    pub fn dump(self) -> &'static str {
+        match self {
+            Dest::None => "",
+            Dest::M => "M",
+            Dest::D => "D",
+            Dest::MD => "MD",
+            Dest::A => "A",
+            Dest::AM => "AM",
+            Dest::AD => "AD",
+            Dest::AMD => "AMD",
+        }
+    }
+}
+
+/// Which flavour of Hack assembly is accepted.
+///
+/// `Strict` is the language from the book with no assembler extensions,
+/// `Standard` adds directives and macros, and `Extended` additionally accepts
+/// shift instructions and every ALU control bit combination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Dialect {
+    Strict,
+    #[default]
+    Standard,
+    Extended,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Comp {
-    ZERO = 0,
-    ONE = 1,
-    MINUS_ONE = 2,
-    D = 3,
-    A = 4,
-    INV_D = 5,
-    INV_A = 6,
-    MINUS_D = 7,
-    MINUS_A = 8,
-    D_PLUS_ONE = 9,
-    A_PLUS_ONE = 10,
-    D_MINUS_ONE = 11,
-    A_MINUS_ONE = 12,
-    D_PLUS_A = 13,
-    D_MINUS_A = 14,
-    A_MINUS_D = 15,
-    D_AND_A = 16,
-    D_OR_A = 17,
-    M = 18,
-    INV_M = 19,
-    MINUS_M = 20,
-    M_PLUS_ONE = 21,
-    M_MINUS_ONE = 22,
-    D_PLUS_M = 23,
-    D_MINUS_M = 24,
-    M_MINUS_D = 25,
-    D_AND_M = 26,
-    D_OR_M = 27,
+    ZERO,
+    ONE,
+    MINUS_ONE,
+    D,
+    A,
+    INV_D,
+    INV_A,
+    MINUS_D,
+    MINUS_A,
+    D_PLUS_ONE,
+    A_PLUS_ONE,
+    D_MINUS_ONE,
+    A_MINUS_ONE,
+    D_PLUS_A,
+    D_MINUS_A,
+    A_MINUS_D,
+    D_AND_A,
+    D_OR_A,
+    M,
+    INV_M,
+    MINUS_M,
+    M_PLUS_ONE,
+    M_MINUS_ONE,
+    D_PLUS_M,
+    D_MINUS_M,
+    M_MINUS_D,
+    D_AND_M,
+    D_OR_M,
+    D_SHL,
+    D_SHR,
+    A_SHL,
+    A_SHR,
+    M_SHL,
+    M_SHR,
+    Raw(u8),
 }
 
 impl Comp {
+    pub fn from(bits: u16) -> anyhow::Result<Self> {
+        match bits {
+            0x002a => Ok(Comp::ZERO),
+            0x003f => Ok(Comp::ONE),
+            0x003a => Ok(Comp::MINUS_ONE),
+            0x000c => Ok(Comp::D),
+            0x0030 => Ok(Comp::A),
+            0x000d => Ok(Comp::INV_D),
+            0x0031 => Ok(Comp::INV_A),
+            0x000f => Ok(Comp::MINUS_D),
+            0x0033 => Ok(Comp::MINUS_A),
+            0x001f => Ok(Comp::D_PLUS_ONE),
+            0x0037 => Ok(Comp::A_PLUS_ONE),
+            0x000e => Ok(Comp::D_MINUS_ONE),
+            0x0032 => Ok(Comp::A_MINUS_ONE),
+            0x0002 => Ok(Comp::D_PLUS_A),
+            0x0013 => Ok(Comp::D_MINUS_A),
+            0x0007 => Ok(Comp::A_MINUS_D),
+            0x0000 => Ok(Comp::D_AND_A),
+            0x0015 => Ok(Comp::D_OR_A),
+            0x0070 => Ok(Comp::M),
+            0x0071 => Ok(Comp::INV_M),
+            0x0073 => Ok(Comp::MINUS_M),
+            0x0077 => Ok(Comp::M_PLUS_ONE),
+            0x0072 => Ok(Comp::M_MINUS_ONE),
+            0x0042 => Ok(Comp::D_PLUS_M),
+            0x0053 => Ok(Comp::D_MINUS_M),
+            0x0047 => Ok(Comp::M_MINUS_D),
+            0x0040 => Ok(Comp::D_AND_M),
+            0x0055 => Ok(Comp::D_OR_M),
+            _ => Err(anyhow::anyhow!("Invalid comp bits: {:07b}", bits)),
+        }
+    }
+
+    /// Decodes the comp field of a C instruction whose top three bits are `prefix`,
+    /// accepting the shift extension and non-standard ALU control bits.
+    pub fn from_extended(prefix: u16, bits: u16) -> anyhow::Result<Self> {
+        match prefix {
+            0b111 => Ok(Comp::from(bits).unwrap_or(Comp::Raw(bits as u8 & 0x7f))),
+            0b101 => match bits {
+                0x30 => Ok(Comp::D_SHL),
+                0x10 => Ok(Comp::D_SHR),
+                0x20 => Ok(Comp::A_SHL),
+                0x00 => Ok(Comp::A_SHR),
+                0x60 => Ok(Comp::M_SHL),
+                0x40 => Ok(Comp::M_SHR),
+                _ => Err(anyhow::anyhow!("Invalid shift bits: {:07b}", bits)),
+            },
+            _ => Err(anyhow::anyhow!("Invalid C command prefix: {:03b}", prefix)),
+        }
+    }
+
+    /// Parses a comp accepted by the extended instruction set: the standard
+    /// mnemonics, `D<<`-style shifts, raw `0b` literals of the seven `a zx nx zy
+    /// ny f no` bits, and the names produced by `alu_name` and `alu_linear_name`.
+    pub fn parse_extended(s: &str) -> anyhow::Result<(Self, &str)> {
+        if let Ok(result) = Comp::parse(s) {
+            return Ok(result);
+        }
+        let s = s.strip_prefix('=').unwrap_or(s);
+
+        let terminals = vec![';', ' ', '\t', '/'];
+        let end = s.find(|c| terminals.contains(&c)).unwrap_or(s.len());
+        let (comp, s) = s.split_at(end);
+        let comp = match comp {
+            "D<<" => Comp::D_SHL,
+            "D>>" => Comp::D_SHR,
+            "A<<" => Comp::A_SHL,
+            "A>>" => Comp::A_SHR,
+            "M<<" => Comp::M_SHL,
+            "M>>" => Comp::M_SHR,
+            _ => match comp.strip_prefix("0b") {
+                Some(bits) if bits.len() == 7 => u16::from_str_radix(bits, 2)
+                    .map(|bits| Comp::from_extended(0b111, bits).unwrap())
+                    .map_err(|_| anyhow::anyhow!("invalid comp: {}", comp))?,
+                _ => (0..0x80u8)
+                    .find(|bits| {
+                        alu_name(*bits) == comp || alu_linear_name(*bits).as_deref() == Some(comp)
+                    })
+                    .map(|bits| Comp::from_extended(0b111, bits as u16).unwrap())
+                    .ok_or_else(|| anyhow::anyhow!("invalid comp: {}", comp))?,
+            },
+        };
+        Ok((comp, s))
+    }
+
+    pub fn is_standard(self) -> bool {
+        self.prefix() == 0b111 && !matches!(self, Comp::Raw(_))
+    }
+
+    /// The top three bits of the instruction: `111` for ALU operations and
+    /// `101` for the shift extension.
+    pub fn prefix(self) -> u16 {
+        match self {
+            Comp::D_SHL | Comp::D_SHR | Comp::A_SHL | Comp::A_SHR | Comp::M_SHL | Comp::M_SHR => {
+                0b101
+            }
+            _ => 0b111,
+        }
+    }
+
     pub fn parse(s: &str) -> anyhow::Result<(Self, &str)> {
-        let s = if s.starts_with('=') { &s[1..] } else { s };
+        let s = s.strip_prefix('=').unwrap_or(s);
 
         let terminals = vec![';', ' ', '\t', '/'];
         let end = s.find(|c| terminals.contains(&c)).unwrap_or(s.len());
@@ -155,8 +290,123 @@ impl Comp {
             Comp::M_MINUS_D => 0x0047,
             Comp::D_AND_M => 0x0040,
             Comp::D_OR_M => 0x0055,
+            Comp::D_SHL => 0x0030,
+            Comp::D_SHR => 0x0010,
+            Comp::A_SHL => 0x0020,
+            Comp::A_SHR => 0x0000,
+            Comp::M_SHL => 0x0060,
+            Comp::M_SHR => 0x0040,
+            Comp::Raw(bits) => bits as u16,
+        }
+    }
+
+    pub fn dump(self) -> String {
+        let name = match self {
+            Comp::ZERO => "0",
+            Comp::ONE => "1",
+            Comp::MINUS_ONE => "-1",
+            Comp::D => "D",
+            Comp::A => "A",
+            Comp::INV_D => "!D",
+            Comp::INV_A => "!A",
+            Comp::MINUS_D => "-D",
+            Comp::MINUS_A => "-A",
+            Comp::D_PLUS_ONE => "D+1",
+            Comp::A_PLUS_ONE => "A+1",
+            Comp::D_MINUS_ONE => "D-1",
+            Comp::A_MINUS_ONE => "A-1",
+            Comp::D_PLUS_A => "D+A",
+            Comp::D_MINUS_A => "D-A",
+            Comp::A_MINUS_D => "A-D",
+            Comp::D_AND_A => "D&A",
+            Comp::D_OR_A => "D|A",
+            Comp::M => "M",
+            Comp::INV_M => "!M",
+            Comp::MINUS_M => "-M",
+            Comp::M_PLUS_ONE => "M+1",
+            Comp::M_MINUS_ONE => "M-1",
+            Comp::D_PLUS_M => "D+M",
+            Comp::D_MINUS_M => "D-M",
+            Comp::M_MINUS_D => "M-D",
+            Comp::D_AND_M => "D&M",
+            Comp::D_OR_M => "D|M",
+            Comp::D_SHL => "D<<",
+            Comp::D_SHR => "D>>",
+            Comp::A_SHL => "A<<",
+            Comp::A_SHR => "A>>",
+            Comp::M_SHL => "M<<",
+            Comp::M_SHR => "M>>",
+            // With `zy` set the `a` bit is ignored, so only a literal keeps it.
+            Comp::Raw(bits) if bits & 0x48 == 0x48 => return format!("0b{:07b}", bits),
+            Comp::Raw(bits) => return alu_name(bits),
+        };
+        name.to_string()
+    }
+}
+
+/// Spells out the ALU function selected by `a zx nx zy ny f no`, e.g.
+/// `0b1010111` is `!(!D+!M)`. Patterns that only differ in an `a` bit the ALU
+/// ignores share a name.
+pub fn alu_name(bits: u8) -> String {
+    let y = if bits & 0x40 == 0 { "A" } else { "M" };
+    let x = match (bits >> 4) & 0x3 {
+        0b00 => "D".to_string(),
+        0b01 => "!D".to_string(),
+        0b10 => "0".to_string(),
+        _ => "-1".to_string(),
+    };
+    let y = match (bits >> 2) & 0x3 {
+        0b00 => y.to_string(),
+        0b01 => format!("!{}", y),
+        0b10 => "0".to_string(),
+        _ => "-1".to_string(),
+    };
+    let op = if bits & 0x2 == 0 { "&" } else { "+" };
+    if bits & 0x1 == 0 {
+        format!("{}{}{}", x, op, y)
+    } else {
+        format!("!({}{}{})", x, op, y)
+    }
+}
+
+/// Names an adding ALU function by the linear expression it computes, e.g.
+/// `0b1010111` is `D+M+1`. Bitwise functions have no linear name.
+pub fn alu_linear_name(bits: u8) -> Option<String> {
+    if bits & 0x2 == 0 {
+        return None;
+    }
+    let y = if bits & 0x40 == 0 { "A" } else { "M" };
+    let alu = |d: i16, y: i16| {
+        let x = if bits & 0x20 != 0 { 0 } else { d };
+        let x = if bits & 0x10 != 0 { !x } else { x };
+        let y = if bits & 0x08 != 0 { 0 } else { y };
+        let y = if bits & 0x04 != 0 { !y } else { y };
+        let out = x.wrapping_add(y);
+        if bits & 0x01 != 0 {
+            !out
+        } else {
+            out
         }
+    };
+    let k = alu(0, 0);
+    let terms = [(alu(1, 0) - k, "D"), (alu(0, 1) - k, y)];
+
+    let mut name = String::new();
+    for (coefficient, operand) in terms {
+        match coefficient {
+            0 => {}
+            1 if name.is_empty() => name += operand,
+            1 => name += &format!("+{}", operand),
+            _ => name += &format!("-{}", operand),
+        }
+    }
+    match k {
+        0 if name.is_empty() => name += "0",
+        0 => {}
+        k if k < 0 || name.is_empty() => name += &k.to_string(),
+        k => name += &format!("+{}", k),
     }
+    Some(name)
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -188,7 +438,7 @@ impl Jump {
 
     pub fn parse(s: &str) -> anyhow::Result<(Self, &str)> {
         let s = s.trim();
-        let s = if s.starts_with(';') { &s[1..] } else { s };
+        let s = s.strip_prefix(';').unwrap_or(s);
         let terminals = vec![' ', '\t', '/'];
         let end = s.find(|c| terminals.contains(&c)).unwrap_or(s.len());
 
@@ -209,6 +459,109 @@ impl Jump {
     pub fn assemble(self) -> u16 {
         self as u16
     }
+
+    pub fn dump(self) -> &'static str {
+        match self {
+            Jump::None => "",
+            Jump::JGT => "JGT",
+            Jump::JEQ => "JEQ",
+            Jump::JGE => "JGE",
+            Jump::JLT => "JLT",
+            Jump::JNE => "JNE",
+            Jump::JLE => "JLE",
+            Jump::JMP => "JMP",
+        }
+    }
+}
+
+pub fn parse_number(s: &str) -> anyhow::Result<i32> {
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, s),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i32::from_str_radix(hex, 16)
+    } else if let Some(bin) = digits.strip_prefix("0b") {
+        i32::from_str_radix(bin, 2)
+    } else {
+        digits.parse::<i32>()
+    }
+    .map_err(|_| anyhow::anyhow!("Invalid number: {}", s))?;
+    Ok(if negative { -value } else { value })
+}
+
+fn parse_address(s: &str) -> anyhow::Result<u16> {
+    match parse_number(s)? {
+        v @ 0..=0x7fff => Ok(v as u16),
+        v => anyhow::bail!("Address out of range: {}", v),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Directive {
+    Equ(String, u16),
+    Ram(String, u16),
+    Org(u16),
+}
+
+impl Directive {
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let s = s.trim();
+        let fields = s
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|f| !f.is_empty())
+            .collect::<Vec<_>>();
+        match fields.as_slice() {
+            [".equ", name, value] => Ok(Directive::Equ(name.to_string(), parse_address(value)?)),
+            [".ram", name, address] => {
+                Ok(Directive::Ram(name.to_string(), parse_address(address)?))
+            }
+            [".org", address] => Ok(Directive::Org(parse_address(address)?)),
+            _ => anyhow::bail!("Invalid directive: {}", s),
+        }
+    }
+
+    pub fn dump(&self) -> String {
+        match self {
+            Directive::Equ(name, value) => format!(".equ {} {}", name, value),
+            Directive::Ram(name, address) => format!(".ram {} {}", name, address),
+            Directive::Org(address) => format!(".org {}", address),
+        }
+    }
+}
+
+/// An error in part of a command: `len` bytes from `offset` in the text that
+/// was parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub message: String,
+    pub offset: usize,
+    pub len: usize,
+}
+
+impl ParseError {
+    fn new(message: String, offset: usize, len: usize) -> Self {
+        Self {
+            message,
+            offset,
+            len,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+pub(crate) fn is_symbol(s: &str) -> bool {
+    !s.is_empty()
+        && !s.starts_with(|c: char| c.is_ascii_digit())
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_.$:".contains(c))
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -217,48 +570,160 @@ pub enum Command {
     ASymbol(String),
     C(Dest, Comp, Jump),
     L(String),
+    Directive(Directive),
 }
 
 impl Command {
+    /// Whether this is a C command that reads or writes `M`, i.e. uses the
+    /// preceding A value as a RAM address.
+    pub fn uses_memory(&self) -> bool {
+        match self {
+            Command::C(dest, comp, _) => {
+                matches!(dest, Dest::M | Dest::MD | Dest::AM | Dest::AMD)
+                    || comp.assemble() & 0x40 != 0
+            }
+            _ => false,
+        }
+    }
+
     pub fn parse_a_command(s: &str) -> anyhow::Result<Self> {
         let s = s.trim();
-        if s.is_empty() || !s.starts_with("@") {
-            anyhow::bail!("Invalid A command: {}", s);
+        match s.strip_prefix('@') {
+            Some(operand) => Ok(Self::parse_a(s, operand)?),
+            None => anyhow::bail!("Invalid A command: {}", s),
         }
+    }
+
+    pub fn parse_c_command(s: &str) -> anyhow::Result<Self> {
+        Ok(Self::parse_c(s.trim(), Dialect::Standard)?)
+    }
+
+    pub fn parse_l_command(s: &str) -> anyhow::Result<Self> {
+        Ok(Self::parse_l(s.trim())?)
+    }
 
-        if let Ok(imm) = s[1..].parse::<u16>() {
-            Ok(Command::AImm(imm))
+    fn parse_a(s: &str, operand: &str) -> Result<Self, ParseError> {
+        let error = |message: String| ParseError::new(message, 1, operand.len());
+        if operand.starts_with(|c: char| c.is_ascii_digit()) {
+            match operand.parse::<u16>() {
+                Ok(imm) if imm <= 0x7fff => Ok(Command::AImm(imm)),
+                _ => Err(error(format!("A command value out of range: {}", operand))),
+            }
+        } else if is_symbol(operand) {
+            Ok(Command::ASymbol(operand.to_string()))
         } else {
-            Ok(Command::ASymbol(s[1..].to_string()))
+            Err(error(format!("Invalid A command: {}", s)))
         }
     }
 
-    pub fn parse_c_command(s: &str) -> anyhow::Result<Self> {
-        let s = s.trim();
-        if s.is_empty() {
-            anyhow::bail!("Invalid C command: {}", s);
+    fn parse_l(s: &str) -> Result<Self, ParseError> {
+        match s.strip_prefix('(').and_then(|s| s.strip_suffix(')')) {
+            Some(label) if is_symbol(label) => Ok(Command::L(label.to_string())),
+            _ => Err(ParseError::new(format!("Invalid L command: {}", s), 0, s.len())),
         }
+    }
 
-        Dest::parse(s).and_then(|(dest, s)| {
-            Comp::parse(s)
-                .and_then(|(comp, s)| Jump::parse(s).map(|(jump, _)| Command::C(dest, comp, jump)))
-        })
+    fn parse_c(s: &str, dialect: Dialect) -> Result<Self, ParseError> {
+        let (dest, comp_offset) = match s.find('=') {
+            Some(end) => {
+                let (dest, _) = Dest::parse(&s[..end + 1]).map_err(|_| {
+                    ParseError::new(format!("invalid dest: {}", &s[..end]), 0, end)
+                })?;
+                (dest, end + 1)
+            }
+            None => (Dest::None, 0),
+        };
+
+        let comp_end = s[comp_offset..]
+            .find(';')
+            .map_or(s.len(), |end| comp_offset + end);
+        let comp_text = &s[comp_offset..comp_end];
+        let parsed = match dialect {
+            Dialect::Extended => Comp::parse_extended(comp_text),
+            _ => Comp::parse(comp_text),
+        };
+        let comp = match parsed {
+            Ok((comp, "")) => comp,
+            _ => {
+                return Err(ParseError::new(
+                    format!("invalid comp: {}", comp_text),
+                    comp_offset,
+                    comp_text.len(),
+                ))
+            }
+        };
+
+        let jump = match s.get(comp_end + 1..) {
+            Some(jump_text) => match Jump::parse(jump_text) {
+                Ok((jump, "")) if jump != Jump::None => jump,
+                _ => {
+                    return Err(ParseError::new(
+                        format!("invalid jump: {}", jump_text),
+                        comp_end + 1,
+                        jump_text.len(),
+                    ))
+                }
+            },
+            None => Jump::None,
+        };
+
+        Ok(Command::C(dest, comp, jump))
     }
 
-    pub fn parse_l_command(s: &str) -> anyhow::Result<Self> {
-        let s = s.trim();
-        if s.is_empty() || !s.starts_with("(") || !s.ends_with(")") {
-            anyhow::bail!("Invalid L command: {}", s);
+    /// Parses a command with neither surrounding whitespace nor a comment,
+    /// locating an error at the part of `s` that is wrong. Directive names
+    /// are not checked against the dialect.
+    pub fn parse_spanned(s: &str, dialect: Dialect) -> Result<Self, ParseError> {
+        if let Some(operand) = s.strip_prefix('@') {
+            Self::parse_a(s, operand)
+        } else if s.starts_with('(') {
+            Self::parse_l(s)
+        } else if s.starts_with('.') {
+            let error = |message: String| ParseError::new(message, 0, s.len());
+            match Self::parse_directive(s).map_err(|e| error(e.to_string()))? {
+                Command::Directive(Directive::Equ(name, _) | Directive::Ram(name, _))
+                    if !is_symbol(&name) =>
+                {
+                    Err(error(format!("Invalid symbol name: {}", name)))
+                }
+                command => Ok(command),
+            }
+        } else {
+            Self::parse_c(s, dialect)
         }
+    }
+
+    pub fn parse_directive(s: &str) -> anyhow::Result<Self> {
+        Directive::parse(s).map(Command::Directive)
+    }
 
-        let label = &s[1..s.len() - 1];
-        Ok(Command::L(label.to_string()))
+    pub fn dump(&self) -> String {
+        match self {
+            Command::AImm(imm) => format!("@{}", imm),
+            Command::ASymbol(symbol) => format!("@{}", symbol),
+            Command::L(label) => format!("({})", label),
+            Command::Directive(directive) => directive.dump(),
+            Command::C(dest, comp, jump) => {
+                let mut s = String::new();
+                if *dest != Dest::None {
+                    s += dest.dump();
+                    s += "=";
+                }
+                s += &comp.dump();
+                if *jump != Jump::None {
+                    s += ";";
+                    s += jump.dump();
+                }
+                s
+            }
+        }
     }
 
+    /// Parses a command of the standard dialect, ignoring anything from a
+    /// `/`, which can only start a comment.
     pub fn parse(s: &str) -> anyhow::Result<Self> {
-        Command::parse_a_command(s)
-            .or_else(|_| Command::parse_c_command(s))
-            .or_else(|_| Command::parse_l_command(s))
+        let code = s.find('/').map_or(s, |end| &s[..end]);
+        Ok(Self::parse_spanned(code.trim(), Dialect::Standard)?)
     }
 }
 
@@ -352,4 +817,85 @@ mod tests {
         let command = Command::parse("(LOOP)").unwrap();
         assert_eq!(command, Command::L("LOOP".to_string()));
     }
+
+    #[test]
+    fn test_parse_spanned() {
+        let command = Command::parse_spanned("D=D<<", Dialect::Extended).unwrap();
+        assert_eq!(command, Command::C(Dest::D, Comp::D_SHL, Jump::None));
+
+        let error = |s| Command::parse_spanned(s, Dialect::Standard).unwrap_err();
+        let err = error("AM=D<<;JMP");
+        assert_eq!((err.message.as_str(), err.offset, err.len), ("invalid comp: D<<", 3, 3));
+        let err = error("MA=1");
+        assert_eq!((err.offset, err.len), (0, 2));
+        let err = error("D;JMPX");
+        assert_eq!((err.offset, err.len), (2, 4));
+        let err = error("@9x");
+        assert_eq!((err.offset, err.len), (1, 2));
+        let err = error(".equ 1X 2");
+        assert_eq!(err.message, "Invalid symbol name: 1X");
+        assert!(Command::parse("@40000").is_err());
+    }
+
+    #[test]
+    fn test_dump() {
+        for s in ["@1", "@Loop", "D=0", "0;JMP", "AM=D+A;JGT", "(LOOP)", ".org 16"] {
+            assert_eq!(Command::parse(s).unwrap().dump(), s);
+        }
+    }
+
+    #[test]
+    fn test_parse_directive() {
+        let command = Command::parse(".equ WIDTH 32").unwrap();
+        assert_eq!(command, Command::Directive(Directive::Equ("WIDTH".to_string(), 32)));
+
+        let command = Command::parse(".ram buffer, 0x100").unwrap();
+        assert_eq!(command, Command::Directive(Directive::Ram("buffer".to_string(), 256)));
+
+        let command = Command::parse(".org 16").unwrap();
+        assert_eq!(command, Command::Directive(Directive::Org(16)));
+
+        assert!(Command::parse(".org -1").is_err());
+        assert!(Command::parse(".equ X").is_err());
+    }
+
+    #[test]
+    fn test_comp_from() {
+        for comp in ["0", "-1", "D", "!A", "D+1", "A-D", "D|A", "M", "M-1", "D&M"] {
+            let (expected, _) = Comp::parse(comp).unwrap();
+            assert_eq!(Comp::from(expected.assemble()).unwrap(), expected);
+            assert_eq!(expected.dump(), comp);
+        }
+        assert!(Comp::from(0x0001).is_err());
+    }
+
+    #[test]
+    fn test_parse_extended() {
+        let (comp, _) = Comp::parse_extended("D<<").unwrap();
+        assert_eq!((comp.prefix(), comp.assemble()), (0b101, 0x30));
+
+        let (comp, rest) = Comp::parse_extended("!(!D+!M);JGT").unwrap();
+        assert_eq!(comp, Comp::Raw(0b1010111));
+        assert_eq!(rest, ";JGT");
+        assert_eq!(Comp::parse_extended("D+M+1").unwrap().0, comp);
+        assert_eq!(Comp::parse_extended("0b1010111").unwrap().0, comp);
+
+        assert_eq!(Comp::parse_extended("!(!D+A)").unwrap().0, Comp::D_MINUS_A);
+        assert_eq!(Comp::parse_extended("0b0110000").unwrap().0, Comp::A);
+        assert!(Comp::parse_extended("0b101").is_err());
+        assert!(Comp::parse("D<<").is_err());
+    }
+
+    #[test]
+    fn test_alu_names_are_distinct() {
+        let names = (0..0x80u8).map(alu_name).collect::<std::collections::HashSet<_>>();
+        assert_eq!(names.len(), 0x80 - 0x20);
+        for bits in 0..0x80u8 {
+            let comp = Comp::from_extended(0b111, bits as u16).unwrap();
+            assert_eq!(Comp::parse_extended(&comp.dump()).unwrap().0, comp);
+        }
+        assert_eq!(alu_linear_name(0b0000010).as_deref(), Some("D+A"));
+        assert_eq!(alu_linear_name(0b0111010).as_deref(), Some("-1"));
+        assert_eq!(alu_linear_name(0b0000000), None);
+    }
 }
@@ -0,0 +1,100 @@
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+    pub len: usize,
+}
+
+impl Span {
+    pub fn new(line: usize, col: usize, len: usize) -> Self {
+        Self { line, col, len }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Located<T> {
+    pub item: T,
+    pub span: Span,
+}
+
+impl<T> Located<T> {
+    pub fn new(item: T, span: Span) -> Self {
+        Self { item, span }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub span: Span,
+    pub source: String,
+}
+
+impl Diagnostic {
+    pub fn new(message: impl Into<String>, span: Span, source: &str) -> Self {
+        Self {
+            message: message.into(),
+            span,
+            source: source.to_string(),
+        }
+    }
+
+    pub fn render(&self, filename: &str) -> String {
+        self.render_as(filename, "error")
+    }
+
+    /// Renders with a severity other than `error`, e.g. `warning`.
+    pub fn render_as(&self, filename: &str, severity: &str) -> String {
+        let caret = format!(
+            "{}{}",
+            " ".repeat(self.span.col.saturating_sub(1)),
+            "^".repeat(self.span.len.max(1))
+        );
+        format!(
+            "{}:{}:{}: {}: {}\n    {}\n    {}",
+            filename, self.span.line, self.span.col, severity, self.message, self.source, caret
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostics {
+    pub filename: String,
+    pub items: Vec<Diagnostic>,
+}
+
+impl Diagnostics {
+    pub fn new(filename: &str, items: Vec<Diagnostic>) -> Self {
+        Self {
+            filename: filename.to_string(),
+            items,
+        }
+    }
+}
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for item in self.items.iter() {
+            writeln!(f, "{}", item.render(&self.filename))?;
+        }
+        write!(f, "{} error(s) in {}", self.items.len(), self.filename)
+    }
+}
+
+impl std::error::Error for Diagnostics {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let diagnostic = Diagnostic::new("invalid comp: D+X", Span::new(12, 5, 3), "  D=D+X");
+        assert_eq!(
+            diagnostic.render("Pong.asm"),
+            "Pong.asm:12:5: error: invalid comp: D+X\n      D=D+X\n        ^^^"
+        );
+    }
+}
@@ -0,0 +1,247 @@
+use std::collections::BTreeMap;
+
+use super::command::{Command, Comp, Dest, Dialect, Jump};
+use super::symbol_map::SymbolMap;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Line {
+    Label(String),
+    Instruction {
+        address: u16,
+        word: u16,
+        command: Command,
+    },
+    Invalid {
+        address: u16,
+        word: u16,
+        reason: String,
+    },
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Disassembly {
+    pub lines: Vec<Line>,
+}
+
+impl Disassembly {
+    pub fn commands(&self) -> Vec<Command> {
+        self.lines
+            .iter()
+            .filter_map(|line| match line {
+                Line::Label(label) => Some(Command::L(label.clone())),
+                Line::Instruction { command, .. } => Some(command.clone()),
+                Line::Invalid { .. } => None,
+            })
+            .collect()
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.lines
+            .iter()
+            .any(|line| matches!(line, Line::Invalid { .. }))
+    }
+
+    pub fn dump(&self) -> String {
+        self.lines
+            .iter()
+            .map(|line| match line {
+                Line::Label(label) => format!("({})", label),
+                Line::Instruction {
+                    address,
+                    word,
+                    command,
+                } => format!("    {:<20}// {:04x}: {:016b}", command.dump(), address, word),
+                Line::Invalid {
+                    address,
+                    word,
+                    reason,
+                } => format!("    // {:04x}: {:016b} invalid: {}", address, word, reason),
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+pub fn decode(word: u16) -> anyhow::Result<Command> {
+    decode_with(word, Dialect::Standard)
+}
+
+pub fn decode_with(word: u16, dialect: Dialect) -> anyhow::Result<Command> {
+    if word & 0x8000 == 0 {
+        return Ok(Command::AImm(word));
+    }
+
+    let comp = match dialect {
+        Dialect::Extended => Comp::from_extended(word >> 13, (word >> 6) & 0x7f)?,
+        _ if word & 0xe000 != 0xe000 => {
+            anyhow::bail!("Invalid C command prefix: {:03b}", word >> 13)
+        }
+        _ => Comp::from((word >> 6) & 0x7f)?,
+    };
+    let dest = Dest::from(((word >> 3) & 0x7) as u8)?;
+    let jump = Jump::from(word & 0x7)?;
+    Ok(Command::C(dest, comp, jump))
+}
+
+fn predefined_variable(address: u16) -> Option<String> {
+    match address {
+        0 => Some("SP".to_string()),
+        1 => Some("LCL".to_string()),
+        2 => Some("ARG".to_string()),
+        3 => Some("THIS".to_string()),
+        4 => Some("THAT".to_string()),
+        5..=15 => Some(format!("R{}", address)),
+        16384 => Some("SCREEN".to_string()),
+        24576 => Some("KBD".to_string()),
+        _ => None,
+    }
+}
+
+fn is_jump(command: &Command) -> bool {
+    matches!(command, Command::C(_, _, jump) if *jump != Jump::None)
+}
+
+pub fn disassemble(words: &[u16], symbols: Option<&SymbolMap>) -> Disassembly {
+    disassemble_with(words, symbols, Dialect::Standard)
+}
+
+pub fn disassemble_with(
+    words: &[u16],
+    symbols: Option<&SymbolMap>,
+    dialect: Dialect,
+) -> Disassembly {
+    let decoded = words
+        .iter()
+        .map(|word| decode_with(*word, dialect))
+        .collect::<Vec<_>>();
+
+    // Only addresses inside the ROM get a label line, so a jump anywhere
+    // else keeps its number rather than naming a label that is never defined.
+    let in_rom = |address: u16| (address as usize) < words.len();
+    let mut labels = BTreeMap::new();
+    if let Some(symbols) = symbols {
+        labels.extend(
+            symbols
+                .labels
+                .iter()
+                .filter(|(k, v)| in_rom(**k) && !v.is_empty())
+                .map(|(k, v)| (*k, v.clone())),
+        );
+    }
+    for pair in decoded.windows(2) {
+        if let [Ok(Command::AImm(target)), Ok(next)] = pair {
+            if is_jump(next) && in_rom(*target) && !labels.contains_key(target) {
+                labels.insert(*target, vec![format!("L{}", target)]);
+            }
+        }
+    }
+
+    let mut lines = Vec::new();
+    for (i, (word, command)) in words.iter().zip(decoded.iter()).enumerate() {
+        let address = i as u16;
+        if let Some(names) = labels.get(&address) {
+            lines.extend(names.iter().cloned().map(Line::Label));
+        }
+
+        let command = match command {
+            Ok(Command::AImm(imm)) => {
+                let next = decoded.get(i + 1).and_then(|c| c.as_ref().ok());
+                let name = match next {
+                    Some(next) if is_jump(next) => labels.get(imm).map(|names| names[0].clone()),
+                    Some(next) if next.uses_memory() => symbols
+                        .and_then(|s| s.variable(*imm))
+                        .map(|s| s.to_string())
+                        .or_else(|| predefined_variable(*imm)),
+                    _ => None,
+                };
+                name.map(Command::ASymbol).unwrap_or(Command::AImm(*imm))
+            }
+            Ok(command) => command.clone(),
+            Err(e) => {
+                lines.push(Line::Invalid {
+                    address,
+                    word: *word,
+                    reason: e.to_string(),
+                });
+                continue;
+            }
+        };
+        lines.push(Line::Instruction {
+            address,
+            word: *word,
+            command,
+        });
+    }
+
+    Disassembly { lines }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::code::{assemble, parse};
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode(0x0010).unwrap(), Command::AImm(16));
+        assert_eq!(
+            decode(0b1111110111011000).unwrap(),
+            Command::C(Dest::MD, Comp::M_PLUS_ONE, Jump::None)
+        );
+        assert!(decode(0b1000000000000000).is_err());
+        assert!(decode(0b1110000001000000).is_err());
+
+        assert_eq!(
+            decode_with(0b1110000001000000, Dialect::Extended).unwrap(),
+            Command::C(Dest::None, Comp::Raw(1), Jump::None)
+        );
+        assert_eq!(
+            decode_with(0b1010110000010000, Dialect::Extended).unwrap(),
+            Command::C(Dest::D, Comp::D_SHL, Jump::None)
+        );
+        assert!(decode_with(0b1100000000000000, Dialect::Extended).is_err());
+    }
+
+    #[test]
+    fn test_disassemble_round_trip() {
+        let asm = "@i\nM=1\n(LOOP)\n@i\nD=M\n@100\nD=D-A\n@END\nD;JGT\n@i\nM=M+1\n@LOOP\n0;JMP\n(END)\n@END\n0;JMP";
+        let lines = asm.lines().map(|s| s.to_string()).collect::<Vec<_>>();
+        let binary = assemble(&parse(&lines).unwrap()).unwrap();
+
+        let disassembly = disassemble(&binary, None);
+        assert!(!disassembly.has_errors());
+        assert!(disassembly.lines.contains(&Line::Label("L2".to_string())));
+        assert_eq!(assemble(&disassembly.commands()).unwrap(), binary);
+
+        let symbols = SymbolMap::parse("label 2 LOOP\nlabel 12 END\nvar 16 i").unwrap();
+        let disassembly = disassemble(&binary, Some(&symbols));
+        let commands = disassembly.commands();
+        assert_eq!(commands[0], Command::ASymbol("i".to_string()));
+        assert!(commands.contains(&Command::L("LOOP".to_string())));
+        assert_eq!(assemble(&commands).unwrap(), binary);
+
+        let symbols = SymbolMap::parse("label 2 LOOP\nlabel 2 TOP").unwrap();
+        let disassembly = disassemble(&binary, Some(&symbols));
+        let top = Line::Label("TOP".to_string());
+        let i = disassembly.lines.iter().position(|line| *line == top).unwrap();
+        assert_eq!(disassembly.lines[i - 1], Line::Label("LOOP".to_string()));
+        assert_eq!(assemble(&disassembly.commands()).unwrap(), binary);
+    }
+
+    #[test]
+    fn test_jump_out_of_rom_round_trips() {
+        let asm = "@3\nD;JGT\n@1000\n0;JMP";
+        let lines = asm.lines().map(|s| s.to_string()).collect::<Vec<_>>();
+        let binary = assemble(&parse(&lines).unwrap()).unwrap();
+
+        let symbols = SymbolMap::parse("label 4 END").unwrap();
+        for symbols in [None, Some(&symbols)] {
+            let disassembly = disassemble(&binary, symbols);
+            let commands = disassembly.commands();
+            assert_eq!(commands[0], Command::ASymbol("L3".to_string()));
+            assert_eq!(commands[2], Command::AImm(1000));
+            assert_eq!(commands[3], Command::L("L3".to_string()));
+            assert_eq!(assemble(&commands).unwrap(), binary);
+        }
+    }
+}
@@ -0,0 +1,299 @@
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Hack,
+    BinaryLe,
+    BinaryBe,
+    IntelHex,
+    VerilogBin,
+    VerilogHex,
+    Rust,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "hack" => Ok(Self::Hack),
+            "bin-le" => Ok(Self::BinaryLe),
+            "bin" | "bin-be" => Ok(Self::BinaryBe),
+            "ihex" => Ok(Self::IntelHex),
+            "memb" => Ok(Self::VerilogBin),
+            "memh" => Ok(Self::VerilogHex),
+            "rust" => Ok(Self::Rust),
+            _ => Err(anyhow::anyhow!("Unknown output format: {}", s)),
+        }
+    }
+}
+
+impl Format {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "hack" => Some(Self::Hack),
+            "bin" => Some(Self::BinaryBe),
+            "hex" | "ihex" => Some(Self::IntelHex),
+            "memb" => Some(Self::VerilogBin),
+            "memh" => Some(Self::VerilogHex),
+            "rs" => Some(Self::Rust),
+            _ => None,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Hack => "hack",
+            Self::BinaryLe | Self::BinaryBe => "bin",
+            Self::IntelHex => "hex",
+            Self::VerilogBin => "memb",
+            Self::VerilogHex => "memh",
+            Self::Rust => "rs",
+        }
+    }
+
+    pub fn write(self, words: &[u16]) -> Vec<u8> {
+        match self {
+            Self::Hack => text_lines(words.iter().map(|w| format!("{:016b}", w))),
+            Self::BinaryLe => words.iter().flat_map(|w| w.to_le_bytes()).collect(),
+            Self::BinaryBe => words.iter().flat_map(|w| w.to_be_bytes()).collect(),
+            Self::IntelHex => write_intel_hex(words).into_bytes(),
+            Self::VerilogBin => text_lines(
+                std::iter::once("@0".to_string()).chain(words.iter().map(|w| format!("{:016b}", w))),
+            ),
+            Self::VerilogHex => text_lines(
+                std::iter::once("@0".to_string()).chain(words.iter().map(|w| format!("{:04x}", w))),
+            ),
+            Self::Rust => {
+                let body = words
+                    .chunks(8)
+                    .map(|chunk| {
+                        let words = chunk.iter().map(|w| format!("0x{:04x},", w));
+                        format!("    {}", words.collect::<Vec<_>>().join(" "))
+                    })
+                    .collect::<Vec<_>>();
+                format!(
+                    "pub const ROM: [u16; {}] = [\n{}\n];\n",
+                    words.len(),
+                    body.join("\n")
+                )
+                .into_bytes()
+            }
+        }
+    }
+
+    pub fn read(self, bytes: &[u8]) -> anyhow::Result<Vec<u16>> {
+        match self {
+            Self::BinaryLe | Self::BinaryBe => {
+                if !bytes.len().is_multiple_of(2) {
+                    anyhow::bail!("binary image has odd length: {}", bytes.len());
+                }
+                Ok(bytes
+                    .chunks(2)
+                    .map(|c| match self {
+                        Self::BinaryLe => u16::from_le_bytes([c[0], c[1]]),
+                        _ => u16::from_be_bytes([c[0], c[1]]),
+                    })
+                    .collect())
+            }
+            _ => {
+                let s = std::str::from_utf8(bytes)?;
+                match self {
+                    Self::Hack => read_hack(s),
+                    Self::IntelHex => read_intel_hex(s),
+                    Self::VerilogBin => read_verilog_mem(s, 2),
+                    Self::VerilogHex => read_verilog_mem(s, 16),
+                    _ => read_rust(s),
+                }
+            }
+        }
+    }
+}
+
+pub fn load(path: &Path) -> anyhow::Result<Vec<u16>> {
+    let format = Format::from_path(path)
+        .ok_or_else(|| anyhow::anyhow!("cannot infer image format of {}", path.display()))?;
+    format.read(&std::fs::read(path)?)
+}
+
+fn text_lines(lines: impl Iterator<Item = String>) -> Vec<u8> {
+    lines
+        .map(|line| line + "\n")
+        .collect::<String>()
+        .into_bytes()
+}
+
+fn read_hack(s: &str) -> anyhow::Result<Vec<u16>> {
+    s.lines()
+        .map(|line| line.trim())
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(i, line)| {
+            if line.len() != 16 {
+                anyhow::bail!("line {}: expected 16 bits: {}", i + 1, line);
+            }
+            u16::from_str_radix(line, 2)
+                .map_err(|_| anyhow::anyhow!("line {}: invalid binary word: {}", i + 1, line))
+        })
+        .collect()
+}
+
+/// Intel HEX records are word addressed: each address counts 16-bit words
+/// and every word is stored as two big-endian data bytes.
+fn write_intel_hex(words: &[u16]) -> String {
+    let record = |address: u16, kind: u8, data: &[u8]| {
+        let mut bytes = vec![data.len() as u8, (address >> 8) as u8, address as u8, kind];
+        bytes.extend_from_slice(data);
+        let sum = bytes.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+        bytes.push(sum.wrapping_neg());
+        format!(
+            ":{}\n",
+            bytes.iter().map(|b| format!("{:02X}", b)).collect::<String>()
+        )
+    };
+
+    let mut s = String::new();
+    for (i, chunk) in words.chunks(8).enumerate() {
+        let data = chunk.iter().flat_map(|w| w.to_be_bytes()).collect::<Vec<_>>();
+        s += &record((i * 8) as u16, 0x00, &data);
+    }
+    s += &record(0, 0x01, &[]);
+    s
+}
+
+fn read_intel_hex(s: &str) -> anyhow::Result<Vec<u16>> {
+    let mut words = Vec::new();
+    for (i, line) in s.lines().map(|line| line.trim()).enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let hex = line
+            .strip_prefix(':')
+            .ok_or_else(|| anyhow::anyhow!("line {}: missing record mark", i + 1))?;
+        if hex.len() < 10 || !hex.len().is_multiple_of(2) {
+            anyhow::bail!("line {}: malformed record", i + 1);
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|j| u8::from_str_radix(&hex[j..j + 2], 16))
+            .collect::<Result<Vec<_>, _>>()?;
+        if bytes.iter().fold(0u8, |acc, b| acc.wrapping_add(*b)) != 0 {
+            anyhow::bail!("line {}: checksum mismatch", i + 1);
+        }
+
+        let len = bytes[0] as usize;
+        let address = u16::from_be_bytes([bytes[1], bytes[2]]) as usize;
+        let data = &bytes[4..bytes.len() - 1];
+        if data.len() != len || !len.is_multiple_of(2) {
+            anyhow::bail!("line {}: invalid data length", i + 1);
+        }
+        match bytes[3] {
+            0x00 => {
+                let chunk = data.chunks(2).map(|c| u16::from_be_bytes([c[0], c[1]]));
+                if words.len() < address + len / 2 {
+                    words.resize(address + len / 2, 0);
+                }
+                for (j, word) in chunk.enumerate() {
+                    words[address + j] = word;
+                }
+            }
+            0x01 => break,
+            kind => anyhow::bail!("line {}: unsupported record type: {:02X}", i + 1, kind),
+        }
+    }
+    Ok(words)
+}
+
+fn read_verilog_mem(s: &str, radix: u32) -> anyhow::Result<Vec<u16>> {
+    let mut words = Vec::new();
+    let mut address = 0;
+    for line in s.lines() {
+        let line = line.find("//").map(|end| &line[..end]).unwrap_or(line);
+        for token in line.split_whitespace() {
+            if let Some(target) = token.strip_prefix('@') {
+                address = usize::from_str_radix(target, 16)?;
+                continue;
+            }
+            let word = u16::from_str_radix(&token.replace('_', ""), radix)
+                .map_err(|_| anyhow::anyhow!("invalid memory word: {}", token))?;
+            if words.len() <= address {
+                words.resize(address + 1, 0);
+            }
+            words[address] = word;
+            address += 1;
+        }
+    }
+    Ok(words)
+}
+
+fn read_rust(s: &str) -> anyhow::Result<Vec<u16>> {
+    let body = s
+        .split_once('=')
+        .and_then(|(_, rhs)| rhs.split_once('['))
+        .and_then(|(_, rhs)| rhs.split_once(']'))
+        .map(|(body, _)| body)
+        .ok_or_else(|| anyhow::anyhow!("no array literal found"))?;
+    body.split(',')
+        .map(|token| token.trim())
+        .filter(|token| !token.is_empty())
+        .map(|token| {
+            let token = token.trim_end_matches("u16").replace('_', "");
+            match token.strip_prefix("0x") {
+                Some(hex) => u16::from_str_radix(hex, 16),
+                None => token.parse::<u16>(),
+            }
+            .map_err(|_| anyhow::anyhow!("invalid array element: {}", token))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMATS: [Format; 7] = [
+        Format::Hack,
+        Format::BinaryLe,
+        Format::BinaryBe,
+        Format::IntelHex,
+        Format::VerilogBin,
+        Format::VerilogHex,
+        Format::Rust,
+    ];
+
+    #[test]
+    fn test_round_trip() {
+        let words = (0..21u16).map(|i| i.wrapping_mul(0x9e37)).collect::<Vec<_>>();
+        for format in FORMATS {
+            assert_eq!(format.read(&format.write(&words)).unwrap(), words, "{:?}", format);
+            assert_eq!(format.read(&format.write(&[])).unwrap(), vec![], "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn test_write() {
+        let words = [0x0002, 0xec10];
+        assert_eq!(
+            Format::Hack.write(&words),
+            b"0000000000000010\n1110110000010000\n"
+        );
+        assert_eq!(Format::BinaryLe.write(&words), vec![0x02, 0x00, 0x10, 0xec]);
+        assert_eq!(
+            String::from_utf8(Format::IntelHex.write(&words)).unwrap(),
+            ":040000000002EC10FE\n:00000001FF\n"
+        );
+        assert_eq!(Format::VerilogHex.write(&words), b"@0\n0002\nec10\n");
+    }
+
+    #[test]
+    fn test_read_errors() {
+        assert!(Format::Hack.read(b"0101").is_err());
+        assert!(Format::BinaryBe.read(&[0x00]).is_err());
+        assert!(Format::IntelHex.read(b":040000000002EC10FF\n").is_err());
+        assert_eq!(
+            Format::VerilogHex.read(b"// rom\n@2 ffff\n").unwrap(),
+            vec![0, 0, 0xffff]
+        );
+    }
+}
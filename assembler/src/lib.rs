@@ -1,2 +1,13 @@
+pub mod assembler;
 pub mod command;
-pub mod code;
\ No newline at end of file
+pub mod code;
+pub mod diagnostic;
+pub mod disassembler;
+pub mod format;
+pub mod lint;
+pub mod listing;
+pub mod macros;
+pub mod object;
+pub mod optimizer;
+pub mod pretty;
+pub mod symbol_map;
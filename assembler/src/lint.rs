@@ -0,0 +1,268 @@
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+
+use super::code::SymbolTable;
+use super::command::{Command, Dest, Directive, Jump};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Lint {
+    JumpUsesMemory,
+    SingleUseVariable,
+    UnusedLabel,
+    ShadowsPredefined,
+    WritesKeyboard,
+    CaseCollision,
+}
+
+impl Lint {
+    pub const ALL: [Lint; 6] = [
+        Lint::JumpUsesMemory,
+        Lint::SingleUseVariable,
+        Lint::UnusedLabel,
+        Lint::ShadowsPredefined,
+        Lint::WritesKeyboard,
+        Lint::CaseCollision,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Lint::JumpUsesMemory => "jump-uses-memory",
+            Lint::SingleUseVariable => "single-use-variable",
+            Lint::UnusedLabel => "unused-label",
+            Lint::ShadowsPredefined => "shadows-predefined",
+            Lint::WritesKeyboard => "writes-keyboard",
+            Lint::CaseCollision => "case-collision",
+        }
+    }
+}
+
+impl FromStr for Lint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Lint::ALL
+            .into_iter()
+            .find(|lint| lint.name() == s)
+            .ok_or_else(|| anyhow::anyhow!("Unknown lint: {}", s))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Allow,
+    Warn,
+    Deny,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Levels {
+    levels: BTreeMap<Lint, Level>,
+}
+
+impl Default for Levels {
+    fn default() -> Self {
+        Self {
+            levels: Lint::ALL.into_iter().map(|lint| (lint, Level::Warn)).collect(),
+        }
+    }
+}
+
+impl Levels {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, lint: Lint) -> Level {
+        self.levels[&lint]
+    }
+
+    pub fn set(&mut self, lint: Lint, level: Level) {
+        self.levels.insert(lint, level);
+    }
+
+    /// Sets the level of a lint by name, or of every lint for `all`.
+    pub fn set_by_name(&mut self, name: &str, level: Level) -> anyhow::Result<()> {
+        if name == "all" {
+            for lint in Lint::ALL {
+                self.set(lint, level);
+            }
+        } else {
+            self.set(name.parse()?, level);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    pub lint: Lint,
+    pub level: Level,
+    pub message: String,
+    /// Index of the offending command in the linted program.
+    pub index: usize,
+}
+
+fn writes_m(dest: Dest) -> bool {
+    matches!(dest, Dest::M | Dest::MD | Dest::AM | Dest::AMD)
+}
+
+fn defined_name(command: &Command) -> Option<&str> {
+    match command {
+        Command::L(name) => Some(name),
+        Command::Directive(Directive::Equ(name, _) | Directive::Ram(name, _)) => Some(name),
+        _ => None,
+    }
+}
+
+pub fn lint(program: &[Command], symbols: &SymbolTable, levels: &Levels) -> Vec<Warning> {
+    let mut warnings = Vec::new();
+    let mut warn = |lint: Lint, index: usize, message: String| {
+        let level = levels.get(lint);
+        if level != Level::Allow {
+            warnings.push(Warning {
+                lint,
+                level,
+                message,
+                index,
+            });
+        }
+    };
+
+    let mut uses: HashMap<&str, Vec<usize>> = HashMap::new();
+    let mut definitions = Vec::new();
+    for (i, command) in program.iter().enumerate() {
+        match command {
+            Command::ASymbol(symbol) => uses.entry(symbol).or_default().push(i),
+            _ => definitions.extend(defined_name(command).map(|name| (name, i))),
+        }
+    }
+
+    let predefined = SymbolTable::predefined();
+    let mut address = None;
+    for (i, command) in program.iter().enumerate() {
+        match command {
+            Command::AImm(imm) => address = Some(*imm),
+            Command::ASymbol(symbol) => address = symbols.get(symbol),
+            Command::C(dest, comp, jump) => {
+                if *jump != Jump::None && (comp.assemble() & 0x40 != 0 || writes_m(*dest)) {
+                    warn(
+                        Lint::JumpUsesMemory,
+                        i,
+                        "jump target in A is also used as a memory address".to_string(),
+                    );
+                }
+                if writes_m(*dest) && address == Some(24576) {
+                    warn(Lint::WritesKeyboard, i, "write to read-only KBD".to_string());
+                }
+                if matches!(dest, Dest::A | Dest::AM | Dest::AD | Dest::AMD) {
+                    address = None;
+                }
+            }
+            _ => address = None,
+        }
+    }
+
+    for (name, index) in definitions.iter() {
+        if predefined.contains_key(*name) {
+            warn(
+                Lint::ShadowsPredefined,
+                *index,
+                format!("{} shadows a predefined symbol", name),
+            );
+        }
+        if matches!(program[*index], Command::L(_)) && !uses.contains_key(name) {
+            warn(Lint::UnusedLabel, *index, format!("label {} is never used", name));
+        }
+    }
+
+    for (name, _) in symbols.variables() {
+        if let Some([index]) = uses.get(name).map(|v| v.as_slice()) {
+            warn(
+                Lint::SingleUseVariable,
+                *index,
+                format!("variable {} is only referenced once", name),
+            );
+        }
+    }
+
+    // Predefined symbols sort first with no index of their own.
+    let mut spellings: BTreeMap<String, Vec<(&str, Option<usize>)>> = BTreeMap::new();
+    let names = predefined
+        .keys()
+        .map(|name| (name.as_str(), None))
+        .chain(definitions.iter().map(|(name, index)| (*name, Some(*index))))
+        .chain(
+            symbols
+                .variables()
+                .map(|(name, _)| (name, Some(uses[name][0]))),
+        );
+    for (name, index) in names {
+        let spelling = spellings.entry(name.to_lowercase()).or_default();
+        if !spelling.iter().any(|(other, _)| *other == name) {
+            spelling.push((name, index));
+        }
+    }
+    for spelling in spellings.values_mut() {
+        spelling.sort_by_key(|(_, index)| *index);
+        if let [(first, _), rest @ ..] = spelling.as_slice() {
+            for (name, index) in rest.iter() {
+                if let Some(index) = index {
+                    warn(
+                        Lint::CaseCollision,
+                        *index,
+                        format!("{} differs from {} only in case", name, first),
+                    );
+                }
+            }
+        }
+    }
+
+    warnings.sort_by_key(|warning| warning.index);
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::code::{assemble_with_symbols, parse};
+
+    fn lint_source(asm: &str, levels: &Levels) -> Vec<(Lint, usize)> {
+        let lines = asm.lines().map(|s| s.to_string()).collect::<Vec<_>>();
+        let program = parse(&lines).unwrap();
+        let (_, symbols) = assemble_with_symbols(&program).unwrap();
+        lint(&program, &symbols, levels)
+            .into_iter()
+            .map(|warning| (warning.lint, warning.index))
+            .collect()
+    }
+
+    #[test]
+    fn test_lint() {
+        let asm = "(R13)\n@count\nM=0\n(LOOP)\n@LOOP\nM=M-1;JGT\n@KBD\nM=0\n@Count\nD=M\n@count\nM=D\n(screen)";
+        let warnings = lint_source(asm, &Levels::new());
+        assert_eq!(
+            warnings,
+            vec![
+                (Lint::ShadowsPredefined, 0),
+                (Lint::UnusedLabel, 0),
+                (Lint::JumpUsesMemory, 5),
+                (Lint::WritesKeyboard, 7),
+                (Lint::SingleUseVariable, 8),
+                (Lint::CaseCollision, 8),
+                (Lint::UnusedLabel, 12),
+                (Lint::CaseCollision, 12),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_levels() {
+        let mut levels = Levels::new();
+        levels.set_by_name("all", Level::Allow).unwrap();
+        levels.set_by_name("writes-keyboard", Level::Deny).unwrap();
+        assert!(levels.set_by_name("no-such-lint", Level::Deny).is_err());
+
+        let warnings = lint_source("@KBD\nM=1\n(END)", &levels);
+        assert_eq!(warnings, vec![(Lint::WritesKeyboard, 1)]);
+    }
+}
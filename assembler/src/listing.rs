@@ -0,0 +1,59 @@
+use super::command::{Command, Directive};
+use super::diagnostic::Located;
+
+pub fn listing(program: &[Located<Command>], binary: &[u16], lines: &[String]) -> String {
+    let mut rows = Vec::new();
+    let mut address = 0;
+    for command in program.iter() {
+        let source = lines
+            .get(command.span.line - 1)
+            .map(|s| s.trim_end())
+            .unwrap_or("");
+        match command.item {
+            Command::Directive(Directive::Org(target)) => {
+                rows.push(format!("     |                  |      | {}", source));
+                while address < target as usize {
+                    rows.push(format!("{:04x} | {:016b} | {:04x} |", address, 0, 0));
+                    address += 1;
+                }
+            }
+            Command::L(_) | Command::Directive(_) => {
+                rows.push(format!("     |                  |      | {}", source))
+            }
+            _ => {
+                let word = binary[address];
+                rows.push(format!(
+                    "{:04x} | {:016b} | {:04x} | {}",
+                    address, word, word, source
+                ));
+                address += 1;
+            }
+        }
+    }
+    rows.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::code::{assemble_located, parse_located};
+
+    #[test]
+    fn test_listing() {
+        let lines = ["(LOOP)", "  @LOOP // again", "  0;JMP"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+        let program = parse_located(&lines).unwrap();
+        let (binary, _) = assemble_located(&program, &lines).unwrap();
+        assert_eq!(
+            listing(&program, &binary, &lines),
+            [
+                "     |                  |      | (LOOP)",
+                "0000 | 0000000000000000 | 0000 |   @LOOP // again",
+                "0001 | 1110101010000111 | ea87 |   0;JMP",
+            ]
+            .join("\n")
+        );
+    }
+}
@@ -0,0 +1,381 @@
+use std::collections::HashMap;
+
+use super::command::{parse_number, Command};
+use super::diagnostic::{Diagnostic, Span};
+
+const MAX_EXPANSION_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLine {
+    pub text: String,
+    pub line: usize,
+    pub expansion: Option<Span>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Macro {
+    params: Vec<String>,
+    body: Vec<String>,
+}
+
+/// Loads a negative number into D, since `@` only takes 0 to 32767.
+fn load_negative(value: i32) -> anyhow::Result<Vec<String>> {
+    match value {
+        -0x8000 => Ok(vec!["@32767".to_string(), "D=-A".to_string(), "D=D-1".to_string()]),
+        v @ -0x7fff..=-1 => Ok(vec![format!("@{}", -v), "D=-A".to_string()]),
+        v => anyhow::bail!("Value out of range: {}", v),
+    }
+}
+
+fn data_table(args: &[&str]) -> anyhow::Result<Vec<String>> {
+    let fields = args
+        .iter()
+        .flat_map(|arg| arg.split_whitespace())
+        .collect::<Vec<_>>();
+    let (base, values) = fields
+        .split_first()
+        .ok_or_else(|| anyhow::anyhow!(".data expects an address and values"))?;
+    let base = parse_number(base)?;
+
+    let mut lines = Vec::new();
+    for (i, value) in values.iter().enumerate() {
+        let address = base + i as i32;
+        if !(0..=0x7fff).contains(&address) {
+            anyhow::bail!("Address out of range: {}", address);
+        }
+        match parse_number(value)? {
+            v @ -1..=1 => lines.extend([format!("@{}", address), format!("M={}", v)]),
+            v @ 2..=0x7fff => lines.extend([format!("@{}", v), "D=A".to_string()]),
+            v @ -0x8000..=-2 => lines.extend(load_negative(v)?),
+            v => anyhow::bail!("Value out of range: {}", v),
+        }
+        if !lines.last().unwrap().starts_with("M=") {
+            lines.extend([format!("@{}", address), "M=D".to_string()]);
+        }
+    }
+    Ok(lines)
+}
+
+fn builtin(name: &str, args: &[&str]) -> Option<anyhow::Result<Vec<String>>> {
+    if name == ".data" {
+        return Some(data_table(args));
+    }
+    if let ("SET", [address, value]) = (name, args) {
+        if value.starts_with('-') && *value != "-1" {
+            return Some(parse_number(value).and_then(load_negative).map(|mut lines| {
+                lines.extend([format!("@{}", address), "M=D".to_string()]);
+                lines
+            }));
+        }
+    }
+
+    let expect = |n: usize| {
+        if args.len() == n {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!(
+                "{} expects {} argument(s), got {}",
+                name,
+                n,
+                args.len()
+            ))
+        }
+    };
+
+    let lines = match name {
+        "PUSHD" => expect(0).map(|_| vec!["@SP", "AM=M+1", "A=A-1", "M=D"]),
+        "POPD" => expect(0).map(|_| vec!["@SP", "AM=M-1", "D=M"]),
+        "GOTO" => expect(1).map(|_| vec!["@\\0", "0;JMP"]),
+        "IFNZ" => expect(1).map(|_| vec!["@\\0", "D;JNE"]),
+        "SET" => expect(2).map(|_| match args[1] {
+            "0" | "1" | "-1" => vec!["@\\0", "M=\\1"],
+            _ => vec!["@\\1", "D=A", "@\\0", "M=D"],
+        }),
+        _ => return None,
+    };
+    Some(lines.map(|lines| {
+        lines
+            .into_iter()
+            .map(|line| {
+                args.iter()
+                    .enumerate()
+                    .fold(line.to_string(), |line, (i, arg)| {
+                        line.replace(&format!("\\{}", i), arg)
+                    })
+            })
+            .collect()
+    }))
+}
+
+pub fn is_builtin(name: &str) -> bool {
+    matches!(name, "PUSHD" | "POPD" | "GOTO" | "IFNZ" | "SET" | ".data")
+}
+
+/// Expands a built-in pseudo-op such as `PUSHD` or `SET R13, 1` into Hack commands.
+pub fn pseudo_op(name: &str, args: &[&str]) -> anyhow::Result<Vec<Command>> {
+    builtin(name, args)
+        .ok_or_else(|| anyhow::anyhow!("Unknown pseudo-op: {}", name))??
+        .iter()
+        .map(|line| Command::parse(line))
+        .collect()
+}
+
+fn split_invocation(text: &str) -> (&str, Vec<&str>) {
+    let end = text.find(char::is_whitespace).unwrap_or(text.len());
+    let (name, args) = text.split_at(end);
+    let args = args.trim();
+    if args.is_empty() {
+        (name, vec![])
+    } else {
+        (name, args.split(',').map(|arg| arg.trim()).collect())
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    line.find("//").map(|end| &line[..end]).unwrap_or(line).trim()
+}
+
+struct Expander {
+    macros: HashMap<String, Macro>,
+    counter: usize,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Expander {
+    fn instantiate(&mut self, m: &Macro, args: &[&str]) -> anyhow::Result<Vec<String>> {
+        if m.params.len() != args.len() {
+            anyhow::bail!(
+                "macro expects {} argument(s), got {}",
+                m.params.len(),
+                args.len()
+            );
+        }
+
+        self.counter += 1;
+        let mut params = m.params.iter().zip(args.iter()).collect::<Vec<_>>();
+        params.sort_by_key(|(param, _)| std::cmp::Reverse(param.len()));
+        Ok(m.body
+            .iter()
+            .map(|line| {
+                params
+                    .iter()
+                    .fold(line.clone(), |line, (param, arg)| {
+                        line.replace(&format!("\\{}", param), arg)
+                    })
+                    .replace("\\@", &self.counter.to_string())
+            })
+            .collect())
+    }
+
+    fn expand_line(
+        &mut self,
+        text: &str,
+        line: usize,
+        expansion: Option<Span>,
+        source: &str,
+        depth: usize,
+        output: &mut Vec<SourceLine>,
+    ) {
+        let code = strip_comment(text);
+        let (name, args) = split_invocation(code);
+        let span = expansion.unwrap_or_else(|| {
+            let col = text.len() - text.trim_start().len() + 1;
+            Span::new(line, col, code.len())
+        });
+
+        let body = if let Some(m) = self.macros.get(name).cloned() {
+            self.instantiate(&m, &args)
+        } else if let Some(body) = builtin(name, &args) {
+            body
+        } else {
+            output.push(SourceLine {
+                text: text.to_string(),
+                line,
+                expansion,
+            });
+            return;
+        };
+
+        if MAX_EXPANSION_DEPTH <= depth {
+            self.diagnostics.push(Diagnostic::new(
+                format!("macro expansion too deep: {}", name),
+                span,
+                source,
+            ));
+            return;
+        }
+        match body {
+            Ok(body) => {
+                for body_line in body.iter() {
+                    self.expand_line(body_line, line, Some(span), source, depth + 1, output);
+                }
+            }
+            Err(e) => self
+                .diagnostics
+                .push(Diagnostic::new(e.to_string(), span, source)),
+        }
+    }
+}
+
+pub fn expand(lines: &[String]) -> Result<Vec<SourceLine>, Vec<Diagnostic>> {
+    let mut expander = Expander {
+        macros: HashMap::new(),
+        counter: 0,
+        diagnostics: Vec::new(),
+    };
+
+    let mut output = Vec::new();
+    let mut definition: Option<(String, Macro, usize)> = None;
+    for (i, text) in lines.iter().enumerate() {
+        let line = i + 1;
+        let code = strip_comment(text);
+        let span = Span::new(line, text.len() - text.trim_start().len() + 1, code.len());
+
+        if let Some(header) = code.strip_prefix(".macro") {
+            if definition.is_some() {
+                expander.diagnostics.push(Diagnostic::new(
+                    "nested macro definition",
+                    span,
+                    text,
+                ));
+                continue;
+            }
+            let (name, params) = split_invocation(header.trim());
+            if name.is_empty() || is_builtin(name) {
+                expander.diagnostics.push(Diagnostic::new(
+                    format!("invalid macro name: {}", name),
+                    span,
+                    text,
+                ));
+            }
+            let params = params.iter().map(|p| p.to_string()).collect();
+            definition = Some((
+                name.to_string(),
+                Macro {
+                    params,
+                    body: vec![],
+                },
+                line,
+            ));
+        } else if code == ".endm" {
+            match definition.take() {
+                Some((name, m, _)) => {
+                    expander.macros.insert(name, m);
+                }
+                None => expander.diagnostics.push(Diagnostic::new(
+                    ".endm without .macro",
+                    span,
+                    text,
+                )),
+            }
+        } else if let Some((_, ref mut m, _)) = definition {
+            m.body.push(text.clone());
+        } else {
+            expander.expand_line(text, line, None, text, 0, &mut output);
+        }
+    }
+
+    if let Some((name, _, line)) = definition {
+        let text = &lines[line - 1];
+        expander.diagnostics.push(Diagnostic::new(
+            format!("unterminated macro: {}", name),
+            Span::new(line, 1, text.len()),
+            text,
+        ));
+    }
+
+    if expander.diagnostics.is_empty() {
+        Ok(output)
+    } else {
+        Err(expander.diagnostics)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::{Comp, Dest, Jump};
+
+    fn to_lines(s: &str) -> Vec<String> {
+        s.lines().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_pseudo_op() {
+        assert_eq!(
+            pseudo_op("GOTO", &["LOOP"]).unwrap(),
+            vec![
+                Command::ASymbol("LOOP".to_string()),
+                Command::C(Dest::None, Comp::ZERO, Jump::JMP),
+            ]
+        );
+        assert_eq!(pseudo_op("SET", &["R13", "-1"]).unwrap().len(), 2);
+        assert_eq!(pseudo_op("SET", &["R13", "100"]).unwrap().len(), 4);
+        assert_eq!(
+            pseudo_op("SET", &["R13", "-5"]).unwrap(),
+            vec![
+                Command::AImm(5),
+                Command::C(Dest::D, Comp::MINUS_A, Jump::None),
+                Command::ASymbol("R13".to_string()),
+                Command::C(Dest::M, Comp::D, Jump::None),
+            ]
+        );
+        assert_eq!(pseudo_op("SET", &["R13", "-32768"]).unwrap().len(), 5);
+        assert!(pseudo_op("SET", &["R13", "-32769"]).is_err());
+        assert!(pseudo_op("SET", &["R13", "-x"]).is_err());
+        assert!(pseudo_op("PUSHD", &["x"]).is_err());
+        assert!(pseudo_op("FOO", &[]).is_err());
+    }
+
+    #[test]
+    fn test_data_table() {
+        let expanded = expand(&to_lines(".data 100 5, 0, -3, -32768")).unwrap();
+        let texts = expanded.iter().map(|l| l.text.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            texts,
+            vec![
+                "@5", "D=A", "@100", "M=D", "@101", "M=0", "@3", "D=-A", "@102", "M=D", "@32767",
+                "D=-A", "D=D-1", "@103", "M=D",
+            ]
+        );
+        assert!(expand(&to_lines(".data 100 40000")).is_err());
+        assert!(expand(&to_lines(".data buffer 1")).is_err());
+    }
+
+    #[test]
+    fn test_expand() {
+        let lines = to_lines(
+            ".macro INC var, n\n@\\n\nD=A\n@\\var\nM=M+D\n.endm\nINC i, 3 // bump\nPUSHD\n(END)",
+        );
+        let expanded = expand(&lines).unwrap();
+        let texts = expanded.iter().map(|l| l.text.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            texts,
+            vec!["@3", "D=A", "@i", "M=M+D", "@SP", "AM=M+1", "A=A-1", "M=D", "(END)"]
+        );
+        assert_eq!(expanded[0].line, 7);
+        assert_eq!(expanded[0].expansion, Some(Span::new(7, 1, 8)));
+        assert_eq!(expanded[8].expansion, None);
+    }
+
+    #[test]
+    fn test_expand_unique_labels() {
+        let lines = to_lines(".macro SPIN\n(SPIN\\@)\nGOTO SPIN\\@\n.endm\nSPIN\nSPIN");
+        let expanded = expand(&lines).unwrap();
+        let texts = expanded.iter().map(|l| l.text.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            texts,
+            vec!["(SPIN1)", "@SPIN1", "0;JMP", "(SPIN2)", "@SPIN2", "0;JMP"]
+        );
+    }
+
+    #[test]
+    fn test_expand_errors() {
+        let errors = expand(&to_lines(".macro A x\n.endm\nA\n.endm\n.macro B")).unwrap_err();
+        let lines = errors.iter().map(|e| e.span.line).collect::<Vec<_>>();
+        assert_eq!(lines, vec![3, 4, 5]);
+
+        let errors = expand(&to_lines(".macro LOOP\nLOOP\n.endm\nLOOP")).unwrap_err();
+        assert_eq!(errors.len(), 1);
+    }
+}
@@ -1,20 +1,597 @@
-use anyhow;
-use std::{io, io::prelude::*};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::{io, io::prelude::*, process};
 
-use assembler::code::{assemble, parse};
-use assembler::command::{Command, Comp, Dest, Jump};
+use clap::{Parser, Subcommand};
 
-pub fn main() -> anyhow::Result<()> {
-    let lines = io::stdin()
-        .lock()
-        .lines()
-        .collect::<std::result::Result<Vec<_>, _>>()?;
+use assembler::assembler::Assembler;
+use assembler::code::{assemble_located, parse_located_partial};
+use assembler::command::{Command, Dialect};
+use assembler::diagnostic::{Diagnostic, Diagnostics, Located};
+use assembler::disassembler::disassemble_with;
+use assembler::format::Format;
+use assembler::lint::{lint, Level, Levels};
+use assembler::listing::listing;
+use assembler::object::{link, Object};
+use assembler::optimizer;
+use assembler::symbol_map::SymbolMap;
 
-    let commands = parse(&lines)?;
-    let binary = assemble(&commands)?;
+#[derive(Parser, Debug)]
+#[clap(version, about, long_about=None, args_conflicts_with_subcommands = true)]
+struct Args {
+    #[clap(subcommand)]
+    command: Option<Commands>,
 
-    for word in binary {
-        println!("{:016b}", word);
+    #[clap(flatten)]
+    assemble: AssembleArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct AssembleArgs {
+    /// .asm files or directories of them; reads stdin when omitted
+    inputs: Vec<PathBuf>,
+
+    /// Output file, or output directory for several inputs; `-` for stdout
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+
+    /// Output format: hack, bin-le, bin-be, ihex, memb, memh or rust
+    #[clap(short, long, default_value = "hack")]
+    format: Format,
+
+    /// Also write the resolved symbol map next to each output as .sym
+    #[clap(long)]
+    sym: bool,
+
+    /// Also write an address/binary/hex/source listing next to each output as .lst
+    #[clap(long)]
+    listing: bool,
+
+    /// Accept shift instructions and non-standard ALU encodings
+    #[clap(long, conflicts_with = "strict")]
+    extended: bool,
+
+    /// Reject directives, macros and anything else outside the book's Hack language
+    #[clap(long)]
+    strict: bool,
+
+    /// Run the peephole optimizer before assembling
+    #[clap(long)]
+    optimize: bool,
+
+    /// Emit relocatable objects (.obj) instead of images
+    #[clap(short = 'c', long)]
+    object: bool,
+
+    /// Object name; defaults to the input file stem
+    #[clap(long, requires = "object")]
+    name: Option<String>,
+
+    /// Report this lint as a warning (or `all`)
+    #[clap(short = 'W', long = "warn", value_name = "LINT")]
+    warn: Vec<String>,
+
+    /// Silence this lint (or `all`)
+    #[clap(short = 'A', long = "allow", value_name = "LINT")]
+    allow: Vec<String>,
+
+    /// Fail when this lint fires (or `all`)
+    #[clap(short = 'D', long = "deny", value_name = "LINT")]
+    deny: Vec<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Assemble .asm files; the default when no subcommand is given
+    Assemble(AssembleArgs),
+    /// Link relocatable objects into an image
+    Link {
+        /// .obj files or directories of them
+        #[clap(required = true)]
+        inputs: Vec<PathBuf>,
+
+        /// Output file; stdout when omitted
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+
+        /// Output format: hack, bin-le, bin-be, ihex, memb, memh or rust
+        #[clap(short, long, default_value = "hack")]
+        format: Format,
+
+        /// Also write the resolved symbol map next to the output as .sym
+        #[clap(long, requires = "output")]
+        sym: bool,
+    },
+    /// Turn a .hack image back into annotated assembly
+    Disassemble {
+        input: PathBuf,
+
+        /// Output file; stdout when omitted
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+
+        /// Input format; inferred from the file extension when omitted
+        #[clap(short, long)]
+        format: Option<Format>,
+
+        /// Symbol map used to re-attach label and variable names
+        #[clap(short, long)]
+        symbols: Option<PathBuf>,
+
+        /// Decode shift instructions and non-standard ALU encodings
+        #[clap(long)]
+        extended: bool,
+    },
+}
+
+/// Why a run failed. Each kind maps to its own exit code so scripts can tell
+/// bad input apart from a bad invocation.
+#[derive(Debug)]
+enum Failure {
+    /// The input was rejected; the message holds the rendered diagnostics.
+    Rejected(String),
+    /// The command line does not make sense.
+    Usage(String),
+    /// A file could not be read or written.
+    Io(PathBuf, io::Error),
+}
+
+impl Failure {
+    fn exit_code(&self) -> i32 {
+        match self {
+            Failure::Rejected(_) => 1,
+            Failure::Usage(_) => 2,
+            Failure::Io(..) => 3,
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Failure::Rejected(message) => write!(f, "{}", message),
+            Failure::Usage(message) => write!(f, "error: {}", message),
+            Failure::Io(path, e) => write!(f, "error: {}: {}", path.display(), e),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Failure>;
+
+fn rejected(name: &str, e: anyhow::Error) -> Failure {
+    Failure::Rejected(format!("{}: error: {}", name, e))
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>> {
+    fs::read(path).map_err(|e| Failure::Io(path.to_path_buf(), e))
+}
+
+fn read_text(path: &Path) -> Result<String> {
+    fs::read_to_string(path).map_err(|e| Failure::Io(path.to_path_buf(), e))
+}
+
+/// Writes to `path`, or to stdout when there is no path.
+fn write_output(path: Option<&Path>, bytes: &[u8]) -> Result<()> {
+    match path {
+        Some(path) => fs::write(path, bytes).map_err(|e| Failure::Io(path.to_path_buf(), e)),
+        None => io::stdout()
+            .lock()
+            .write_all(bytes)
+            .map_err(|e| Failure::Io(PathBuf::from("<stdout>"), e)),
+    }
+}
+
+/// Expands directories into the files with `extension` directly inside them.
+fn find_input_files(paths: &[PathBuf], extension: &str) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for path in paths.iter() {
+        if path.is_file() {
+            files.push(path.clone());
+        } else if path.is_dir() {
+            let mut found = fs::read_dir(path)
+                .and_then(|entries| {
+                    entries
+                        .map(|entry| entry.map(|entry| entry.path()))
+                        .collect::<io::Result<Vec<_>>>()
+                })
+                .map_err(|e| Failure::Io(path.clone(), e))?
+                .into_iter()
+                .filter(|path| path.is_file() && path.extension().is_some_and(|e| e == extension))
+                .collect::<Vec<_>>();
+            if found.is_empty() {
+                return Err(Failure::Usage(format!(
+                    "no .{} files in {}",
+                    extension,
+                    path.display()
+                )));
+            }
+            found.sort();
+            files.extend(found);
+        } else {
+            return Err(Failure::Usage(format!(
+                "{} is not a file or directory",
+                path.display()
+            )));
+        }
+    }
+    Ok(files)
+}
+
+struct Source {
+    name: String,
+    path: Option<PathBuf>,
+    lines: Vec<String>,
+}
+
+impl Source {
+    fn stdin() -> Result<Self> {
+        let lines = io::stdin()
+            .lock()
+            .lines()
+            .collect::<io::Result<Vec<_>>>()
+            .map_err(|e| Failure::Io(PathBuf::from("<stdin>"), e))?;
+        Ok(Self {
+            name: "<stdin>".to_string(),
+            path: None,
+            lines,
+        })
+    }
+
+    fn file(path: &Path) -> Result<Self> {
+        let lines = read_text(path)?.lines().map(|s| s.to_string()).collect();
+        Ok(Self {
+            name: path.display().to_string(),
+            path: Some(path.to_path_buf()),
+            lines,
+        })
+    }
+
+    fn rejected(&self, diagnostics: Vec<Diagnostic>) -> Failure {
+        Failure::Rejected(Diagnostics::new(&self.name, diagnostics).to_string())
+    }
+}
+
+struct Options {
+    format: Format,
+    sym: bool,
+    listing: bool,
+    dialect: Dialect,
+    optimize: bool,
+    object: bool,
+    name: Option<String>,
+    levels: Levels,
+}
+
+impl Options {
+    fn new(args: &AssembleArgs) -> Result<Self> {
+        let dialect = match (args.extended, args.strict) {
+            (true, _) => Dialect::Extended,
+            (_, true) => Dialect::Strict,
+            _ => Dialect::Standard,
+        };
+        let mut levels = Levels::new();
+        for (names, level) in [
+            (&args.allow, Level::Allow),
+            (&args.warn, Level::Warn),
+            (&args.deny, Level::Deny),
+        ] {
+            for name in names.iter() {
+                levels
+                    .set_by_name(name, level)
+                    .map_err(|e| Failure::Usage(e.to_string()))?;
+            }
+        }
+        Ok(Self {
+            format: args.format,
+            sym: args.sym,
+            listing: args.listing,
+            dialect,
+            optimize: args.optimize,
+            object: args.object,
+            name: args.name.clone(),
+            levels,
+        })
+    }
+
+    fn extension(&self) -> &'static str {
+        if self.object {
+            "obj"
+        } else {
+            self.format.extension()
+        }
+    }
+}
+
+fn commands(program: &[Located<Command>]) -> Vec<Command> {
+    program.iter().map(|c| c.item.clone()).collect()
+}
+
+/// Where the output for `input` goes, or `None` for stdout.
+fn output_path(
+    input: Option<&Path>,
+    output: Option<&Path>,
+    several: bool,
+    options: &Options,
+) -> Result<Option<PathBuf>> {
+    let stdout = Path::new("-");
+    let path = match (input, output) {
+        (_, Some(output)) if output == stdout => {
+            if several {
+                return Err(Failure::Usage("-o - needs a single input".to_string()));
+            }
+            None
+        }
+        (Some(input), Some(output)) if several => {
+            let name = input.file_name().unwrap_or_default();
+            Some(output.join(name).with_extension(options.extension()))
+        }
+        (_, Some(output)) => Some(output.to_path_buf()),
+        (Some(input), None) => Some(input.with_extension(options.extension())),
+        (None, None) => None,
+    };
+    if path.is_none() && (options.sym || options.listing) {
+        return Err(Failure::Usage(
+            "--sym and --listing need an output file".to_string(),
+        ));
+    }
+    Ok(path)
+}
+
+fn report_lints(
+    source: &Source,
+    program: &[Located<Command>],
+    warnings: &[assembler::lint::Warning],
+) -> Result<()> {
+    for warning in warnings.iter() {
+        let span = program[warning.index].span;
+        let diagnostic = Diagnostic::new(
+            format!("{} [{}]", warning.message, warning.lint.name()),
+            span,
+            &source.lines[span.line - 1],
+        );
+        let severity = match warning.level {
+            Level::Deny => "error",
+            _ => "warning",
+        };
+        eprintln!("{}", diagnostic.render_as(&source.name, severity));
+    }
+    let denied = warnings
+        .iter()
+        .filter(|warning| warning.level == Level::Deny)
+        .count();
+    if denied > 0 {
+        return Err(Failure::Rejected(format!(
+            "{} error(s) in {}",
+            denied, source.name
+        )));
+    }
+    Ok(())
+}
+
+fn assemble_object(
+    source: &Source,
+    program: &[Located<Command>],
+    options: &Options,
+) -> Result<Object> {
+    let name = match (&options.name, &source.path) {
+        (Some(name), _) => name.clone(),
+        (None, Some(path)) => path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default(),
+        (None, None) => {
+            return Err(Failure::Usage(
+                "--name is required for objects read from stdin".to_string(),
+            ))
+        }
+    };
+
+    let mut assembler = Assembler::new();
+    let mut diagnostics = Vec::new();
+    for command in program.iter() {
+        if let Err(e) = assembler.push_command(command.item.clone()) {
+            let source_line = &source.lines[command.span.line - 1];
+            diagnostics.push(Diagnostic::new(e.to_string(), command.span, source_line));
+        }
+    }
+    if !diagnostics.is_empty() {
+        return Err(source.rejected(diagnostics));
+    }
+    let object = assembler
+        .finish_object(&name)
+        .map_err(|e| rejected(&source.name, e))?;
+    let warnings = lint(&commands(program), &object.symbols(), &options.levels);
+    report_lints(source, program, &warnings)?;
+    if !options.optimize {
+        return Ok(object);
+    }
+
+    let (optimized, report) = optimizer::optimize(&commands(program));
+    eprintln!("{}: optimized {}", source.name, report);
+    Object::assemble(&name, &optimized).map_err(|e| rejected(&source.name, e))
+}
+
+fn assemble_source(source: &Source, output: Option<&Path>, options: &Options) -> Result<()> {
+    let (program, mut diagnostics) = parse_located_partial(&source.lines, options.dialect);
+    if !diagnostics.is_empty() {
+        // Also report what the lines that did parse get wrong, such as
+        // duplicate labels.
+        if let Err(errors) = assemble_located(&program, &source.lines) {
+            diagnostics.extend(errors);
+            diagnostics.sort_by_key(|diagnostic| (diagnostic.span.line, diagnostic.span.col));
+        }
+        return Err(source.rejected(diagnostics));
+    }
+
+    if options.object {
+        let object = assemble_object(source, &program, options)?;
+        return write_output(output, format!("{}\n", object.dump()).as_bytes());
+    }
+
+    let (binary, symbols) = assemble_located(&program, &source.lines)
+        .map_err(|diagnostics| source.rejected(diagnostics))?;
+    let warnings = lint(&commands(&program), &symbols, &options.levels);
+    report_lints(source, &program, &warnings)?;
+
+    // Optimized commands keep the spans of the source commands they came
+    // from, so the listing still shows the source lines.
+    let (program, binary, symbols) = if options.optimize {
+        let (optimized, report) = optimizer::optimize_located(&program);
+        eprintln!("{}: optimized {}", source.name, report);
+        let (binary, symbols) = assemble_located(&optimized, &source.lines)
+            .map_err(|diagnostics| source.rejected(diagnostics))?;
+        (optimized, binary, symbols)
+    } else {
+        (program, binary, symbols)
+    };
+
+    write_output(output, &options.format.write(&binary))?;
+    if let Some(output) = output {
+        if options.sym {
+            let path = output.with_extension("sym");
+            write_output(Some(&path), symbols.to_symbol_map().dump().as_bytes())?;
+        }
+        if options.listing {
+            let path = output.with_extension("lst");
+            write_output(
+                Some(&path),
+                listing(&program, &binary, &source.lines).as_bytes(),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Assembles every input on its own, so one bad file does not hide the
+/// errors in the others.
+fn run_assemble(args: AssembleArgs) -> Vec<Failure> {
+    let options = match Options::new(&args) {
+        Ok(options) => options,
+        Err(failure) => return vec![failure],
+    };
+    let inputs = match find_input_files(&args.inputs, "asm") {
+        Ok(inputs) => inputs,
+        Err(failure) => return vec![failure],
+    };
+    let output = args.output.as_deref();
+    if inputs.is_empty() {
+        let result = output_path(None, output, false, &options)
+            .and_then(|path| assemble_source(&Source::stdin()?, path.as_deref(), &options));
+        return result.err().into_iter().collect();
+    }
+
+    let several = inputs.len() > 1 || args.inputs.iter().any(|path| path.is_dir());
+    if let Some(dir) = output.filter(|path| several && *path != Path::new("-")) {
+        if let Err(e) = fs::create_dir_all(dir) {
+            return vec![Failure::Io(dir.to_path_buf(), e)];
+        }
+    }
+    inputs
+        .iter()
+        .filter_map(|input| {
+            output_path(Some(input), output, several, &options)
+                .and_then(|path| assemble_source(&Source::file(input)?, path.as_deref(), &options))
+                .err()
+        })
+        .collect()
+}
+
+fn run_link(
+    inputs: Vec<PathBuf>,
+    output: Option<PathBuf>,
+    format: Format,
+    sym: bool,
+) -> Result<()> {
+    let mut objects = Vec::new();
+    for path in find_input_files(&inputs, "obj")? {
+        let object = Object::parse(&read_text(&path)?)
+            .map_err(|e| rejected(&path.display().to_string(), e))?;
+        objects.push(object);
+    }
+    let image = link(&objects).map_err(|e| Failure::Rejected(format!("error: {}", e)))?;
+
+    write_output(output.as_deref(), &image.write(format))?;
+    if let (Some(output), true) = (output, sym) {
+        let path = output.with_extension("sym");
+        write_output(Some(&path), image.symbols.to_symbol_map().dump().as_bytes())?;
     }
     Ok(())
 }
+
+fn run_disassemble(
+    input: PathBuf,
+    output: Option<PathBuf>,
+    format: Option<Format>,
+    symbols: Option<PathBuf>,
+    dialect: Dialect,
+) -> Result<()> {
+    let name = input.display().to_string();
+    let format = format
+        .or_else(|| Format::from_path(&input))
+        .unwrap_or(Format::Hack);
+    let words = format
+        .read(&read_file(&input)?)
+        .map_err(|e| rejected(&name, e))?;
+    let symbols = match symbols {
+        Some(path) => {
+            let map = SymbolMap::parse(&read_text(&path)?)
+                .map_err(|e| rejected(&path.display().to_string(), e))?;
+            Some(map)
+        }
+        None => None,
+    };
+
+    let disassembly = disassemble_with(&words, symbols.as_ref(), dialect);
+    write_output(
+        output.as_deref(),
+        format!("{}\n", disassembly.dump()).as_bytes(),
+    )?;
+    if disassembly.has_errors() {
+        return Err(Failure::Rejected(format!(
+            "{}: error: some words are not valid instructions",
+            name
+        )));
+    }
+    Ok(())
+}
+
+pub fn main() {
+    let args = Args::parse();
+    let failures = match args.command {
+        None => run_assemble(args.assemble),
+        Some(Commands::Assemble(assemble)) => run_assemble(assemble),
+        Some(Commands::Link {
+            inputs,
+            output,
+            format,
+            sym,
+        }) => run_link(inputs, output, format, sym)
+            .err()
+            .into_iter()
+            .collect(),
+        Some(Commands::Disassemble {
+            input,
+            output,
+            format,
+            symbols,
+            extended,
+        }) => {
+            let dialect = if extended {
+                Dialect::Extended
+            } else {
+                Dialect::Standard
+            };
+            run_disassemble(input, output, format, symbols, dialect)
+                .err()
+                .into_iter()
+                .collect()
+        }
+    };
+    for failure in failures.iter() {
+        eprintln!("{}", failure);
+    }
+    if let Some(failure) = failures.first() {
+        process::exit(failure.exit_code());
+    }
+}
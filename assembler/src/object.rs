@@ -0,0 +1,338 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use super::assembler::{Assembler, Image};
+use super::code::SymbolTable;
+use super::command::Command;
+
+/// How the linker patches a code word.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Relocation {
+    /// The word is an offset into the object's own code.
+    Code,
+    /// The word is a slot in the object's static segment.
+    Static,
+    /// The word is the address of a label exported by another object.
+    Import(String),
+    /// The word is the address of a variable shared by name between objects.
+    Common(String),
+}
+
+impl Relocation {
+    fn dump(&self) -> String {
+        match self {
+            Relocation::Code => "code".to_string(),
+            Relocation::Static => "static".to_string(),
+            Relocation::Import(symbol) => format!("import {}", symbol),
+            Relocation::Common(symbol) => format!("common {}", symbol),
+        }
+    }
+
+    fn parse(fields: &[&str]) -> anyhow::Result<Self> {
+        match fields {
+            ["code"] => Ok(Relocation::Code),
+            ["static"] => Ok(Relocation::Static),
+            ["import", symbol] => Ok(Relocation::Import(symbol.to_string())),
+            ["common", symbol] => Ok(Relocation::Common(symbol.to_string())),
+            _ => anyhow::bail!("Invalid relocation: {}", fields.join(" ")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Object {
+    pub name: String,
+    pub code: Vec<u16>,
+    pub relocations: Vec<(u16, Relocation)>,
+    pub exports: BTreeMap<String, u16>,
+    pub statics: Vec<String>,
+    pub reserved: BTreeSet<u16>,
+}
+
+impl Object {
+    pub fn assemble(name: &str, program: &[Command]) -> anyhow::Result<Self> {
+        let mut assembler = Assembler::new();
+        for command in program.iter() {
+            assembler.push_command(command.clone())?;
+        }
+        assembler.finish_object(name)
+    }
+
+    pub fn imports(&self) -> BTreeSet<&str> {
+        self.relocations
+            .iter()
+            .filter_map(|(_, relocation)| match relocation {
+                Relocation::Import(symbol) => Some(symbol.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The symbols as they resolve when this object is linked on its own:
+    /// exports are labels at their offsets, statics and commons are variables
+    /// in order of first use, and imports are left out.
+    pub fn symbols(&self) -> SymbolTable {
+        let mut table = SymbolTable::predefined();
+        table.extend(
+            self.exports
+                .iter()
+                .map(|(name, offset)| (name.clone(), *offset)),
+        );
+        let labels = self.exports.keys().cloned().collect();
+
+        let names = self
+            .relocations
+            .iter()
+            .filter_map(|(index, relocation)| match relocation {
+                Relocation::Static => self.statics.get(self.code[*index as usize] as usize),
+                Relocation::Common(symbol) => Some(symbol),
+                _ => None,
+            });
+        let mut variables = Vec::new();
+        let mut next = 0x10;
+        for name in names {
+            if table.contains_key(name) {
+                continue;
+            }
+            while self.reserved.contains(&next) {
+                next += 1;
+            }
+            table.insert(name.clone(), next);
+            variables.push(name.clone());
+            next += 1;
+        }
+        SymbolTable::from_parts(table, labels, variables)
+    }
+
+    /// Text form: an `object NAME` header, `export`, `static` and `reserve`
+    /// lines, then one `0xWORD [relocation]` line per code word.
+    pub fn dump(&self) -> String {
+        let mut lines = vec![format!("object {}", self.name)];
+        lines.extend(
+            self.exports
+                .iter()
+                .map(|(name, offset)| format!("export 0x{:04x} {}", offset, name)),
+        );
+        lines.extend(self.statics.iter().map(|name| format!("static {}", name)));
+        lines.extend(
+            self.reserved
+                .iter()
+                .map(|address| format!("reserve 0x{:04x}", address)),
+        );
+
+        let relocations = self.relocations.iter().cloned().collect::<HashMap<_, _>>();
+        for (i, word) in self.code.iter().enumerate() {
+            match relocations.get(&(i as u16)) {
+                Some(relocation) => lines.push(format!("0x{:04x} {}", word, relocation.dump())),
+                None => lines.push(format!("0x{:04x}", word)),
+            }
+        }
+        lines.join("\n")
+    }
+
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let mut lines = s
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with("//"));
+        let mut object = match lines.next().map(|line| line.split_whitespace().collect::<Vec<_>>()) {
+            Some(fields) if fields.len() == 2 && fields[0] == "object" => Object {
+                name: fields[1].to_string(),
+                ..Object::default()
+            },
+            _ => anyhow::bail!("Missing object header"),
+        };
+
+        for line in lines {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            match fields.as_slice() {
+                ["export", offset, name] => {
+                    object.exports.insert(name.to_string(), parse_word(offset)?);
+                }
+                ["static", name] => object.statics.push(name.to_string()),
+                ["reserve", address] => {
+                    object.reserved.insert(parse_word(address)?);
+                }
+                [word, relocation @ ..] => {
+                    if !relocation.is_empty() {
+                        let index = object.code.len() as u16;
+                        object.relocations.push((index, Relocation::parse(relocation)?));
+                    }
+                    object.code.push(parse_word(word)?);
+                }
+                [] => {}
+            }
+        }
+        Ok(object)
+    }
+}
+
+fn parse_word(s: &str) -> anyhow::Result<u16> {
+    let word = match s.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse::<u16>(),
+    };
+    word.map_err(|_| anyhow::anyhow!("Invalid word: {}", s))
+}
+
+/// Lays `objects` out in order, resolves imports against every object's
+/// exports and allocates statics and common variables from RAM[16] in order
+/// of first use, so linking matches assembling the concatenated sources.
+pub fn link(objects: &[Object]) -> anyhow::Result<Image> {
+    let mut errors = Vec::new();
+    let mut table = SymbolTable::predefined();
+    let mut owners = HashMap::new();
+    let mut labels = Vec::new();
+    let mut bases = Vec::new();
+    let mut size = 0;
+    for object in objects.iter() {
+        bases.push(size as u16);
+        for (name, offset) in object.exports.iter() {
+            if let Some(owner) = owners.insert(name.as_str(), object.name.as_str()) {
+                errors.push(format!(
+                    "Duplicate symbol: {} (defined in {} and {})",
+                    name, owner, object.name
+                ));
+                continue;
+            }
+            table.insert(name.clone(), size as u16 + offset);
+            labels.push(name.clone());
+        }
+        size += object.code.len();
+    }
+    if size > 0x8000 {
+        errors.push(format!("Program does not fit in ROM: {} words", size));
+    }
+
+    let reserved = objects
+        .iter()
+        .flat_map(|object| object.reserved.iter().copied())
+        .collect::<BTreeSet<_>>();
+    let mut variables = Vec::new();
+    let mut next = 0x10;
+    let mut resolve = |name: &str, table: &mut HashMap<String, u16>| -> anyhow::Result<u16> {
+        if let Some(address) = table.get(name) {
+            return Ok(*address);
+        }
+        while reserved.contains(&next) {
+            next += 1;
+        }
+        if next >= 0x4000 {
+            anyhow::bail!("Out of variable memory at {}", name);
+        }
+        table.insert(name.to_string(), next);
+        variables.push(name.to_string());
+        next += 1;
+        Ok(next - 1)
+    };
+
+    let mut words: Vec<u16> = Vec::with_capacity(size);
+    let mut missing = BTreeSet::new();
+    for (object, base) in objects.iter().zip(bases) {
+        let start = words.len();
+        words.extend(object.code.iter());
+        for (index, relocation) in object.relocations.iter() {
+            let word = &mut words[start + *index as usize];
+            let resolved = match relocation {
+                Relocation::Code => Ok(word.wrapping_add(base)),
+                Relocation::Static => match object.statics.get(*word as usize) {
+                    Some(name) => resolve(name, &mut table),
+                    None => Err(anyhow::anyhow!("Invalid static slot {} in {}", word, object.name)),
+                },
+                Relocation::Import(symbol) => match table.get(symbol) {
+                    Some(address) => Ok(*address),
+                    None => {
+                        missing.insert((symbol.as_str(), object.name.as_str()));
+                        continue;
+                    }
+                },
+                Relocation::Common(symbol) => resolve(symbol, &mut table),
+            };
+            match resolved {
+                Ok(address) => *word = address,
+                Err(e) => errors.push(e.to_string()),
+            }
+        }
+    }
+    errors.extend(
+        missing
+            .into_iter()
+            .map(|(symbol, name)| format!("Undefined symbol: {} (referenced from {})", symbol, name)),
+    );
+
+    if !errors.is_empty() {
+        anyhow::bail!("{}", errors.join("\n"));
+    }
+    Ok(Image {
+        words,
+        symbols: SymbolTable::from_parts(table, labels, variables),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::code::{assemble, parse};
+
+    fn to_commands(asm: &str) -> Vec<Command> {
+        let lines = asm.lines().map(|s| s.to_string()).collect::<Vec<_>>();
+        parse(&lines).unwrap()
+    }
+
+    const MAIN: &str = "(Main.main)\n@Main.0\nM=1\n@tmp\nM=0\n@Sys.halt\n0;JMP";
+    const SYS: &str = "(Sys.init)\n@Main.main\n0;JMP\n(Sys.halt)\n@Sys.0\nD=M\n@tmp\nM=D\n@Sys.halt\n0;JMP";
+
+    #[test]
+    fn test_object() {
+        let object = Object::assemble("Main", &to_commands(MAIN)).unwrap();
+        assert_eq!(object.exports.get("Main.main"), Some(&0));
+        assert_eq!(object.statics, vec!["Main.0".to_string()]);
+        assert_eq!(object.imports(), ["Sys.halt"].into_iter().collect());
+        assert_eq!(
+            object.relocations,
+            vec![
+                (0, Relocation::Static),
+                (2, Relocation::Common("tmp".to_string())),
+                (4, Relocation::Import("Sys.halt".to_string())),
+            ]
+        );
+        assert_eq!(Object::parse(&object.dump()).unwrap(), object);
+
+        let symbols = object.symbols();
+        assert_eq!(symbols.get("Main.main"), Some(0));
+        assert_eq!(symbols.get("Sys.halt"), None);
+        let variables = symbols.variables().collect::<Vec<_>>();
+        assert_eq!(variables, vec![("Main.0", 16), ("tmp", 17)]);
+    }
+
+    #[test]
+    fn test_link_matches_whole_program() {
+        let sys = Object::assemble("Sys", &to_commands(SYS)).unwrap();
+        let main = Object::assemble("Main", &to_commands(MAIN)).unwrap();
+        let image = link(&[sys, main]).unwrap();
+
+        let whole = to_commands(&format!("{}\n{}", SYS, MAIN));
+        assert_eq!(image.words, assemble(&whole).unwrap());
+        assert_eq!(image.symbols.get("Main.main"), Some(8));
+        assert_eq!(image.symbols.get("Main.0"), Some(18));
+    }
+
+    #[test]
+    fn test_link_errors() {
+        let main = Object::assemble("Main", &to_commands(MAIN)).unwrap();
+        let err = link(std::slice::from_ref(&main)).unwrap_err();
+        assert_eq!(err.to_string(), "Undefined symbol: Sys.halt (referenced from Main)");
+
+        // A callee whose address is only loaded into D, as in a shared call
+        // site, is still imported rather than allocated as a variable.
+        let call = "(Main.main)\n@Sys.halt\nD=A\n@R15\nM=D\n@Main.main\n0;JMP";
+        let call = Object::assemble("Main", &to_commands(call)).unwrap();
+        assert_eq!(call.imports(), ["Sys.halt"].into_iter().collect());
+        let err = link(&[call]).unwrap_err();
+        assert_eq!(err.to_string(), "Undefined symbol: Sys.halt (referenced from Main)");
+
+        let mut copy = main.clone();
+        copy.name = "Copy".to_string();
+        let err = link(&[main, copy]).unwrap_err().to_string();
+        assert!(err.starts_with("Duplicate symbol: Main.main (defined in Main and Copy)"));
+    }
+}
@@ -0,0 +1,388 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use super::command::{Command, Comp, Dest, Jump};
+use super::diagnostic::Located;
+
+/// A pass rewrites the commands and keeps `origins[i]`, the index in the
+/// input of the command that `commands[i]` came from, in step with them.
+type Pass = fn(&mut Vec<Command>, &mut Vec<usize>) -> usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Register {
+    A,
+    D,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    pub words_before: usize,
+    pub words_after: usize,
+    pub passes: Vec<(&'static str, usize)>,
+}
+
+impl Report {
+    pub fn saved(&self) -> usize {
+        self.words_before - self.words_after
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} -> {} words ({} saved)",
+            self.words_before,
+            self.words_after,
+            self.saved()
+        )?;
+        for (name, count) in self.passes.iter() {
+            write!(f, "\n    {:<20}{}", name, count)?;
+        }
+        Ok(())
+    }
+}
+
+fn word_count(commands: &[Command]) -> usize {
+    commands
+        .iter()
+        .filter(|c| matches!(c, Command::AImm(_) | Command::ASymbol(_) | Command::C(..)))
+        .count()
+}
+
+/// Which of D and A/M the comp reads.
+fn comp_operands(comp: Comp) -> (bool, bool) {
+    let bits = comp.assemble();
+    if comp.prefix() == 0b101 {
+        let d = bits & 0x10 != 0;
+        (d, !d)
+    } else {
+        (bits & 0x20 == 0, bits & 0x08 == 0)
+    }
+}
+
+fn writes_a(dest: Dest) -> bool {
+    matches!(dest, Dest::A | Dest::AM | Dest::AD | Dest::AMD)
+}
+
+fn writes_d(dest: Dest) -> bool {
+    matches!(dest, Dest::D | Dest::MD | Dest::AD | Dest::AMD)
+}
+
+fn writes_m(dest: Dest) -> bool {
+    matches!(dest, Dest::M | Dest::MD | Dest::AM | Dest::AMD)
+}
+
+fn reads(command: &Command, register: Register) -> bool {
+    match command {
+        Command::C(dest, comp, jump) => {
+            let (d, y) = comp_operands(*comp);
+            match register {
+                Register::D => d,
+                Register::A => y || writes_m(*dest) || *jump != Jump::None,
+            }
+        }
+        _ => false,
+    }
+}
+
+fn writes(command: &Command, register: Register) -> bool {
+    match command {
+        Command::AImm(_) | Command::ASymbol(_) => register == Register::A,
+        Command::C(dest, _, _) => match register {
+            Register::A => writes_a(*dest),
+            Register::D => writes_d(*dest),
+        },
+        _ => false,
+    }
+}
+
+/// Whether the value of `register` after `commands[i]` is overwritten before
+/// anything reads it. Labels, jumps and the end of the program count as reads.
+fn is_dead(commands: &[Command], i: usize, register: Register) -> bool {
+    for command in commands[i + 1..].iter() {
+        match command {
+            Command::L(_) | Command::Directive(_) => return false,
+            Command::C(_, _, jump) if *jump != Jump::None => return false,
+            _ if reads(command, register) => return false,
+            _ if writes(command, register) => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+fn remove(
+    commands: &mut Vec<Command>,
+    origins: &mut Vec<usize>,
+    removed: &HashSet<usize>,
+) -> usize {
+    let mut i = 0;
+    commands.retain(|_| {
+        i += 1;
+        !removed.contains(&(i - 1))
+    });
+    let mut i = 0;
+    origins.retain(|_| {
+        i += 1;
+        !removed.contains(&(i - 1))
+    });
+    removed.len()
+}
+
+/// Drops `@X` when A is already known to hold X.
+fn redundant_loads(commands: &mut Vec<Command>, origins: &mut Vec<usize>) -> usize {
+    let mut removed = HashSet::new();
+    let mut known: Option<&Command> = None;
+    for (i, command) in commands.iter().enumerate() {
+        match command {
+            Command::AImm(_) | Command::ASymbol(_) if known == Some(command) => {
+                removed.insert(i);
+            }
+            Command::AImm(_) | Command::ASymbol(_) => known = Some(command),
+            Command::C(dest, _, _) if !writes_a(*dest) => {}
+            _ => known = None,
+        }
+    }
+    remove(commands, origins, &removed)
+}
+
+/// Replaces a push of D immediately followed by a pop into A with `A=D`, and
+/// drops a `D=A` right after `A=D`.
+fn push_pop_pairs(commands: &mut Vec<Command>, origins: &mut Vec<usize>) -> usize {
+    let sp = || Command::ASymbol("SP".to_string());
+    let push_pop = [
+        sp(),
+        Command::C(Dest::A, Comp::M, Jump::None),
+        Command::C(Dest::M, Comp::D, Jump::None),
+        sp(),
+        Command::C(Dest::M, Comp::M_PLUS_ONE, Jump::None),
+        sp(),
+        Command::C(Dest::M, Comp::M_MINUS_ONE, Jump::None),
+        sp(),
+        Command::C(Dest::A, Comp::M, Jump::None),
+        Command::C(Dest::A, Comp::M, Jump::None),
+    ];
+    let a_from_d = Command::C(Dest::A, Comp::D, Jump::None);
+    let d_from_a = Command::C(Dest::D, Comp::A, Jump::None);
+
+    let before = commands.len();
+    let mut result = Vec::with_capacity(commands.len());
+    let mut result_origins = Vec::with_capacity(commands.len());
+    let mut i = 0;
+    while i < commands.len() {
+        if commands[i..].starts_with(&push_pop) {
+            result.push(a_from_d.clone());
+            result_origins.push(origins[i]);
+            i += push_pop.len();
+        } else if commands[i] == d_from_a && result.last() == Some(&a_from_d) {
+            i += 1;
+        } else {
+            result.push(commands[i].clone());
+            result_origins.push(origins[i]);
+            i += 1;
+        }
+    }
+    *commands = result;
+    *origins = result_origins;
+    before - commands.len()
+}
+
+/// Drops register writes whose value is overwritten before it is read.
+fn dead_stores(commands: &mut Vec<Command>, origins: &mut Vec<usize>) -> usize {
+    let removed = (0..commands.len())
+        .filter(|&i| match &commands[i] {
+            Command::AImm(_) | Command::ASymbol(_) => is_dead(commands, i, Register::A),
+            Command::C(dest, _, Jump::None) if !writes_m(*dest) => {
+                (!writes_a(*dest) || is_dead(commands, i, Register::A))
+                    && (!writes_d(*dest) || is_dead(commands, i, Register::D))
+            }
+            _ => false,
+        })
+        .collect::<HashSet<_>>();
+    remove(commands, origins, &removed)
+}
+
+/// Drops everything between an unconditional jump and the next label.
+fn unreachable_code(commands: &mut Vec<Command>, origins: &mut Vec<usize>) -> usize {
+    let mut removed = HashSet::new();
+    let mut reachable = true;
+    for (i, command) in commands.iter().enumerate() {
+        match command {
+            Command::L(_) | Command::Directive(_) => reachable = true,
+            _ if !reachable => {
+                removed.insert(i);
+            }
+            Command::C(_, _, Jump::JMP) => reachable = false,
+            _ => {}
+        }
+    }
+    remove(commands, origins, &removed)
+}
+
+/// Points jumps at a label whose only instruction is `@L; 0;JMP` straight at `L`.
+fn jump_chains(commands: &mut [Command]) -> usize {
+    let mut forwards = HashMap::new();
+    for (i, command) in commands.iter().enumerate() {
+        if let Command::L(label) = command {
+            let mut rest = commands[i + 1..]
+                .iter()
+                .filter(|c| !matches!(c, Command::L(_)));
+            if let (Some(Command::ASymbol(target)), Some(Command::C(Dest::None, _, Jump::JMP))) =
+                (rest.next(), rest.next())
+            {
+                forwards.insert(label.clone(), target.clone());
+            }
+        }
+    }
+
+    let resolve = |label: &String| {
+        let mut seen = HashSet::new();
+        let mut label = label;
+        while let Some(target) = forwards.get(label) {
+            if !seen.insert(label) {
+                break;
+            }
+            label = target;
+        }
+        label.clone()
+    };
+
+    let mut count = 0;
+    for i in 0..commands.len().saturating_sub(1) {
+        let target = match (&commands[i], &commands[i + 1]) {
+            (Command::ASymbol(label), Command::C(Dest::None | Dest::D, comp, jump))
+                if *jump != Jump::None && !comp_operands(*comp).1 =>
+            {
+                resolve(label)
+            }
+            _ => continue,
+        };
+        if commands[i] != Command::ASymbol(target.clone()) && is_dead(commands, i + 1, Register::A)
+        {
+            commands[i] = Command::ASymbol(target);
+            count += 1;
+        }
+    }
+    count
+}
+
+/// Runs the peephole passes to a fixed point.
+///
+/// Commands are assumed to be reached only through labels, so code that jumps
+/// to computed addresses must keep a label on every entry point.
+pub fn optimize(commands: &[Command]) -> (Vec<Command>, Report) {
+    let (commands, _, report) = optimize_with_origins(commands);
+    (commands, report)
+}
+
+/// Like `optimize`, but every command keeps the span of the source command it
+/// came from, so listings and diagnostics can still point at the source.
+pub fn optimize_located(program: &[Located<Command>]) -> (Vec<Located<Command>>, Report) {
+    let commands = program.iter().map(|c| c.item.clone()).collect::<Vec<_>>();
+    let (commands, origins, report) = optimize_with_origins(&commands);
+    let program = commands
+        .into_iter()
+        .zip(origins)
+        .map(|(command, origin)| Located::new(command, program[origin].span))
+        .collect();
+    (program, report)
+}
+
+fn optimize_with_origins(commands: &[Command]) -> (Vec<Command>, Vec<usize>, Report) {
+    let passes: [(&'static str, Pass); 5] = [
+        ("push/pop pairs", push_pop_pairs),
+        ("redundant loads", redundant_loads),
+        ("dead stores", dead_stores),
+        ("jump chains", |c, _| jump_chains(c)),
+        ("unreachable code", unreachable_code),
+    ];
+
+    let words_before = word_count(commands);
+    let mut commands = commands.to_vec();
+    let mut origins = (0..commands.len()).collect::<Vec<_>>();
+    let mut counts = [0; 5];
+    loop {
+        let mut changed = false;
+        for (count, (_, pass)) in counts.iter_mut().zip(passes.iter()) {
+            let n = pass(&mut commands, &mut origins);
+            *count += n;
+            changed |= n > 0;
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    let report = Report {
+        words_before,
+        words_after: word_count(&commands),
+        passes: passes
+            .iter()
+            .zip(counts.iter())
+            .map(|((name, _), count)| (*name, *count))
+            .collect(),
+    };
+    (commands, origins, report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::code::{assemble, parse, parse_located};
+
+    fn to_commands(asm: &str) -> Vec<Command> {
+        let lines = asm.lines().map(|s| s.to_string()).collect::<Vec<_>>();
+        parse(&lines).unwrap()
+    }
+
+    #[test]
+    fn test_push_pop_pair() {
+        let push = "@SP\nA=M\nM=D\n@SP\nM=M+1";
+        let pop = "@SP\nM=M-1\n@SP\nA=M\nA=M";
+        let program = to_commands(&format!("@7\nD=A\n{}\n{}\nD=A\n@R13\nM=D", push, pop));
+        let (optimized, report) = optimize(&program);
+        assert_eq!(optimized, to_commands("@7\nD=A\n@R13\nM=D"));
+        assert_eq!((report.words_before, report.words_after), (15, 4));
+    }
+
+    #[test]
+    fn test_redundant_loads() {
+        let program = to_commands("@SP\nM=M-1\n@SP\nA=M\nD=M\n@SP\nM=D");
+        let (optimized, _) = optimize(&program);
+        assert_eq!(optimized, to_commands("@SP\nM=M-1\nA=M\nD=M\n@SP\nM=D"));
+
+        let program = to_commands("@SP\nM=M-1\n(LOOP)\n@SP\nM=0");
+        assert_eq!(optimize(&program).0, program);
+    }
+
+    #[test]
+    fn test_unreachable_and_jump_chains() {
+        let program = to_commands("@X\nD;JEQ\n@Y\n0;JMP\nD=M\n@1\n(X)\n@Y\n0;JMP\n(Y)\n@Y\n0;JMP");
+        let (optimized, report) = optimize(&program);
+        assert_eq!(
+            optimized,
+            to_commands("@Y\nD;JEQ\n0;JMP\n(X)\n@Y\n0;JMP\n(Y)\n@Y\n0;JMP")
+        );
+        assert_eq!(report.saved(), 3);
+    }
+
+    #[test]
+    fn test_optimize_located() {
+        let lines = ["@SP", "M=M-1", "@SP // again", "A=M"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+        let program = parse_located(&lines).unwrap();
+        let (optimized, _) = optimize_located(&program);
+        let lines = optimized.iter().map(|c| c.span.line).collect::<Vec<_>>();
+        assert_eq!(lines, vec![1, 2, 4]);
+    }
+
+    #[test]
+    fn test_keeps_live_values() {
+        let program = to_commands("@5\nD=A\n@i\nM=D\nD=M\n@j\nM=D\n@END\n(END)\n0;JMP");
+        let (optimized, _) = optimize(&program);
+        assert_eq!(assemble(&optimized).unwrap(), assemble(&program).unwrap());
+    }
+}
@@ -0,0 +1,94 @@
+use super::code::parse_line;
+use super::command::{Command, Dialect};
+
+const COMMENT_COLUMN: usize = 24;
+
+fn indent(command: &Command) -> &'static str {
+    match command {
+        Command::L(_) => "",
+        _ => "    ",
+    }
+}
+
+/// Prints commands as `.asm` text: labels flush left, everything else
+/// indented by four spaces, one command per line.
+pub fn format_program(program: &[Command]) -> String {
+    program
+        .iter()
+        .map(|command| format!("{}{}\n", indent(command), command.dump()))
+        .collect()
+}
+
+/// Reformats `.asm` source in the layout of `format_program`, keeping
+/// comments and blank lines. Trailing comments are aligned to one column and
+/// lines that are not a single command, such as macros, are only re-indented.
+pub fn format_source(lines: &[String]) -> String {
+    let mut result = String::new();
+    let mut blank = true;
+    for (i, line) in lines.iter().enumerate() {
+        let (code, comment) = match line.find("//") {
+            Some(start) => (line[..start].trim(), line[start..].trim_end()),
+            None => (line.trim(), ""),
+        };
+
+        if code.is_empty() && comment.is_empty() {
+            if !blank {
+                result.push('\n');
+            }
+            blank = true;
+            continue;
+        }
+        blank = false;
+
+        if code.is_empty() {
+            let indent = if line.starts_with("//") { "" } else { "    " };
+            result += &format!("{}{}\n", indent, comment);
+            continue;
+        }
+
+        let code = match parse_line(i + 1, code, Dialect::Extended) {
+            Ok(Some(command)) => format!("{}{}", indent(&command.item), command.item.dump()),
+            _ if code.starts_with(".macro") || code == ".endm" => code.to_string(),
+            _ => format!("    {}", code),
+        };
+        if comment.is_empty() {
+            result += &format!("{}\n", code);
+        } else if code.len() < COMMENT_COLUMN {
+            result += &format!("{:<width$}{}\n", code, comment, width = COMMENT_COLUMN);
+        } else {
+            result += &format!("{} {}\n", code, comment);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::code::parse;
+
+    fn to_lines(s: &str) -> Vec<String> {
+        s.lines().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_format_program_round_trip() {
+        let source = "@i\nM=1\n(LOOP)\n@i\nD=M;JGT\n.equ SIZE 0x10\n@SIZE\nAMD=D+1\n0;JMP";
+        let program = parse(&to_lines(source)).unwrap();
+        let formatted = format_program(&program);
+        assert!(formatted.starts_with("    @i\n    M=1\n(LOOP)\n"));
+        assert_eq!(parse(&to_lines(&formatted)).unwrap(), program);
+        assert_eq!(format_source(&to_lines(&formatted)), formatted);
+    }
+
+    #[test]
+    fn test_format_source() {
+        let source = "// Adds one\n\n\n(LOOP)   // top\n  @i  \nM = M+1\n   // bump\nD=M//check\n.macro INC x\n@\\x\n.endm\nINC i";
+        let formatted = format_source(&to_lines(source));
+        assert_eq!(
+            formatted,
+            "// Adds one\n\n(LOOP)                  // top\n    @i\n    M = M+1\n    // bump\n    D=M                 //check\n.macro INC x\n    @\\x\n.endm\n    INC i\n"
+        );
+        assert_eq!(format_source(&to_lines(&formatted)), formatted);
+    }
+}
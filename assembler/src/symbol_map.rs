@@ -0,0 +1,123 @@
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SymbolMap {
+    /// Every label at each address, in the order they were defined. VM
+    /// output often has several, such as a function and a label right at
+    /// its start.
+    pub labels: BTreeMap<u16, Vec<String>>,
+    pub variables: BTreeMap<u16, String>,
+}
+
+impl SymbolMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert_label(&mut self, address: u16, name: String) {
+        self.labels.entry(address).or_default().push(name);
+    }
+
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let mut map = Self::new();
+        for line in s.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with("//") {
+                continue;
+            }
+
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            if fields.len() != 3 {
+                anyhow::bail!("Invalid symbol map entry: {}", line);
+            }
+            let address = parse_address(fields[1])?;
+            let name = fields[2].to_string();
+            match fields[0] {
+                "label" => map.insert_label(address, name),
+                "var" => {
+                    map.variables.insert(address, name);
+                }
+                kind => anyhow::bail!("Unknown symbol kind: {}", kind),
+            }
+        }
+        Ok(map)
+    }
+
+    pub fn dump(&self) -> String {
+        let labels = self.labels.iter().flat_map(|(address, names)| {
+            names
+                .iter()
+                .map(move |name| format!("label 0x{:04x} {}", address, name))
+        });
+        let variables = self
+            .variables
+            .iter()
+            .map(|(address, name)| format!("var 0x{:04x} {}", address, name));
+        labels.chain(variables).collect::<Vec<_>>().join("\n")
+    }
+
+    /// The first label defined at `address`.
+    pub fn label(&self, address: u16) -> Option<&str> {
+        self.labels_at(address).first().map(|s| s.as_str())
+    }
+
+    pub fn labels_at(&self, address: u16) -> &[String] {
+        self.labels.get(&address).map_or(&[], |names| names.as_slice())
+    }
+
+    pub fn variable(&self, address: u16) -> Option<&str> {
+        self.variables.get(&address).map(|s| s.as_str())
+    }
+
+    /// Finds the closest label at or before `pc` and the offset from it,
+    /// taking the first label defined where there are several.
+    pub fn locate(&self, pc: u16) -> Option<(&str, u16)> {
+        self.labels
+            .range(..=pc)
+            .next_back()
+            .and_then(|(address, names)| Some((names.first()?.as_str(), pc - address)))
+    }
+}
+
+fn parse_address(s: &str) -> anyhow::Result<u16> {
+    let address = match s.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16)?,
+        None => s.parse::<u16>()?,
+    };
+    Ok(address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let text = "label 0x0004 LOOP\nlabel 0x0012 END\nvar 0x0010 i";
+        let map = SymbolMap::parse(text).unwrap();
+        assert_eq!(map.label(4), Some("LOOP"));
+        assert_eq!(map.variable(16), Some("i"));
+        assert_eq!(map.dump(), text);
+
+        assert!(SymbolMap::parse("const 0x0001 FOO").is_err());
+    }
+
+    #[test]
+    fn test_labels_at_one_address() {
+        let text = "label 0x0004 Main.main\nlabel 0x0004 Main.main$WHILE_EXP0\nlabel 0x0009 END";
+        let map = SymbolMap::parse(text).unwrap();
+        assert_eq!(map.labels_at(4), ["Main.main", "Main.main$WHILE_EXP0"]);
+        assert_eq!(map.label(4), Some("Main.main"));
+        assert_eq!(map.locate(6), Some(("Main.main", 2)));
+        assert_eq!(map.dump(), text);
+    }
+
+    #[test]
+    fn test_locate() {
+        let map = SymbolMap::parse("label 4 LOOP\nlabel 18 END").unwrap();
+        assert_eq!(map.locate(2), None);
+        assert_eq!(map.locate(4), Some(("LOOP", 0)));
+        assert_eq!(map.locate(9), Some(("LOOP", 5)));
+        assert_eq!(map.locate(20), Some(("END", 2)));
+    }
+}
@@ -0,0 +1,119 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+const ADD: &str = "@2\nD=A\n@3\nD=D+A\n@0\nM=D\n";
+const ADD_HACK: &str =
+    "0000000000000010\n1110110000010000\n0000000000000011\n1110000010010000\n0000000000000000\n1110001100001000\n";
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("assembler-cli-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn run(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_assembler"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn arg(path: &Path) -> &str {
+    path.to_str().unwrap()
+}
+
+#[test]
+fn test_stdin_to_stdout() {
+    for args in [&[][..], &["assemble"][..]] {
+        let output = run(args, ADD);
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), ADD_HACK);
+    }
+}
+
+#[test]
+fn test_output_paths() {
+    let dir = scratch_dir("paths");
+    let add = dir.join("Add.asm");
+    fs::write(&add, ADD).unwrap();
+
+    // A single input is written next to itself, with its side files.
+    let output = run(&["assemble", arg(&add), "--sym", "--listing"], "");
+    assert!(output.status.success());
+    assert_eq!(fs::read_to_string(dir.join("Add.hack")).unwrap(), ADD_HACK);
+    assert!(dir.join("Add.sym").is_file());
+    assert!(dir.join("Add.lst").is_file());
+
+    // A directory goes into the output directory, one file per input.
+    fs::write(dir.join("Loop.asm"), "(LOOP)\n@LOOP\n0;JMP\n").unwrap();
+    let out = dir.join("out");
+    let output = run(&[arg(&dir), "-o", arg(&out), "-c"], "");
+    assert!(output.status.success());
+    assert!(out.join("Add.obj").is_file());
+    assert!(out.join("Loop.obj").is_file());
+
+    // Several inputs cannot share stdout, and stdout has no side files.
+    let output = run(&[arg(&dir), "-o", "-"], "");
+    assert_eq!(output.status.code(), Some(2));
+    let output = run(&["--sym"], ADD);
+    assert_eq!(output.status.code(), Some(2));
+
+    let output = run(&[arg(&dir.join("Missing.asm"))], "");
+    assert_eq!(output.status.code(), Some(2));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_rejected_input() {
+    let output = run(&[], "@2\nD=Q\n(X)\n(X)\n");
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("<stdin>:2:"));
+    assert!(stderr.contains("<stdin>:4:"));
+
+    let output = run(&["-W", "no-such-lint"], ADD);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn test_lints_objects() {
+    let source = "(Main.main)\n(UNUSED)\n@Sys.halt\n0;JMP\n";
+    let output = run(&["-c", "--name", "Main"], source);
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("label UNUSED is never used"));
+    assert!(!stderr.contains("Sys.halt"));
+
+    let output = run(&["-c", "--name", "Main", "-D", "unused-label"], source);
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn test_optimized_listing_shows_source() {
+    let dir = scratch_dir("listing");
+    let path = dir.join("Pop.asm");
+    fs::write(&path, "@SP\nM=M-1\n@SP // reloaded\nA=M\nD=M\n").unwrap();
+    let output = run(&[arg(&path), "--optimize", "--listing"], "");
+    assert!(output.status.success());
+
+    let listing = fs::read_to_string(dir.join("Pop.lst")).unwrap();
+    let sources = listing
+        .lines()
+        .map(|line| line.rsplit(" | ").next().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(sources, vec!["@SP", "M=M-1", "A=M", "D=M"]);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,3 +1,5 @@
+mod cli;
+
 mod chapter06 {
     mod test_add;
     mod test_max;
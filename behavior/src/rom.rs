@@ -1,3 +1,7 @@
+use std::path::Path;
+
+use assembler::format::{self, Format};
+
 pub struct Rom {
     pub address: u32,
     pub out: u32,
@@ -14,6 +18,14 @@ impl Rom {
         }
     }
 
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        Ok(Self::new(format::load(path)?))
+    }
+
+    pub fn from_image(bytes: &[u8], format: Format) -> anyhow::Result<Self> {
+        Ok(Self::new(format.read(bytes)?))
+    }
+
     pub fn posedge_clk(&mut self) {}
 
     pub fn prop(&mut self) {
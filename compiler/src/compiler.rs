@@ -1,23 +1,128 @@
 use super::dump_vm::Context;
 use super::dump_vm::*;
-use super::parser::parse;
-use super::tokenizer::tokenize;
+use super::parser::parse_class;
+use super::program::Class;
+use super::semantic::{check, ClassTable, TypeCheck};
+use super::token::Token;
+use super::tokenizer::tokenize_located;
 use assembler::code::assemble;
+use assembler::diagnostic::{Diagnostics, Located};
 use assembler::command::Command as HackCommand;
 use vm::code::translate;
 use vm::command::Command as VmCommand;
 
-use anyhow::*;
+/// A parsed Jack file, with its tokens kept for locating later errors.
+pub struct Source {
+    pub filename: String,
+    pub text: String,
+    pub tokens: Vec<Located<Token>>,
+    pub class: Box<Class>,
+}
+
+/// Tokenizes and parses a Jack file, reporting every syntax error found
+/// instead of stopping at the first.
+pub fn parse_source(program: &str, filename: &str) -> Result<Source, Diagnostics> {
+    let tokens = tokenize_located(program)
+        .map_err(|diagnostics| Diagnostics::new(filename, diagnostics))?;
+    let items = tokens.iter().map(|token| token.item.clone()).collect::<Vec<_>>();
+    let class = parse_class(&items).map_err(|errors| {
+        let diagnostics = errors
+            .iter()
+            .map(|error| error.diagnostic(&tokens, program))
+            .collect();
+        Diagnostics::new(filename, diagnostics)
+    })?;
+    Ok(Source {
+        filename: filename.to_string(),
+        text: program.to_string(),
+        tokens,
+        class,
+    })
+}
+
+/// Runs the semantic checks over parsed files, resolving calls between them.
+/// `complete` says that the files are the whole program, so that a class
+/// none of them defines is an error. Gives the errors and the warnings, each
+/// grouped by file.
+pub fn check_sources(
+    sources: &[Source],
+    complete: bool,
+    type_check: TypeCheck,
+) -> (Vec<Diagnostics>, Vec<Diagnostics>) {
+    let classes = sources.iter().map(|source| &*source.class).collect::<Vec<_>>();
+    let table = ClassTable::new(&classes, complete);
+    let mut errors = vec![];
+    let mut warnings = vec![];
+    for source in sources {
+        let (warned, failed): (Vec<_>, Vec<_>) = check(&source.class, &table, type_check)
+            .into_iter()
+            .partition(|error| error.warning);
+        for (found, list) in [(failed, &mut errors), (warned, &mut warnings)] {
+            if !found.is_empty() {
+                let diagnostics = found
+                    .iter()
+                    .map(|error| error.diagnostic(&source.tokens, &source.text))
+                    .collect();
+                list.push(Diagnostics::new(&source.filename, diagnostics));
+            }
+        }
+    }
+    (errors, warnings)
+}
+
+/// Generates VM code for a file that has been checked.
+pub fn compile_source(source: &Source, extended_vm: bool) -> Vec<VmCommand> {
+    let mut context = Context::new(source.filename.clone());
+    context.extended_vm = extended_vm;
+    source.class.dump_as_vm(&mut context)
+}
 
-pub fn compile_to_vm(program: &str, filename: String) -> Result<Vec<VmCommand>> {
-    let tokens = tokenize(program).unwrap();
-    let class = parse(&tokens).unwrap();
+pub fn compile_to_vm(program: &str, filename: String) -> Result<Vec<VmCommand>, Diagnostics> {
+    compile_to_vm_with(program, filename, false)
+}
 
-    let mut context = Context::new(filename);
-    Ok(class.dump_as_vm(&mut context))
+/// Like `compile_to_vm`, but multiplies and divides with the extended `mul`
+/// and `div` commands when `extended_vm` is set.
+pub fn compile_to_vm_with(
+    program: &str,
+    filename: String,
+    extended_vm: bool,
+) -> Result<Vec<VmCommand>, Diagnostics> {
+    let source = parse_source(program, &filename)?;
+    let (mut errors, _) = check_sources(std::slice::from_ref(&source), false, TypeCheck::Off);
+    if !errors.is_empty() {
+        return Err(errors.remove(0));
+    }
+    Ok(compile_source(&source, extended_vm))
 }
 
-pub fn compile_to_hack(program: &str, filename: String) -> Result<Vec<HackCommand>> {
+pub fn compile_to_hack(program: &str, filename: String) -> anyhow::Result<Vec<HackCommand>> {
     let vm_commands = compile_to_vm(program, filename.clone())?;
     translate(&vm_commands, Some(&filename))
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_sources_gives_warnings_with_errors() {
+        let source = parse_source(
+            "class Main {
+    function void main() {
+        do Output.printInt(true);
+        let x = 1;
+        return;
+    }
+}",
+            "Main.jack",
+        )
+        .unwrap();
+        let (errors, warnings) = check_sources(&[source], true, TypeCheck::Warn);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].items.len(), 1);
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].items.len(), 1);
+        assert!(warnings[0].items[0].message.contains("Output.printInt"));
+    }
+}
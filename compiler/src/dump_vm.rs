@@ -132,6 +132,9 @@ pub struct Context {
     symbol_table: SymbolTable,
     subroutine_name: Option<String>,
     subroutine_kind: Option<SubroutineKind>,
+    /// Emit the extended VM commands, such as `mul` and `div`, instead of
+    /// calling into `Math`.
+    pub extended_vm: bool,
 }
 
 impl Context {
@@ -142,6 +145,7 @@ impl Context {
             symbol_table,
             subroutine_name: None,
             subroutine_kind: None,
+            extended_vm: false,
         }
     }
 
@@ -296,10 +300,12 @@ impl DumpVm for Term {
 }
 
 impl DumpVm for Op {
-    fn dump_as_vm(&self, _context: &mut Context) -> Vec<Command> {
+    fn dump_as_vm(&self, context: &mut Context) -> Vec<Command> {
         match self {
             Op::Plus => vec![Command::Add],
             Op::Minus => vec![Command::Sub],
+            Op::Asterisk if context.extended_vm => vec![Command::Mul],
+            Op::Slash if context.extended_vm => vec![Command::Div],
             Op::Asterisk => vec![Command::Call("Math.multiply".to_string(), 2)],
             Op::Slash => vec![Command::Call("Math.divide".to_string(), 2)],
             Op::Lt => vec![Command::Lt],
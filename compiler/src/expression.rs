@@ -1,5 +1,3 @@
-use anyhow::*;
-
 use super::foundation::*;
 use super::token::Token;
 
@@ -15,11 +13,16 @@ impl ExpressionList {
 }
 
 impl Parsable for ExpressionList {
-    fn parse(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
+    fn parse_tracked(tokens: &[Token]) -> Parsed<'_, Self> {
+        let mut failures = Failures::default();
         let (vs, rem) = option_parser(seq2_parser(
-            Expression::parse,
-            repeat_parser(seq2_parser(token_parser(Token::Comma), Expression::parse)),
-        ))(tokens)?;
+            Expression::parse_tracked,
+            repeat_parser(seq2_parser(
+                token_parser(Token::Comma),
+                Expression::parse_tracked,
+            )),
+        ))(tokens)
+        .track(&mut failures)?;
 
         let expressions = if let Some(es) = *vs {
             let (head, tails) = *es;
@@ -30,7 +33,7 @@ impl Parsable for ExpressionList {
             Collection::default()
         };
         let expressions = Box::new(expressions);
-        Ok((Box::new(Self { expressions }), rem))
+        Ok((Box::new(Self { expressions }), rem, failures))
     }
 }
 
@@ -40,26 +43,38 @@ pub enum SubroutineCall {
     InternalCall(Box<Identifier>, Box<ExpressionList>),
 }
 impl SubroutineCall {
-    pub fn parse_method_call(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
-        let (var_name, rem) = Identifier::parse(tokens)?;
-        let (_, rem) = token_parser(Token::Period)(rem)?;
-        let (method_name, rem) = Identifier::parse(rem)?;
+    pub fn parse_method_call(tokens: &[Token]) -> Parsed<'_, Self> {
+        let mut failures = Failures::default();
+        let (var_name, rem) = Identifier::parse_tracked(tokens).track(&mut failures)?;
+        let (_, rem) = token_parser(Token::Period)(rem).track(&mut failures)?;
+        let (method_name, rem) = Identifier::parse_tracked(rem).track(&mut failures)?;
         let (args, rem) =
-            surround_parser(ExpressionList::parse, Token::LParen, Token::RParen)(rem)?;
-        Ok((Box::new(Self::ExternalCall(var_name, method_name, args)), rem))
+            surround_parser(ExpressionList::parse_tracked, Token::LParen, Token::RParen)(rem)
+                .track(&mut failures)?;
+        Ok((
+            Box::new(Self::ExternalCall(var_name, method_name, args)),
+            rem,
+            failures,
+        ))
     }
 
-    pub fn parse_function_call(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
-        let (function_name, rem) = Identifier::parse(tokens)?;
+    pub fn parse_function_call(tokens: &[Token]) -> Parsed<'_, Self> {
+        let mut failures = Failures::default();
+        let (function_name, rem) = Identifier::parse_tracked(tokens).track(&mut failures)?;
         let (args, rem) =
-            surround_parser(ExpressionList::parse, Token::LParen, Token::RParen)(rem)?;
-        Ok((Box::new(Self::InternalCall(function_name, args)), rem))
+            surround_parser(ExpressionList::parse_tracked, Token::LParen, Token::RParen)(rem)
+                .track(&mut failures)?;
+        Ok((
+            Box::new(Self::InternalCall(function_name, args)),
+            rem,
+            failures,
+        ))
     }
 }
 
 impl Parsable for SubroutineCall {
-    fn parse(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
-        Self::parse_method_call(tokens).or_else(|_| Self::parse_function_call(tokens))
+    fn parse_tracked(tokens: &[Token]) -> Parsed<'_, Self> {
+        Self::parse_method_call(tokens).otherwise(|| Self::parse_function_call(tokens))
     }
 }
 
@@ -74,51 +89,62 @@ pub enum Term {
 }
 
 impl Term {
-    fn parse_constant(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
-        let (c, rem) = Constant::parse(tokens)?;
-        Ok((Box::new(Self::Constant(c)), rem))
+    fn parse_constant(tokens: &[Token]) -> Parsed<'_, Self> {
+        let mut failures = Failures::default();
+        let (c, rem) = Constant::parse_tracked(tokens).track(&mut failures)?;
+        Ok((Box::new(Self::Constant(c)), rem, failures))
     }
 
-    fn parse_variable(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
-        let (v, rem) = Identifier::parse(tokens)?;
-        Ok((Box::new(Self::Variable(v)), rem))
+    fn parse_variable(tokens: &[Token]) -> Parsed<'_, Self> {
+        let mut failures = Failures::default();
+        let (v, rem) = Identifier::parse_tracked(tokens).track(&mut failures)?;
+        Ok((Box::new(Self::Variable(v)), rem, failures))
     }
 
-    fn parse_array_access(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
-        let (var_name, rem) = Identifier::parse(tokens)?;
+    fn parse_array_access(tokens: &[Token]) -> Parsed<'_, Self> {
+        let mut failures = Failures::default();
+        let (var_name, rem) = Identifier::parse_tracked(tokens).track(&mut failures)?;
         let (index, rem) =
-            surround_parser(Expression::parse, Token::LBracket, Token::RBracket)(rem)?;
+            surround_parser(Expression::parse_tracked, Token::LBracket, Token::RBracket)(rem)
+                .track(&mut failures)?;
 
-        Ok((Box::new(Self::ArrayAccess(var_name, index)), rem))
+        Ok((Box::new(Self::ArrayAccess(var_name, index)), rem, failures))
     }
 
-    fn parse_subroutine_call(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
-        let (s, rem) = SubroutineCall::parse(tokens)?;
-        Ok((Box::new(Self::SubroutineCall(s)), rem))
+    fn parse_subroutine_call(tokens: &[Token]) -> Parsed<'_, Self> {
+        let mut failures = Failures::default();
+        let (s, rem) = SubroutineCall::parse_tracked(tokens).track(&mut failures)?;
+        Ok((Box::new(Self::SubroutineCall(s)), rem, failures))
     }
 
-    fn parse_expression(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
-        let (e, rem) = surround_parser(Expression::parse, Token::LParen, Token::RParen)(tokens)?;
+    fn parse_expression(tokens: &[Token]) -> Parsed<'_, Self> {
+        let mut failures = Failures::default();
+        let (e, rem) =
+            surround_parser(Expression::parse_tracked, Token::LParen, Token::RParen)(tokens)
+                .track(&mut failures)?;
 
-        Ok((Box::new(Self::Expression(e)), rem))
+        Ok((Box::new(Self::Expression(e)), rem, failures))
     }
 
-    fn parse_unaryop(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
-        let (op, rem) = UnaryOp::parse(tokens)?;
-        let (t, rem) = Term::parse(rem)?;
-        Ok((Box::new(Self::UnaryOp(op, t)), rem))
+    fn parse_unaryop(tokens: &[Token]) -> Parsed<'_, Self> {
+        let mut failures = Failures::default();
+        let (op, rem) = UnaryOp::parse_tracked(tokens).track(&mut failures)?;
+        let (t, rem) = Term::parse_tracked(rem).track(&mut failures)?;
+        Ok((Box::new(Self::UnaryOp(op, t)), rem, failures))
     }
 }
 
 impl Parsable for Term {
-    fn parse(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
-        Self::parse_constant(tokens)
-            .or_else(|_| Self::parse_subroutine_call(tokens))
-            .or_else(|_| Self::parse_constant(tokens))
-            .or_else(|_| Self::parse_array_access(tokens))
-            .or_else(|_| Self::parse_variable(tokens))
-            .or_else(|_| Self::parse_expression(tokens))
-            .or_else(|_| Self::parse_unaryop(tokens))
+    fn parse_tracked(tokens: &[Token]) -> Parsed<'_, Self> {
+        labelled("an expression", tokens, |tokens| {
+            Self::parse_constant(tokens)
+                .otherwise(|| Self::parse_subroutine_call(tokens))
+                .otherwise(|| Self::parse_constant(tokens))
+                .otherwise(|| Self::parse_array_access(tokens))
+                .otherwise(|| Self::parse_variable(tokens))
+                .otherwise(|| Self::parse_expression(tokens))
+                .otherwise(|| Self::parse_unaryop(tokens))
+        })
     }
 }
 
@@ -129,10 +155,12 @@ pub struct Expression {
 }
 
 impl Parsable for Expression {
-    fn parse(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
-        let (term, rem) = Term::parse(tokens)?;
-        let (extras, rem) = Collection::<Seq2<Op, Term>>::parse(rem)?;
-        Ok((Box::new(Self { term, extras }), rem))
+    fn parse_tracked(tokens: &[Token]) -> Parsed<'_, Self> {
+        let mut failures = Failures::default();
+        let (term, rem) = Term::parse_tracked(tokens).track(&mut failures)?;
+        let (extras, rem) =
+            Collection::<Seq2<Op, Term>>::parse_tracked(rem).track(&mut failures)?;
+        Ok((Box::new(Self { term, extras }), rem, failures))
     }
 }
 
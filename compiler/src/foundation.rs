@@ -1,9 +1,119 @@
-use anyhow::*;
+use std::collections::BTreeSet;
+
+use anyhow::{anyhow, Error, Result};
 
 use super::token::Token;
 
+/// What a parser returns: the item, the tokens after it and the failures it
+/// got past on the way, or the failures that stopped it.
+pub type Parsed<'a, T> = Result<(Box<T>, &'a [Token], Failures), Failures>;
+
 pub trait Parsable {
-    fn parse(tokens: &[Token]) -> Result<(Box<Self>, &[Token])>;
+    /// Parses an item, keeping what was expected where parsing failed.
+    fn parse_tracked(tokens: &[Token]) -> Parsed<'_, Self>;
+
+    fn parse(tokens: &[Token]) -> Result<(Box<Self>, &[Token]), Failures> {
+        let (item, rem, _) = Self::parse_tracked(tokens)?;
+        Ok((item, rem))
+    }
+}
+
+/// What the parsers expected where they failed, located by the number of
+/// tokens left so that it holds for any suffix of the input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Failure {
+    pub remaining: usize,
+    pub expected: BTreeSet<String>,
+}
+
+impl Failure {
+    /// Keeps the failure that got further, or everything expected at the
+    /// same point.
+    fn merge(a: Option<Failure>, b: Option<Failure>) -> Option<Failure> {
+        match (a, b) {
+            (Some(mut a), Some(b)) if a.remaining == b.remaining => {
+                a.expected.extend(b.expected);
+                Some(a)
+            }
+            (Some(a), Some(b)) => Some(if b.remaining < a.remaining { b } else { a }),
+            (a, None) => a,
+            (None, b) => b,
+        }
+    }
+}
+
+/// Optional items and alternatives succeed after a part of them failed, so
+/// parsers pass on what they expected whether they succeed or not: the
+/// furthest failure, and the failures that `recovering_parser` skipped over
+/// in the order they were found.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Failures {
+    pub furthest: Option<Failure>,
+    pub recovered: Vec<Failure>,
+}
+
+impl Failures {
+    fn expected(tokens: &[Token], expected: &[&str]) -> Self {
+        Self {
+            furthest: Some(Failure {
+                remaining: tokens.len(),
+                expected: expected.iter().map(|s| s.to_string()).collect(),
+            }),
+            recovered: Vec::new(),
+        }
+    }
+
+    fn merge(&mut self, other: Failures) {
+        self.furthest = Failure::merge(self.furthest.take(), other.furthest);
+        self.recovered.extend(other.recovered);
+    }
+}
+
+pub(crate) trait Track<'a, T> {
+    /// Adds the failures the parser recorded to `failures`, and hands all of
+    /// them on as the error if it failed.
+    fn track(self, failures: &mut Failures) -> Result<(Box<T>, &'a [Token]), Failures>;
+
+    /// Runs `other` if this parser failed, keeping the failures of both.
+    fn otherwise(self, other: impl FnOnce() -> Parsed<'a, T>) -> Parsed<'a, T>;
+}
+
+impl<'a, T> Track<'a, T> for Parsed<'a, T> {
+    fn track(self, failures: &mut Failures) -> Result<(Box<T>, &'a [Token]), Failures> {
+        match self {
+            Ok((item, rem, recorded)) => {
+                failures.merge(recorded);
+                Ok((item, rem))
+            }
+            Err(recorded) => {
+                failures.merge(recorded);
+                Err(std::mem::take(failures))
+            }
+        }
+    }
+
+    fn otherwise(self, other: impl FnOnce() -> Parsed<'a, T>) -> Parsed<'a, T> {
+        let mut failures = match self {
+            Ok(parsed) => return Ok(parsed),
+            Err(failures) => failures,
+        };
+        let (item, rem) = other().track(&mut failures)?;
+        Ok((item, rem, failures))
+    }
+}
+
+/// Parses one token with `TryFrom`, recording `what` as expected on failure.
+fn parse_token<'a, T: TryFrom<Token, Error = Error>>(
+    tokens: &'a [Token],
+    what: &[&str],
+) -> Parsed<'a, T> {
+    match tokens.split_first() {
+        Some((t, rem)) => match T::try_from(t.clone()) {
+            Ok(item) => Ok((Box::new(item), rem, Failures::default())),
+            Err(_) => Err(Failures::expected(tokens, what)),
+        },
+        None => Err(Failures::expected(tokens, what)),
+    }
 }
 
 
@@ -12,17 +122,23 @@ pub struct PlaceHolder {
 }
 
 impl Parsable for PlaceHolder {
-    fn parse(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
-        let (t, rem) = tokens.split_first().context("tokens are empty")?;
-        Ok((Box::new(Self { token: t.clone() }), rem))
+    fn parse_tracked(tokens: &[Token]) -> Parsed<'_, Self> {
+        let (t, rem) = tokens
+            .split_first()
+            .ok_or_else(|| Failures::expected(tokens, &["a token"]))?;
+        Ok((
+            Box::new(Self { token: t.clone() }),
+            rem,
+            Failures::default(),
+        ))
     }
 }
 
 pub struct Empty {}
 
 impl Parsable for Empty {
-    fn parse(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
-        Ok((Box::new(Self {}), tokens))
+    fn parse_tracked(tokens: &[Token]) -> Parsed<'_, Self> {
+        Ok((Box::new(Self {}), tokens, Failures::default()))
     }
 }
 
@@ -66,10 +182,8 @@ impl Into<Token> for Constant {
 }
 
 impl Parsable for Constant {
-    fn parse(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
-        let (t, rem) = tokens.split_first().context("tokens are empty")?;
-        let k = Self::try_from(t.clone())?;
-        Ok((Box::new(k), rem))
+    fn parse_tracked(tokens: &[Token]) -> Parsed<'_, Self> {
+        parse_token(tokens, &["a constant"])
     }
 }
 
@@ -101,10 +215,8 @@ impl Into<Token> for UnaryOp {
 }
 
 impl Parsable for UnaryOp {
-    fn parse(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
-        let (t, rem) = tokens.split_first().context("tokens are empty")?;
-        let k = Self::try_from(t.clone())?;
-        Ok((Box::new(k), rem))
+    fn parse_tracked(tokens: &[Token]) -> Parsed<'_, Self> {
+        parse_token(tokens, &["`-`", "`~`"])
     }
 }
 
@@ -157,10 +269,8 @@ impl Into<Token> for Op {
 }
 
 impl Parsable for Op {
-    fn parse(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
-        let (t, rem) = tokens.split_first().context("tokens are empty")?;
-        let k = Self::try_from(t.clone())?;
-        Ok((Box::new(k), rem))
+    fn parse_tracked(tokens: &[Token]) -> Parsed<'_, Self> {
+        parse_token(tokens, &["an operator"])
     }
 }
 
@@ -186,10 +296,8 @@ impl Into<Token> for Identifier {
 }
 
 impl Parsable for Identifier {
-    fn parse(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
-        let (t, rem) = tokens.split_first().context("tokens are empty")?;
-        let k = Self::try_from(t.clone())?;
-        Ok((Box::new(k), rem))
+    fn parse_tracked(tokens: &[Token]) -> Parsed<'_, Self> {
+        parse_token(tokens, &["an identifier"])
     }
 }
 
@@ -227,10 +335,8 @@ impl Into<Token> for Type {
 }
 
 impl Parsable for Type {
-    fn parse(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
-        let (t, rem) = tokens.split_first().context("tokens are empty")?;
-        let k = Self::try_from(t.clone())?;
-        Ok((Box::new(k), rem))
+    fn parse_tracked(tokens: &[Token]) -> Parsed<'_, Self> {
+        parse_token(tokens, &["a type"])
     }
 }
 
@@ -263,10 +369,8 @@ impl Into<Token> for ClassVarKind {
 }
 
 impl Parsable for ClassVarKind {
-    fn parse(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
-        let (t, rem) = tokens.split_first().context("tokens are empty")?;
-        let k = Self::try_from(t.clone())?;
-        Ok((Box::new(k), rem))
+    fn parse_tracked(tokens: &[Token]) -> Parsed<'_, Self> {
+        parse_token(tokens, &["`field`", "`static`"])
     }
 }
 
@@ -301,10 +405,8 @@ impl Into<Token> for SubroutineKind {
 }
 
 impl Parsable for SubroutineKind {
-    fn parse(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
-        let (t, rem) = tokens.split_first().context("tokens are empty")?;
-        let k = Self::try_from(t.clone())?;
-        Ok((Box::new(k), rem))
+    fn parse_tracked(tokens: &[Token]) -> Parsed<'_, Self> {
+        parse_token(tokens, &["`constructor`", "`function`", "`method`"])
     }
 }
 
@@ -346,10 +448,8 @@ impl Into<Token> for SubroutineType {
 }
 
 impl Parsable for SubroutineType {
-    fn parse(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
-        let (t, rem) = tokens.split_first().context("tokens are empty")?;
-        let k = Self::try_from(t.clone())?;
-        Ok((Box::new(k), rem))
+    fn parse_tracked(tokens: &[Token]) -> Parsed<'_, Self> {
+        parse_token(tokens, &["a type", "`void`"])
     }
 }
 
@@ -376,9 +476,9 @@ impl<T: Parsable> Default for Collection<T> {
 }
 
 impl<T: Parsable> Parsable for Collection<T> {
-    fn parse(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
-        let (items, rem) = repeat_parser(T::parse)(tokens)?;
-        Ok((Box::new(Self { items: *items }), rem))
+    fn parse_tracked(tokens: &[Token]) -> Parsed<'_, Self> {
+        let (items, rem, failures) = repeat_parser(T::parse_tracked)(tokens)?;
+        Ok((Box::new(Self { items: *items }), rem, failures))
     }
 }
 
@@ -395,20 +495,15 @@ impl<T: Parsable> Optional<T> {
 }
 
 impl<T: Parsable> Parsable for Optional<T> {
-    fn parse(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
-        if let anyhow::Result::Ok((item, rem_)) = T::parse(tokens) {
-            Ok((Box::new(Self { item: Some(item) }), rem_))
-        } else {
-            Ok((Box::new(Self { item: None }), tokens))
-        }
+    fn parse_tracked(tokens: &[Token]) -> Parsed<'_, Self> {
+        let (item, rem, failures) = option_parser(T::parse_tracked)(tokens)?;
+        Ok((Box::new(Self { item: *item }), rem, failures))
     }
 }
 
 impl<T: Parsable, U: Parsable> Parsable for (Box<T>, Box<U>) {
-    fn parse(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
-        let (t, rem) = T::parse(tokens)?;
-        let (u, rem_) = U::parse(rem)?;
-        Ok((Box::new((t, u)), rem_))
+    fn parse_tracked(tokens: &[Token]) -> Parsed<'_, Self> {
+        seq2_parser(T::parse_tracked, U::parse_tracked)(tokens)
     }
 }
 
@@ -417,100 +512,186 @@ pub type Seq2<T, U> = (Box<T>, Box<U>);
 pub type Seq3<T, U, V> = (Box<T>, Box<U>, Box<V>);
 pub type Seq4<T, U, V, W> = (Box<T>, Box<U>, Box<V>, Box<W>);
 
-pub(crate) fn token_parser(
-    target: Token,
-) -> impl Fn(&[Token]) -> Result<(Box<PlaceHolder>, &[Token])> {
+pub(crate) fn token_parser(target: Token) -> impl Fn(&[Token]) -> Parsed<'_, PlaceHolder> {
+    move |tokens: &[Token]| match tokens.first() {
+        Some(token) if *token == target => Ok((
+            Box::new(PlaceHolder {
+                token: target.clone(),
+            }),
+            &tokens[1..],
+            Failures::default(),
+        )),
+        _ => Err(Failures::expected(tokens, &[&format!("`{}`", target)])),
+    }
+}
+
+pub(crate) fn option_parser<T>(
+    parser: impl Fn(&[Token]) -> Parsed<'_, T>,
+) -> impl Fn(&[Token]) -> Parsed<'_, Option<Box<T>>> {
+    move |tokens: &[Token]| match parser(tokens) {
+        Ok((t, rem, failures)) => Ok((Box::new(Some(t)), rem, failures)),
+        Err(failures) => Ok((Box::new(None), tokens, failures)),
+    }
+}
+
+pub(crate) fn repeat_parser<T>(
+    parser: impl Fn(&[Token]) -> Parsed<'_, T>,
+) -> impl Fn(&[Token]) -> Parsed<'_, Vec<Box<T>>> {
     move |tokens: &[Token]| {
-        if tokens.is_empty() {
-            return Err(anyhow!("tokens are empty"));
+        let mut result = vec![];
+        let mut tokens = tokens;
+        let mut failures = Failures::default();
+        loop {
+            match parser(tokens) {
+                Ok((item, rem, recorded)) => {
+                    failures.merge(recorded);
+                    result.push(item);
+                    tokens = rem;
+                }
+                Err(recorded) => {
+                    failures.merge(recorded);
+                    break;
+                }
+            }
         }
-
-        let (token, rem) = tokens.split_at(1);
-        if token[0] == target {
-            Ok((
-                Box::new(PlaceHolder {
-                    token: target.clone(),
-                }),
-                rem,
-            ))
-        } else {
-            Err(anyhow!("invalid token: {:?}", token))
+        Ok((Box::new(result), tokens, failures))
+    }
+}
+
+/// Runs `parser`, reporting a failure at its first token as expecting
+/// `label` instead of every token the parser could have started with.
+pub(crate) fn labelled<'a, T>(
+    label: &str,
+    tokens: &'a [Token],
+    parser: impl Fn(&'a [Token]) -> Parsed<'a, T>,
+) -> Parsed<'a, T> {
+    let relabel = |mut failures: Failures| {
+        if let Some(failure) = failures
+            .furthest
+            .as_mut()
+            .filter(|f| f.remaining == tokens.len())
+        {
+            failure.expected = BTreeSet::from([label.to_string()]);
         }
+        failures
+    };
+    match parser(tokens) {
+        Ok((item, rem, failures)) => Ok((item, rem, relabel(failures))),
+        Err(failures) => Err(relabel(failures)),
     }
 }
 
-pub(crate) fn option_parser<T>(
-    parser: impl Fn(&[Token]) -> Result<(Box<T>, &[Token])>,
-) -> impl Fn(&[Token]) -> Result<(Box<Option<Box<T>>>, &[Token])> {
-    move |tokens: &[Token]| {
-        if let anyhow::Result::Ok((t, tokens)) = parser(tokens) {
-            Ok((Box::new(Some(t)), tokens))
-        } else {
-            Ok((Box::new(None), tokens))
+/// Skips the rest of an item that failed `remaining` tokens from the end:
+/// up to the next token in `starts` from there on, past the next `;` or
+/// block, or up to the `}` closing the enclosing block.
+fn skip_item<'a>(tokens: &'a [Token], remaining: usize, starts: &[Token]) -> &'a [Token] {
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate().skip(1) {
+        match token {
+            Token::LBrace => depth += 1,
+            Token::RBrace if depth == 0 => return &tokens[i..],
+            Token::RBrace => {
+                depth -= 1;
+                if depth == 0 && tokens.get(i + 1) != Some(&Token::Else) {
+                    return &tokens[i + 1..];
+                }
+            }
+            Token::Semicolon if depth == 0 => return &tokens[i + 1..],
+            _ if depth == 0 && tokens.len() - i <= remaining && starts.contains(token) => {
+                return &tokens[i..]
+            }
+            _ => {}
         }
     }
+    &tokens[tokens.len()..]
 }
 
-pub(crate) fn repeat_parser<T>(
-    parser: impl Fn(&[Token]) -> Result<(Box<T>, &[Token])>,
-) -> impl Fn(&[Token]) -> Result<(Box<Vec<Box<T>>>, &[Token])> {
+/// Like `repeat_parser`, but an item that fails after starting with one of
+/// `starts` is recorded and skipped with `skip_item`, so that one mistake
+/// does not hide the ones after it.
+pub(crate) fn recovering_parser<T>(
+    parser: impl Fn(&[Token]) -> Parsed<'_, T>,
+    starts: &'static [Token],
+) -> impl Fn(&[Token]) -> Parsed<'_, Vec<Box<T>>> {
     move |tokens: &[Token]| {
         let mut result = vec![];
         let mut tokens = tokens;
-        while let anyhow::Result::Ok((item, rem)) = parser(tokens) {
-            result.push(item);
-            tokens = rem;
+        let mut failures = Failures::default();
+        loop {
+            match parser(tokens) {
+                Ok((item, rem, recorded)) => {
+                    failures.merge(recorded);
+                    result.push(item);
+                    tokens = rem;
+                }
+                Err(Failures {
+                    furthest: Some(failure),
+                    recovered,
+                }) if tokens.first().is_some_and(|t| starts.contains(t)) => {
+                    failures.recovered.extend(recovered);
+                    tokens = skip_item(tokens, failure.remaining, starts);
+                    failures.recovered.push(failure);
+                }
+                Err(recorded) => {
+                    failures.merge(recorded);
+                    break;
+                }
+            }
         }
-        Ok((Box::new(result), tokens))
+        Ok((Box::new(result), tokens, failures))
     }
 }
 
 pub(crate) fn seq2_parser<T, U>(
-    parser_t: impl Fn(&[Token]) -> Result<(Box<T>, &[Token])>,
-    parser_u: impl Fn(&[Token]) -> Result<(Box<U>, &[Token])>,
-) -> impl Fn(&[Token]) -> Result<(Box<(Box<T>, Box<U>)>, &[Token])> {
+    parser_t: impl Fn(&[Token]) -> Parsed<'_, T>,
+    parser_u: impl Fn(&[Token]) -> Parsed<'_, U>,
+) -> impl Fn(&[Token]) -> Parsed<'_, (Box<T>, Box<U>)> {
     move |tokens: &[Token]| {
-        let (t, tokens) = parser_t(tokens)?;
-        let (u, tokens) = parser_u(tokens)?;
-        Ok((Box::new((t, u)), tokens))
+        let mut failures = Failures::default();
+        let (t, tokens) = parser_t(tokens).track(&mut failures)?;
+        let (u, tokens) = parser_u(tokens).track(&mut failures)?;
+        Ok((Box::new((t, u)), tokens, failures))
     }
 }
 
 pub(crate) fn take1_parser<T, U>(
-    parser_t: impl Fn(&[Token]) -> Result<(Box<T>, &[Token])>,
-    parser_u: impl Fn(&[Token]) -> Result<(Box<U>, &[Token])>,
-) -> impl Fn(&[Token]) -> Result<(Box<T>, &[Token])> {
+    parser_t: impl Fn(&[Token]) -> Parsed<'_, T>,
+    parser_u: impl Fn(&[Token]) -> Parsed<'_, U>,
+) -> impl Fn(&[Token]) -> Parsed<'_, T> {
     move |tokens: &[Token]| {
-        let (t, tokens) = parser_t(tokens)?;
-        let (_, tokens) = parser_u(tokens)?;
-        Ok((t, tokens))
+        let mut failures = Failures::default();
+        let (t, tokens) = parser_t(tokens).track(&mut failures)?;
+        let (_, tokens) = parser_u(tokens).track(&mut failures)?;
+        Ok((t, tokens, failures))
     }
 }
 
 
 pub(crate) fn drop1_parser<T, U>(
-    parser_t: impl Fn(&[Token]) -> Result<(Box<T>, &[Token])>,
-    parser_u: impl Fn(&[Token]) -> Result<(Box<U>, &[Token])>,
-) -> impl Fn(&[Token]) -> Result<(Box<U>, &[Token])> {
+    parser_t: impl Fn(&[Token]) -> Parsed<'_, T>,
+    parser_u: impl Fn(&[Token]) -> Parsed<'_, U>,
+) -> impl Fn(&[Token]) -> Parsed<'_, U> {
     move |tokens: &[Token]| {
-        let (_, tokens) = parser_t(tokens)?;
-        let (u, tokens) = parser_u(tokens)?;
-        Ok((u, tokens))
+        let mut failures = Failures::default();
+        let (_, tokens) = parser_t(tokens).track(&mut failures)?;
+        let (u, tokens) = parser_u(tokens).track(&mut failures)?;
+        Ok((u, tokens, failures))
     }
 }
 
 pub(crate) fn surround_parser<T>(
-    parser: impl Fn(&[Token]) -> Result<(Box<T>, &[Token])>,
+    parser: impl Fn(&[Token]) -> Parsed<'_, T>,
     surround_left: Token,
     surround_right: Token,
-) -> impl Fn(&[Token]) -> Result<(Box<T>, &[Token])> {
+) -> impl Fn(&[Token]) -> Parsed<'_, T> {
     let parser_left = token_parser(surround_left);
     let parser_right = token_parser(surround_right);
     move |tokens: &[Token]| {
-        let (_, tokens) = parser_left(tokens)?;
-        let (t, tokens) = parser(tokens)?;
-        let (_, tokens) = parser_right(tokens)?;
-        Ok((t, tokens))
+        let mut failures = Failures::default();
+        let (_, tokens) = parser_left(tokens).track(&mut failures)?;
+        let (t, tokens) = parser(tokens).track(&mut failures)?;
+        let (_, tokens) = parser_right(tokens).track(&mut failures)?;
+        Ok((t, tokens, failures))
     }
 }
 
@@ -599,4 +780,28 @@ mod tests {
         assert!(Op::parse(&[Token::False]).is_err());
         assert!(Op::parse(&[]).is_err());
     }
+
+    #[test]
+    fn test_failures() {
+        let tokens = [Token::Plus, Token::Semicolon];
+        let (ops, rem, failures) = Collection::<Op>::parse_tracked(&tokens).unwrap();
+        assert_eq!(ops.len(), 1);
+        assert_eq!(rem, [Token::Semicolon].as_slice());
+        assert_eq!(
+            failures.furthest,
+            Some(Failure {
+                remaining: 1,
+                expected: BTreeSet::from(["an operator".to_string()]),
+            })
+        );
+
+        let failures = seq2_parser(Collection::<Op>::parse_tracked, Identifier::parse_tracked)(
+            &tokens,
+        )
+        .unwrap_err();
+        assert_eq!(
+            failures.furthest.unwrap().expected,
+            BTreeSet::from(["an identifier".to_string(), "an operator".to_string()])
+        );
+    }
 }
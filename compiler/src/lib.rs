@@ -7,4 +7,5 @@ pub mod foundation;
 pub mod expression;
 pub mod statement;
 pub mod program;
+pub mod semantic;
 pub mod compiler;
\ No newline at end of file
@@ -1,16 +1,27 @@
 use std::fs::{self, File};
 use std::io::{Read, Write};
+use std::process;
 use std::vec::Vec;
 use std::{path::PathBuf, str::FromStr};
 
-use anyhow::*;
-use assembler::assembler;
+use anyhow::{anyhow, Context, Result};
+use assembler::assembler::Assembler;
+use assembler::diagnostic::{Located, Span};
+use assembler::format::Format;
+use assembler::optimizer;
+use assembler::pretty::format_program;
 use clap::Parser;
-use compiler::compiler;
+use compiler::compiler::{check_sources, compile_source, parse_source};
+use compiler::semantic::TypeCheck;
+use vm::code::{prelude, translate_indexed, translate_with, Mode};
+use vm::command::Command as VmCommand;
+use vm::optimizer as vm_optimizer;
+use vm::source_map::{Mapper, SourceMap};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum OutputFormat {
     VM,
+    Asm,
     Binary,
 }
 
@@ -20,6 +31,7 @@ impl FromStr for OutputFormat {
     fn from_str(s: &str) -> Result<Self> {
         match s {
             "vm" => Ok(Self::VM),
+            "asm" => Ok(Self::Asm),
             "bin" => Ok(Self::Binary),
             _ => Err(anyhow::anyhow!("Unknown output format: {}", s)),
         }
@@ -34,6 +46,32 @@ struct Args {
 
     #[clap(short, long)]
     output_format: OutputFormat,
+
+    /// Format of the binary image, which is written next to the input as
+    /// `Main` with the format's extension, such as `Main.hex` for `ihex`,
+    /// together with `Main.map` and the `.vm` files the map points into
+    #[clap(long, default_value = "hack")]
+    image_format: Format,
+
+    /// Optimize the generated VM commands, and for binaries also run the
+    /// peephole optimizer on the Hack assembly
+    #[clap(long)]
+    optimize: bool,
+
+    /// Translate call, return and comparisons `inline`, or as jumps into
+    /// `shared` routines for smaller binaries
+    #[clap(long, default_value = "inline")]
+    vm_mode: Mode,
+
+    /// Multiply and divide with the extended VM commands `mul` and `div`
+    /// instead of calling Math.multiply and Math.divide
+    #[clap(long)]
+    extended_vm: bool,
+
+    /// Check the types of values: `off`, `warn`, or `error` to stop on a
+    /// mismatch
+    #[clap(long, default_value = "off")]
+    type_check: TypeCheck,
 }
 
 fn find_input_files(path: PathBuf) -> Result<Vec<PathBuf>> {
@@ -83,11 +121,58 @@ fn read_inputs(path: PathBuf) -> Result<Vec<FileContent>> {
     Ok(ret)
 }
 
-fn compile_to_vm(contents: &[FileContent]) -> Result<Vec<FileContent>> {
-    let mut ret = vec![];
+/// Compiles every file to VM commands, optimizing them as one program when
+/// `optimize` is set so that functions nothing calls can be dropped. Errors
+/// in every file are printed before exiting. `complete` says that the files
+/// are the whole program, so that calls into other classes can be checked.
+/// Type mismatches are checked as `type_check` says.
+fn compile_program(
+    contents: &[FileContent],
+    complete: bool,
+    type_check: TypeCheck,
+    extended_vm: bool,
+    optimize: bool,
+) -> Result<Vec<Vec<VmCommand>>> {
+    let mut sources = vec![];
+    let mut errors = vec![];
     for content in contents {
         let filename = content.filename()?;
-        let commands = compiler::compile_to_vm(&content.content, filename)?;
+        match parse_source(&content.content, &filename) {
+            Ok(source) => sources.push(source),
+            Err(diagnostics) => errors.push(diagnostics),
+        }
+    }
+    if errors.is_empty() {
+        let (failed, warnings) = check_sources(&sources, complete, type_check);
+        for diagnostics in warnings.iter() {
+            for item in diagnostics.items.iter() {
+                eprintln!("{}", item.render_as(&diagnostics.filename, "warning"));
+            }
+        }
+        errors = failed;
+    }
+    if !errors.is_empty() {
+        for diagnostics in errors.iter() {
+            eprintln!("{}", diagnostics);
+        }
+        process::exit(1);
+    }
+
+    let mut files = sources
+        .iter()
+        .map(|source| compile_source(source, extended_vm))
+        .collect::<Vec<_>>();
+    if optimize {
+        let (optimized, report) = vm_optimizer::optimize_program(&files);
+        eprintln!("vm: optimized {}", report);
+        files = optimized;
+    }
+    Ok(files)
+}
+
+fn compile_to_vm(contents: &[FileContent], files: &[Vec<VmCommand>]) -> Vec<FileContent> {
+    let mut ret = vec![];
+    for (content, commands) in contents.iter().zip(files.iter()) {
         let output_content = commands
             .iter()
             .map(|c| c.dump())
@@ -97,39 +182,111 @@ fn compile_to_vm(contents: &[FileContent]) -> Result<Vec<FileContent>> {
         ret.push(FileContent::new(output_path, output_content));
     }
 
+    ret
+}
+
+/// Translates every file to its own `.asm` file. The first one starts with
+/// the prelude, which in `Mode::Shared` holds the runtime that all of them
+/// jump into, so the files go together in the order given.
+fn compile_to_asm(
+    contents: &[FileContent],
+    files: &[Vec<VmCommand>],
+    mode: Mode,
+) -> Result<Vec<FileContent>> {
+    let mut ret = vec![];
+    for (i, (content, vm_commands)) in contents.iter().zip(files.iter()).enumerate() {
+        let filename = content.filename()?;
+        let mut commands = if i == 0 { prelude(None, mode) } else { vec![] };
+        translate_with(vm_commands, Some(&filename), mode, |command| {
+            commands.push(command);
+            Ok(())
+        })?;
+        let output_path = content.path.with_extension("asm");
+        ret.push(FileContent::new(output_path, format_program(&commands)));
+    }
+
     Ok(ret)
 }
 
-fn compile_to_binary(contents: &[FileContent]) -> Result<String> {
-    let mut hack_commands = vec![];
-    for content in contents {
+/// Translates and assembles every file into one image, along with a source
+/// map that points each instruction at its line in the `.vm` file that
+/// `compile_to_vm` writes, one command per line.
+fn compile_to_binary(
+    contents: &[FileContent],
+    files: &[Vec<VmCommand>],
+    format: Format,
+    optimize: bool,
+    mode: Mode,
+) -> Result<(Vec<u8>, SourceMap)> {
+    let mut assembler = Assembler::new();
+    let mut mapper = Mapper::new();
+    for command in prelude(None, mode) {
+        mapper.skip(&command);
+        assembler.push_command(command)?;
+    }
+    for (content, vm_commands) in contents.iter().zip(files.iter()) {
         let filename = content.filename()?;
-        hack_commands.extend(compiler::compile_to_hack(&content.content, filename)?);
+        if optimize {
+            // The optimizer works on whole files, so only these are buffered.
+            let mut commands = vec![];
+            translate_indexed(vm_commands, Some(&filename), mode, |i, command| {
+                commands.push(Located::new(command, Span::new(i + 1, 1, 0)));
+                Ok(())
+            })?;
+            let (optimized, report) = optimizer::optimize_located(&commands);
+            eprintln!("{}: optimized {}", filename, report);
+            for command in optimized {
+                mapper.record(command.span.line - 1, &command.item);
+                assembler.push_command(command.item)?;
+            }
+        } else {
+            translate_indexed(vm_commands, Some(&filename), mode, |i, command| {
+                mapper.record(i, &command);
+                assembler.push_command(command)
+            })?;
+        }
+
+        let vm_file = content.path.with_extension("vm");
+        let vm_file = vm_file.file_name().unwrap_or_default().to_string_lossy();
+        let lines = (1..=vm_commands.len()).map(Some).collect::<Vec<_>>();
+        mapper.finish_file(&vm_file, vm_commands, &lines);
     }
 
-    let commands = assembler::code::assemble(&hack_commands)?;
-    let output_content = commands
-        .iter()
-        .map(|c| format!("{:016b}", c))
-        .collect::<Vec<_>>()
-        .join("\n");
-    Ok(output_content)
+    Ok((assembler.finish()?.write(format), mapper.finish()))
 }
 
-fn main() {
-    let args = Args::parse();
-    let input_file_contents = read_inputs(args.input).unwrap();
+fn run(args: &Args) -> Result<()> {
+    let input_file_contents = read_inputs(args.input.clone())?;
+    let files = compile_program(
+        &input_file_contents,
+        args.input.is_dir(),
+        args.type_check,
+        args.extended_vm,
+        args.optimize,
+    )?;
     match args.output_format {
         OutputFormat::VM => {
-            let contents = compile_to_vm(&input_file_contents).expect("failed to compile to vm");
+            let contents = compile_to_vm(&input_file_contents, &files);
             for content in contents.iter() {
-                let mut file = File::create(&content.path).unwrap();
-                file.write_all(content.content.as_bytes()).unwrap();
+                let mut file = File::create(&content.path)?;
+                file.write_all(content.content.as_bytes())?;
+            }
+        }
+        OutputFormat::Asm => {
+            let contents = compile_to_asm(&input_file_contents, &files, args.vm_mode)?;
+            for content in contents.iter() {
+                let mut file = File::create(&content.path)?;
+                file.write_all(content.content.as_bytes())?;
             }
         }
         OutputFormat::Binary => {
-            let content =
-                compile_to_binary(&input_file_contents).expect("failed to compile to binary");
+            let (content, source_map) = compile_to_binary(
+                &input_file_contents,
+                &files,
+                args.image_format,
+                args.optimize,
+                args.vm_mode,
+            )?;
             let output_dir_path = if args.input.is_dir() {
                 args.input.to_str().unwrap()
             } else {
@@ -138,9 +295,25 @@ fn main() {
                     .and_then(|path| path.to_str())
                     .unwrap_or("./")
             };
-            let output_path = format!("{}/{}", output_dir_path, "Main.bin");
-            let mut file = File::create(&output_path).unwrap();
-            file.write_all(content.as_bytes()).unwrap();
+            let output_path = PathBuf::from(output_dir_path)
+                .join("Main")
+                .with_extension(args.image_format.extension());
+            let mut file = File::create(&output_path)?;
+            file.write_all(&content)?;
+            fs::write(output_path.with_extension("map"), source_map.dump())?;
+            // The map points into the `.vm` files, which sit next to the image.
+            for content in compile_to_vm(&input_file_contents, &files) {
+                fs::write(&content.path, content.content)?;
+            }
         }
     };
+    Ok(())
+}
+
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(&args) {
+        eprintln!("error: {}", e);
+        process::exit(2);
+    }
 }
@@ -1,10 +1,194 @@
+use std::collections::BTreeSet;
+
 use super::program::Class;
-use super::foundation::Parsable;
+use super::foundation::{Failure, Failures, Parsable};
 use super::token::Token;
+use assembler::diagnostic::{Diagnostic, Located, Span};
+
+/// A syntax error: what the parser expected at the furthest token it got to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// Index of the token the parser stopped at, which is the number of
+    /// tokens when it ran out of them.
+    pub position: usize,
+    pub expected: BTreeSet<String>,
+}
+
+impl ParseError {
+    fn new(failure: Failure, token_count: usize) -> Self {
+        Self {
+            position: token_count - failure.remaining,
+            expected: failure.expected,
+        }
+    }
+
+    fn expected_list(&self) -> String {
+        let expected = self.expected.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+        match expected.split_last() {
+            Some((last, [])) => last.to_string(),
+            Some((last, init)) => format!("{} or {}", init.join(", "), last),
+            None => "nothing".to_string(),
+        }
+    }
+
+    fn found(token: Option<&Token>) -> String {
+        match token {
+            Some(token) => format!("`{}`", token),
+            None => "end of file".to_string(),
+        }
+    }
+
+    /// Describes the error without source positions.
+    pub fn message(&self, tokens: &[Token]) -> String {
+        format!(
+            "expected {}, found {}",
+            self.expected_list(),
+            Self::found(tokens.get(self.position))
+        )
+    }
+
+    /// Locates the error right after the last token that parsed, quoting the
+    /// source from the start of that token's statement.
+    pub fn diagnostic(&self, tokens: &[Located<Token>], source: &str) -> Diagnostic {
+        let found = Self::found(tokens.get(self.position).map(|t| &t.item));
+        let last = match self.position.checked_sub(1) {
+            Some(last) => last,
+            None => {
+                let span = tokens.first().map_or(Span::new(1, 1, 1), |t| t.span);
+                let line = source.lines().nth(span.line - 1).unwrap_or("");
+                let message = format!("expected {}, found {}", self.expected_list(), found);
+                return Diagnostic::new(message, span, line);
+            }
+        };
+
+        let end = tokens[last].span;
+        let mut start = last;
+        while start > 0 {
+            let before = &tokens[start - 1];
+            if before.span.line != end.line
+                || matches!(before.item, Token::Semicolon | Token::LBrace | Token::RBrace)
+            {
+                break;
+            }
+            start -= 1;
+        }
+        let line = source.lines().nth(end.line - 1).unwrap_or("");
+        let context = line
+            .get(tokens[start].span.col - 1..end.col - 1 + end.len)
+            .unwrap_or("");
+        let message = format!(
+            "expected {} after `{}`, found {}",
+            self.expected_list(),
+            context,
+            found
+        );
+        Diagnostic::new(message, Span::new(end.line, end.col + end.len, 1), line)
+    }
+}
+
+/// Parses a class, reporting every syntax error it can recover from rather
+/// than stopping at the first.
+pub fn parse_class(tokens: &[Token]) -> Result<Box<Class>, Vec<ParseError>> {
+    let (parsed, Failures { furthest, recovered }) = match Class::parse_tracked(tokens) {
+        Ok((class, rem, failures)) => (Some((class, rem)), failures),
+        Err(failures) => (None, failures),
+    };
+
+    let mut errors = recovered
+        .into_iter()
+        .map(|failure| ParseError::new(failure, tokens.len()))
+        .collect::<Vec<_>>();
+    let last = match parsed {
+        Some((class, [])) if errors.is_empty() => return Ok(class),
+        Some((_, [])) => None,
+        Some((_, rem)) => Some(ParseError {
+            position: tokens.len() - rem.len(),
+            expected: BTreeSet::from(["end of file".to_string()]),
+        }),
+        None => furthest.map(|failure| ParseError::new(failure, tokens.len())),
+    };
+    if let Some(last) = last {
+        if errors.iter().all(|error| error.position != last.position) {
+            errors.push(last);
+        }
+    }
+    errors.sort_by_key(|error| error.position);
+    Err(errors)
+}
+
+pub fn parse(tokens: &[Token]) -> anyhow::Result<Box<Class>> {
+    parse_class(tokens).map_err(|errors| {
+        let messages = errors
+            .iter()
+            .map(|error| error.message(tokens))
+            .collect::<Vec<_>>();
+        anyhow::anyhow!(messages.join("\n"))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::tokenize_located;
+
+    fn errors(source: &str) -> Vec<String> {
+        let tokens = tokenize_located(source).unwrap();
+        let items = tokens.iter().map(|t| t.item.clone()).collect::<Vec<_>>();
+        parse_class(&items)
+            .unwrap_err()
+            .iter()
+            .map(|error| {
+                let diagnostic = error.diagnostic(&tokens, source);
+                format!(
+                    "{}:{}: {}",
+                    diagnostic.span.line, diagnostic.span.col, diagnostic.message
+                )
+            })
+            .collect()
+    }
 
-use anyhow::*;
+    #[test]
+    fn test_reports_furthest_failure() {
+        assert_eq!(
+            errors("class Main {\n  function void main() {\n    let x = 5\n  }\n}"),
+            vec!["3:14: expected `;` or an operator after `let x = 5`, found `}`"]
+        );
+        assert_eq!(
+            errors("class Main {\n  function void f() {\n    do f(1,);\n  }\n}"),
+            vec!["3:12: expected an expression after `do f(1,`, found `)`"]
+        );
+        assert_eq!(
+            errors("class Main {\n  var int x;\n}"),
+            vec![
+                "1:13: expected `constructor`, `field`, `function`, `method`, `static` or `}` \
+                 after `class Main {`, found `var`"
+            ]
+        );
+        assert_eq!(
+            errors("class Main { } }"),
+            vec!["1:15: expected end of file after `}`, found `}`"]
+        );
+    }
 
-pub fn parse(tokens: &[Token]) -> Result<Box<Class>> {
-    let (c, _) = Class::parse(tokens)?;
-    Ok(c)
+    #[test]
+    fn test_recovers_from_errors() {
+        let source = "class Main {
+  field int x = 1;
+  function void main() {
+    let x = ;
+    if (x) { do f(; }
+    let y = 1 let z = 2;
+    return;
+  }
+  method void g( { return; }
+  function int h() { return 1; }
+}";
+        let messages = errors(source);
+        assert_eq!(messages.len(), 5);
+        assert!(messages[0].starts_with("2:14: expected "));
+        assert!(messages[1].starts_with("4:12: expected an expression"));
+        assert!(messages[2].starts_with("5:19: expected `)` or an expression"));
+        assert!(messages[3].starts_with("6:14: expected `;` or an operator"));
+        assert!(messages[4].starts_with("9:17: expected `)` or a type"));
+    }
 }
@@ -1,5 +1,3 @@
-use anyhow::*;
-
 use super::foundation::*;
 use super::statement::*;
 use super::token::Token;
@@ -11,15 +9,17 @@ pub struct VarDec {
 }
 
 impl Parsable for VarDec {
-    fn parse(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
-        let (_, rem) = token_parser(Token::Var)(tokens)?;
-        let (type_, rem) = Type::parse(rem)?;
+    fn parse_tracked(tokens: &[Token]) -> Parsed<'_, Self> {
+        let mut failures = Failures::default();
+        let (_, rem) = token_parser(Token::Var)(tokens).track(&mut failures)?;
+        let (type_, rem) = Type::parse_tracked(rem).track(&mut failures)?;
         let (var_names, rem) = repeat_parser(take1_parser(
-            Identifier::parse,
+            Identifier::parse_tracked,
             option_parser(token_parser(Token::Comma)),
-        ))(rem)?;
-        let (_, rem) = token_parser(Token::Semicolon)(rem)?;
-        Ok((Box::new(Self { type_, var_names }), rem))
+        ))(rem)
+        .track(&mut failures)?;
+        let (_, rem) = token_parser(Token::Semicolon)(rem).track(&mut failures)?;
+        Ok((Box::new(Self { type_, var_names }), rem, failures))
     }
 }
 
@@ -30,18 +30,24 @@ pub struct SubroutineBody {
 }
 
 impl Parsable for SubroutineBody {
-    fn parse(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
+    fn parse_tracked(tokens: &[Token]) -> Parsed<'_, Self> {
+        let mut failures = Failures::default();
         let (ret, rem) = surround_parser(
-            seq2_parser(repeat_parser(VarDec::parse), Statements::parse),
+            seq2_parser(
+                recovering_parser(VarDec::parse_tracked, &[Token::Var]),
+                Statements::parse_tracked,
+            ),
             Token::LBrace,
             Token::RBrace,
-        )(tokens)?;
+        )(tokens)
+        .track(&mut failures)?;
         Ok((
             Box::new(Self {
                 var_decs: ret.0,
                 statements: ret.1,
             }),
             rem,
+            failures,
         ))
     }
 }
@@ -58,14 +64,16 @@ impl ParameterList {
 }
 
 impl Parsable for ParameterList {
-    fn parse(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
+    fn parse_tracked(tokens: &[Token]) -> Parsed<'_, Self> {
+        let mut failures = Failures::default();
         let (vs, rem) = option_parser(seq2_parser(
-            seq2_parser(Type::parse, Identifier::parse),
+            seq2_parser(Type::parse_tracked, Identifier::parse_tracked),
             repeat_parser(drop1_parser(
                 token_parser(Token::Comma),
-                seq2_parser(Type::parse, Identifier::parse),
+                seq2_parser(Type::parse_tracked, Identifier::parse_tracked),
             )),
-        ))(tokens)?;
+        ))(tokens)
+        .track(&mut failures)?;
 
         let mut params = vec![];
         if let Some(es) = *vs {
@@ -75,7 +83,7 @@ impl Parsable for ParameterList {
                 params.push(vs);
             }
         }
-        Ok((Box::new(Self { params }), rem))
+        Ok((Box::new(Self { params }), rem, failures))
     }
 }
 
@@ -89,13 +97,15 @@ pub struct SubroutineDec {
 }
 
 impl Parsable for SubroutineDec {
-    fn parse(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
-        let (kind, rem) = SubroutineKind::parse(tokens)?;
-        let (type_, rem) = SubroutineType::parse(rem)?;
-        let (subroutine_name, rem) = Identifier::parse(rem)?;
+    fn parse_tracked(tokens: &[Token]) -> Parsed<'_, Self> {
+        let mut failures = Failures::default();
+        let (kind, rem) = SubroutineKind::parse_tracked(tokens).track(&mut failures)?;
+        let (type_, rem) = SubroutineType::parse_tracked(rem).track(&mut failures)?;
+        let (subroutine_name, rem) = Identifier::parse_tracked(rem).track(&mut failures)?;
         let (params, rem) =
-            surround_parser(ParameterList::parse, Token::LParen, Token::RParen)(rem)?;
-        let (body, rem) = SubroutineBody::parse(rem)?;
+            surround_parser(ParameterList::parse_tracked, Token::LParen, Token::RParen)(rem)
+                .track(&mut failures)?;
+        let (body, rem) = SubroutineBody::parse_tracked(rem).track(&mut failures)?;
         Ok((
             Box::new(Self {
                 kind,
@@ -105,6 +115,7 @@ impl Parsable for SubroutineDec {
                 body,
             }),
             rem,
+            failures,
         ))
     }
 }
@@ -117,14 +128,16 @@ pub struct ClassVarDec {
 }
 
 impl Parsable for ClassVarDec {
-    fn parse(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
-        let (kind, rem) = ClassVarKind::parse(tokens)?;
-        let (type_, rem) = Type::parse(rem)?;
+    fn parse_tracked(tokens: &[Token]) -> Parsed<'_, Self> {
+        let mut failures = Failures::default();
+        let (kind, rem) = ClassVarKind::parse_tracked(tokens).track(&mut failures)?;
+        let (type_, rem) = Type::parse_tracked(rem).track(&mut failures)?;
         let (var_names, rem) = repeat_parser(take1_parser(
-            Identifier::parse,
+            Identifier::parse_tracked,
             option_parser(token_parser(Token::Comma)),
-        ))(rem)?;
-        let (_, rem) = token_parser(Token::Semicolon)(rem)?;
+        ))(rem)
+        .track(&mut failures)?;
+        let (_, rem) = token_parser(Token::Semicolon)(rem).track(&mut failures)?;
 
         Ok((
             Box::new(Self {
@@ -133,6 +146,7 @@ impl Parsable for ClassVarDec {
                 var_names: var_names,
             }),
             rem,
+            failures,
         ))
     }
 }
@@ -146,7 +160,8 @@ pub struct Class {
 }
 
 impl Parsable for Class {
-    fn parse(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
+    fn parse_tracked(tokens: &[Token]) -> Parsed<'_, Self> {
+        let mut failures = Failures::default();
         let string_constants = tokens
             .iter()
             .filter_map(|t| match t {
@@ -155,12 +170,18 @@ impl Parsable for Class {
             })
             .collect::<Vec<_>>();
 
-        let (_, rem) = token_parser(Token::Class)(tokens)?;
-        let (class_name, rem) = Identifier::parse(rem)?;
-        let (_, rem) = token_parser(Token::LBrace)(rem)?;
-        let (var_decs, rem) = repeat_parser(ClassVarDec::parse)(rem)?;
-        let (subroutine_decs, rem) = repeat_parser(SubroutineDec::parse)(rem)?;
-        let (_, rem) = token_parser(Token::RBrace)(rem)?;
+        let (_, rem) = token_parser(Token::Class)(tokens).track(&mut failures)?;
+        let (class_name, rem) = Identifier::parse_tracked(rem).track(&mut failures)?;
+        let (_, rem) = token_parser(Token::LBrace)(rem).track(&mut failures)?;
+        let (var_decs, rem) =
+            recovering_parser(ClassVarDec::parse_tracked, &[Token::Field, Token::Static])(rem)
+                .track(&mut failures)?;
+        let (subroutine_decs, rem) = recovering_parser(
+            SubroutineDec::parse_tracked,
+            &[Token::Constructor, Token::Function, Token::Method],
+        )(rem)
+        .track(&mut failures)?;
+        let (_, rem) = token_parser(Token::RBrace)(rem).track(&mut failures)?;
         Ok((
             Box::new(Self {
                 class_name,
@@ -169,6 +190,7 @@ impl Parsable for Class {
                 string_constants,
             }),
             rem,
+            failures,
         ))
     }
 }
@@ -0,0 +1,975 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use super::dump_vm::SymbolKind;
+use super::expression::*;
+use super::foundation::*;
+use super::parser::parse_class;
+use super::program::*;
+use super::statement::*;
+use super::token::Token;
+use super::tokenizer::tokenize;
+use assembler::diagnostic::{Diagnostic, Located, Span};
+
+/// Declarations of the Jack OS classes, for checking calls into them.
+const OS_CLASSES: [&str; 8] = [
+    "class Math {
+        function void init() {}
+        function int abs(int x) {}
+        function int multiply(int x, int y) {}
+        function int divide(int x, int y) {}
+        function int min(int x, int y) {}
+        function int max(int x, int y) {}
+        function int sqrt(int x) {}
+    }",
+    "class String {
+        constructor String new(int maxLength) {}
+        method void dispose() {}
+        method int length() {}
+        method char charAt(int j) {}
+        method void setCharAt(int j, char c) {}
+        method String appendChar(char c) {}
+        method void eraseLastChar() {}
+        method int intValue() {}
+        method void setInt(int val) {}
+        function char backSpace() {}
+        function char doubleQuote() {}
+        function char newLine() {}
+    }",
+    "class Array {
+        function Array new(int size) {}
+        method void dispose() {}
+    }",
+    "class Output {
+        function void init() {}
+        function void moveCursor(int i, int j) {}
+        function void printChar(char c) {}
+        function void printString(String s) {}
+        function void printInt(int i) {}
+        function void println() {}
+        function void backSpace() {}
+    }",
+    "class Screen {
+        function void init() {}
+        function void clearScreen() {}
+        function void setColor(boolean b) {}
+        function void drawPixel(int x, int y) {}
+        function void drawLine(int x1, int y1, int x2, int y2) {}
+        function void drawRectangle(int x1, int y1, int x2, int y2) {}
+        function void drawCircle(int x, int y, int r) {}
+    }",
+    "class Keyboard {
+        function void init() {}
+        function char keyPressed() {}
+        function char readChar() {}
+        function String readLine(String message) {}
+        function int readInt(String message) {}
+    }",
+    "class Memory {
+        function void init() {}
+        function int peek(int address) {}
+        function void poke(int address, int value) {}
+        function Array alloc(int size) {}
+        function void deAlloc(Array o) {}
+    }",
+    "class Sys {
+        function void init() {}
+        function void halt() {}
+        function void error(int errorCode) {}
+        function void wait(int duration) {}
+    }",
+];
+
+/// What callers of a subroutine need to know about it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    pub kind: SubroutineKind,
+    pub return_type: SubroutineType,
+    pub params: Vec<Type>,
+}
+
+/// The subroutines of every class in a program.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClassTable {
+    classes: HashMap<String, HashMap<String, Signature>>,
+    /// Whether the table holds the whole program, so that a class missing
+    /// from it is an error rather than one compiled separately.
+    complete: bool,
+}
+
+impl ClassTable {
+    /// Collects `classes` and the OS classes that they do not replace.
+    pub fn new(classes: &[&Class], complete: bool) -> Self {
+        let mut table = Self {
+            classes: HashMap::new(),
+            complete,
+        };
+        for source in OS_CLASSES {
+            let tokens = tokenize(source).expect("OS declarations tokenize");
+            let class = parse_class(&tokens).expect("OS declarations parse");
+            table.add(&class);
+        }
+        for class in classes {
+            table.add(class);
+        }
+        table
+    }
+
+    fn add(&mut self, class: &Class) {
+        let subroutines = class
+            .subroutine_decs
+            .iter()
+            .map(|dec| {
+                let signature = Signature {
+                    kind: *dec.kind,
+                    return_type: (*dec.type_).clone(),
+                    params: dec.params.params.iter().map(|p| (*p.0).clone()).collect(),
+                };
+                (dec.subroutine_name.0.clone(), signature)
+            })
+            .collect();
+        self.classes.insert(class.class_name.0.clone(), subroutines);
+    }
+
+    pub fn contains(&self, class: &str) -> bool {
+        self.classes.contains_key(class)
+    }
+
+    pub fn get(&self, class: &str, subroutine: &str) -> Option<&Signature> {
+        self.classes.get(class)?.get(subroutine)
+    }
+}
+
+/// What to do about values of the wrong type, which Jack itself never
+/// checks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TypeCheck {
+    #[default]
+    Off,
+    /// Report mismatches as warnings, which do not stop compilation.
+    Warn,
+    Error,
+}
+
+impl FromStr for TypeCheck {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "off" => Ok(Self::Off),
+            "warn" => Ok(Self::Warn),
+            "error" => Ok(Self::Error),
+            _ => Err(anyhow::anyhow!("Unknown type check mode: {}", s)),
+        }
+    }
+}
+
+/// The type of an expression as far as the checker can tell.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ValueType {
+    Int,
+    Char,
+    Boolean,
+    Class(String),
+    Null,
+    /// Anything, such as an array element. It fits wherever it is used.
+    Unknown,
+}
+
+impl ValueType {
+    fn is_numeric(&self) -> bool {
+        matches!(self, Self::Int | Self::Char | Self::Unknown)
+    }
+
+    /// Whether a value of this type can be stored where `to` is expected.
+    /// `int` and `char` mix freely, and `Array` stands for any object since
+    /// Jack code uses it as a raw pointer, e.g. for `Memory.alloc`.
+    fn fits(&self, to: &Self) -> bool {
+        match (self, to) {
+            (Self::Unknown, _) | (_, Self::Unknown) => true,
+            (Self::Boolean, Self::Boolean) => true,
+            (Self::Null, Self::Class(_)) => true,
+            (Self::Class(from), Self::Class(to)) => from == to || from == "Array" || to == "Array",
+            (from, to) => from.is_numeric() && to.is_numeric(),
+        }
+    }
+}
+
+impl From<&Type> for ValueType {
+    fn from(type_: &Type) -> Self {
+        match type_ {
+            Type::Int => Self::Int,
+            Type::Char => Self::Char,
+            Type::Boolean => Self::Boolean,
+            Type::Class(name) => Self::Class(name.clone()),
+        }
+    }
+}
+
+impl From<&SubroutineType> for ValueType {
+    fn from(type_: &SubroutineType) -> Self {
+        match type_ {
+            SubroutineType::Int => Self::Int,
+            SubroutineType::Char => Self::Char,
+            SubroutineType::Boolean => Self::Boolean,
+            SubroutineType::Void => Self::Unknown,
+            SubroutineType::Class(name) => Self::Class(name.clone()),
+        }
+    }
+}
+
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Int => write!(f, "int"),
+            Self::Char => write!(f, "char"),
+            Self::Boolean => write!(f, "boolean"),
+            Self::Class(name) => write!(f, "{}", name),
+            Self::Null => write!(f, "null"),
+            Self::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// A program that parses but cannot mean anything, such as a use of an
+/// undeclared variable, or with type checking on, a value of the wrong type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SemanticError {
+    /// Which identifier, keyword or operator token of the class the error
+    /// is at, counting from 0 in source order; `is_anchor` lists the kinds.
+    /// The AST has no positions, so this is what `diagnostic` finds the
+    /// source location by.
+    pub anchor: usize,
+    pub message: String,
+    /// Set for type mismatches under `TypeCheck::Warn`.
+    pub warning: bool,
+}
+
+impl SemanticError {
+    fn is_anchor(token: &Token) -> bool {
+        matches!(
+            token,
+            Token::Identifier(_)
+                | Token::This
+                | Token::Return
+                | Token::If
+                | Token::While
+                | Token::Plus
+                | Token::Minus
+                | Token::Asterisk
+                | Token::Slash
+                | Token::And
+                | Token::Or
+                | Token::Lt
+                | Token::Gt
+                | Token::Eq
+                | Token::Tilde
+        )
+    }
+
+    pub fn diagnostic(&self, tokens: &[Located<Token>], source: &str) -> Diagnostic {
+        let span = tokens
+            .iter()
+            .filter(|token| Self::is_anchor(&token.item))
+            .nth(self.anchor)
+            .map_or(Span::new(1, 1, 1), |token| token.span);
+        let line = source.lines().nth(span.line - 1).unwrap_or("");
+        Diagnostic::new(self.message.clone(), span, line)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Variable {
+    kind: SymbolKind,
+    type_: Type,
+}
+
+struct Subroutine {
+    name: String,
+    kind: SubroutineKind,
+    return_type: SubroutineType,
+    locals: HashMap<String, Variable>,
+}
+
+/// Walks a class in source order, counting anchors as `SemanticError`
+/// describes.
+struct Checker<'a> {
+    classes: &'a ClassTable,
+    class_name: String,
+    fields: HashMap<String, Variable>,
+    subroutine: Option<Subroutine>,
+    anchors: usize,
+    type_check: TypeCheck,
+    errors: Vec<SemanticError>,
+}
+
+impl Checker<'_> {
+    fn anchor(&mut self) -> usize {
+        self.anchors += 1;
+        self.anchors - 1
+    }
+
+    fn error(&mut self, anchor: usize, message: String) {
+        self.errors.push(SemanticError {
+            anchor,
+            message,
+            warning: false,
+        });
+    }
+
+    /// Reports a value of the wrong type, as `type_check` says to.
+    fn mismatch(&mut self, anchor: usize, message: String) {
+        if self.type_check != TypeCheck::Off {
+            self.errors.push(SemanticError {
+                anchor,
+                message,
+                warning: self.type_check == TypeCheck::Warn,
+            });
+        }
+    }
+
+    fn subroutine(&self) -> &Subroutine {
+        self.subroutine
+            .as_ref()
+            .expect("statements are in a subroutine")
+    }
+
+    fn full_name(&self) -> String {
+        format!("{}.{}", self.class_name, self.subroutine().name)
+    }
+
+    fn is_function(&self) -> bool {
+        self.subroutine().kind == SubroutineKind::Function
+    }
+
+    fn check_type(&mut self, type_: &Type) {
+        if let Type::Class(name) = type_ {
+            let anchor = self.anchor();
+            if self.classes.complete && !self.classes.contains(name) {
+                self.error(anchor, format!("unknown class `{}`", name));
+            }
+        }
+    }
+
+    fn check_subroutine_type(&mut self, type_: &SubroutineType) {
+        if let SubroutineType::Class(name) = type_ {
+            self.check_type(&Type::Class(name.clone()));
+        }
+    }
+
+    fn declare_local(&mut self, name: &str, kind: SymbolKind, type_: &Type) {
+        let anchor = self.anchor();
+        let variable = Variable {
+            kind,
+            type_: type_.clone(),
+        };
+        let subroutine = self
+            .subroutine
+            .as_mut()
+            .expect("locals are in a subroutine");
+        if subroutine
+            .locals
+            .insert(name.to_string(), variable)
+            .is_some()
+        {
+            let message = format!("`{}` is declared twice in {}", name, self.full_name());
+            self.error(anchor, message);
+        }
+    }
+
+    /// Looks up a variable, reporting it when it is not declared or is a
+    /// field used where there is no object.
+    fn variable(&mut self, anchor: usize, name: &str) -> Option<Variable> {
+        let found = self
+            .subroutine()
+            .locals
+            .get(name)
+            .or_else(|| self.fields.get(name))
+            .cloned();
+        match &found {
+            None => {
+                let message = format!("undeclared variable `{}` in {}", name, self.full_name());
+                self.error(anchor, message);
+            }
+            Some(variable) if variable.kind == SymbolKind::Field && self.is_function() => {
+                let message = format!(
+                    "field `{}` cannot be used in function {}",
+                    name,
+                    self.full_name()
+                );
+                self.error(anchor, message);
+            }
+            Some(_) => {}
+        }
+        found
+    }
+
+    fn check_class(&mut self, class: &Class) {
+        self.anchor();
+        for var_dec in class.var_decs.iter() {
+            self.check_type(&var_dec.type_);
+            for name in var_dec.var_names.iter() {
+                let anchor = self.anchor();
+                let variable = Variable {
+                    kind: (*var_dec.kind).into(),
+                    type_: (*var_dec.type_).clone(),
+                };
+                if self.fields.insert(name.0.clone(), variable).is_some() {
+                    let message = format!("`{}` is declared twice in {}", name.0, self.class_name);
+                    self.error(anchor, message);
+                }
+            }
+        }
+
+        let mut names = Vec::new();
+        for subroutine_dec in class.subroutine_decs.iter() {
+            self.check_subroutine(subroutine_dec, &mut names);
+        }
+    }
+
+    fn check_subroutine(&mut self, dec: &SubroutineDec, names: &mut Vec<String>) {
+        self.check_subroutine_type(&dec.type_);
+        let name_anchor = self.anchor();
+        let name = dec.subroutine_name.0.clone();
+        if names.contains(&name) {
+            let message = format!("`{}.{}` is declared twice", self.class_name, name);
+            self.error(name_anchor, message);
+        }
+        names.push(name.clone());
+
+        self.subroutine = Some(Subroutine {
+            name,
+            kind: *dec.kind,
+            return_type: (*dec.type_).clone(),
+            locals: HashMap::new(),
+        });
+        for param in dec.params.params.iter() {
+            self.check_type(&param.0);
+            self.declare_local(&param.1 .0, SymbolKind::Argument, &param.0);
+        }
+        for var_dec in dec.body.var_decs.iter() {
+            self.check_type(&var_dec.type_);
+            for name in var_dec.var_names.iter() {
+                self.declare_local(&name.0, SymbolKind::Var, &var_dec.type_);
+            }
+        }
+
+        self.check_statements(&dec.body.statements);
+        if !always_returns(&dec.body.statements) {
+            let message = format!("{} can end without `return`", self.full_name());
+            self.error(name_anchor, message);
+        }
+        if *dec.kind == SubroutineKind::Constructor
+            && *dec.type_ != SubroutineType::Class(self.class_name.clone())
+        {
+            let message = format!("constructor {} must return its own class", self.full_name());
+            self.error(name_anchor, message);
+        }
+    }
+
+    fn check_statements(&mut self, statements: &Statements) {
+        for statement in statements.statements.items.iter() {
+            self.check_statement(statement);
+        }
+    }
+
+    fn check_statement(&mut self, statement: &Statement) {
+        match statement {
+            Statement::Let(let_) => {
+                let anchor = self.anchor();
+                let name = &let_.var_name.0;
+                let mut target = self.variable_type(anchor, name);
+                if let Some(index) = &*let_.array_size {
+                    target = self.check_index(anchor, name, &target, index);
+                }
+                let eq_anchor = self.anchor();
+                let value = self.check_expression(&let_.expression);
+                if !value.fits(&target) {
+                    let message =
+                        format!("cannot assign {} to `{}` of type {}", value, name, target);
+                    self.mismatch(eq_anchor, message);
+                }
+            }
+            Statement::If(if_) => {
+                self.check_condition("if", &if_.condition);
+                self.check_statements(&if_.then_body);
+                if let Some(else_body) = &*if_.else_body {
+                    self.check_statements(else_body);
+                }
+            }
+            Statement::While(while_) => {
+                self.check_condition("while", &while_.condition);
+                self.check_statements(&while_.body);
+            }
+            Statement::Do(do_) => {
+                self.check_call(&do_.0, false);
+            }
+            Statement::Return(return_) => {
+                let anchor = self.anchor();
+                let subroutine = self.subroutine();
+                let kind = subroutine.kind;
+                let return_type = subroutine.return_type.clone();
+                let value = return_.0.item.as_deref();
+                let message = match (kind, &return_type, value) {
+                    (SubroutineKind::Constructor, _, Some(value)) if returns_this(value) => None,
+                    (SubroutineKind::Constructor, _, _) => Some("must return `this`"),
+                    (_, SubroutineType::Void, Some(_)) => Some("is void and cannot return a value"),
+                    (_, SubroutineType::Void, None) => None,
+                    (_, _, None) => Some("must return a value"),
+                    (_, _, Some(_)) => None,
+                };
+                if let Some(message) = message {
+                    let message = format!("{} {}", self.full_name(), message);
+                    self.error(anchor, message);
+                }
+                if let Some(value) = value {
+                    let found = self.check_expression(value);
+                    let expected = ValueType::from(&return_type);
+                    if kind != SubroutineKind::Constructor && !found.fits(&expected) {
+                        let message =
+                            format!("{} returns {}, found {}", self.full_name(), expected, found);
+                        self.mismatch(anchor, message);
+                    }
+                }
+            }
+        }
+    }
+
+    fn check_condition(&mut self, keyword: &str, condition: &Expression) {
+        let anchor = self.anchor();
+        let found = self.check_expression(condition);
+        if !found.fits(&ValueType::Boolean) {
+            let message = format!("`{}` condition should be boolean, found {}", keyword, found);
+            self.mismatch(anchor, message);
+        }
+    }
+
+    /// Looks up a variable as `variable` does, giving its type.
+    fn variable_type(&mut self, anchor: usize, name: &str) -> ValueType {
+        self.variable(anchor, name)
+            .map_or(ValueType::Unknown, |variable| (&variable.type_).into())
+    }
+
+    /// Checks `name[index]`, whose elements can hold anything.
+    fn check_index(
+        &mut self,
+        anchor: usize,
+        name: &str,
+        array: &ValueType,
+        index: &Expression,
+    ) -> ValueType {
+        if matches!(array, ValueType::Int | ValueType::Char | ValueType::Boolean) {
+            let message = format!("`{}` is {} and cannot be indexed", name, array);
+            self.mismatch(anchor, message);
+        }
+        let found = self.check_expression(index);
+        if !found.is_numeric() {
+            let message = format!("index into `{}` should be int, found {}", name, found);
+            self.mismatch(anchor, message);
+        }
+        ValueType::Unknown
+    }
+
+    fn check_expression(&mut self, expression: &Expression) -> ValueType {
+        let mut left = self.check_term(&expression.term);
+        for extra in expression.extras.items.iter() {
+            let anchor = self.anchor();
+            let right = self.check_term(&extra.1);
+            left = self.check_op(anchor, &extra.0, left, right);
+        }
+        left
+    }
+
+    /// The type of `left op right`. `&` and `|` are logical on booleans and
+    /// bitwise on numbers.
+    fn check_op(&mut self, anchor: usize, op: &Op, left: ValueType, right: ValueType) -> ValueType {
+        let boolean = ValueType::Boolean;
+        let numeric = left.is_numeric() && right.is_numeric();
+        let (fits, result) = match op {
+            Op::Plus | Op::Minus | Op::Asterisk | Op::Slash => (numeric, ValueType::Int),
+            Op::Lt | Op::Gt => (numeric, ValueType::Boolean),
+            Op::Eq => (left.fits(&right) || right.fits(&left), ValueType::Boolean),
+            Op::And | Op::Or => match (&left, &right) {
+                (ValueType::Unknown, ValueType::Unknown) => (true, ValueType::Unknown),
+                (ValueType::Boolean, _) | (_, ValueType::Boolean) => (
+                    left.fits(&boolean) && right.fits(&boolean),
+                    ValueType::Boolean,
+                ),
+                _ => (numeric, ValueType::Int),
+            },
+        };
+        if !fits {
+            let token: Token = op.clone().into();
+            let message = format!("`{}` cannot combine {} and {}", token, left, right);
+            self.mismatch(anchor, message);
+        }
+        result
+    }
+
+    fn check_term(&mut self, term: &Term) -> ValueType {
+        match term {
+            Term::Constant(constant) => match &**constant {
+                Constant::This => {
+                    let anchor = self.anchor();
+                    if self.is_function() {
+                        let message =
+                            format!("`this` cannot be used in function {}", self.full_name());
+                        self.error(anchor, message);
+                    }
+                    ValueType::Class(self.class_name.clone())
+                }
+                Constant::True | Constant::False => ValueType::Boolean,
+                Constant::Null => ValueType::Null,
+                Constant::Integer(_) => ValueType::Int,
+                Constant::String(_) => ValueType::Class("String".to_string()),
+            },
+            Term::Variable(name) => {
+                let anchor = self.anchor();
+                self.variable_type(anchor, &name.0)
+            }
+            Term::ArrayAccess(name, index) => {
+                let anchor = self.anchor();
+                let array = self.variable_type(anchor, &name.0);
+                self.check_index(anchor, &name.0, &array, index)
+            }
+            Term::SubroutineCall(call) => self.check_call(call, true),
+            Term::Expression(expression) => self.check_expression(expression),
+            Term::UnaryOp(op, term) => {
+                let anchor = self.anchor();
+                let found = self.check_term(term);
+                match (&**op, &found) {
+                    (UnaryOp::Tilde, ValueType::Boolean | ValueType::Unknown) => found,
+                    (_, found) if found.is_numeric() => ValueType::Int,
+                    (op, found) => {
+                        let token: Token = op.clone().into();
+                        let message = format!("`{}` cannot be applied to {}", token, found);
+                        self.mismatch(anchor, message);
+                        ValueType::Unknown
+                    }
+                }
+            }
+        }
+    }
+
+    /// Checks a call against the class table, giving the type of its result.
+    /// `as_value` is set when the result is used, which a void subroutine
+    /// does not have.
+    fn check_call(&mut self, call: &SubroutineCall, as_value: bool) -> ValueType {
+        let (class, anchor, name, args, method) = match call {
+            SubroutineCall::ExternalCall(target, name, args) => {
+                let target_anchor = self.anchor();
+                let anchor = self.anchor();
+                let is_variable = self.subroutine().locals.contains_key(&target.0)
+                    || self.fields.contains_key(&target.0);
+                let (class, method) = if is_variable {
+                    match self.variable(target_anchor, &target.0).map(|v| v.type_) {
+                        Some(Type::Class(class)) => (Some(class), true),
+                        _ => {
+                            let message = format!("`{}` is not an object", target.0);
+                            self.error(target_anchor, message);
+                            (None, true)
+                        }
+                    }
+                } else if self.classes.contains(&target.0) {
+                    (Some(target.0.clone()), false)
+                } else {
+                    if self.classes.complete {
+                        let message = format!("unknown variable or class `{}`", target.0);
+                        self.error(target_anchor, message);
+                    }
+                    (None, false)
+                };
+                (class, anchor, &name.0, args, method)
+            }
+            SubroutineCall::InternalCall(name, args) => {
+                let anchor = self.anchor();
+                (Some(self.class_name.clone()), anchor, &name.0, args, true)
+            }
+        };
+        let found = args
+            .expressions
+            .items
+            .iter()
+            .map(|arg| self.check_expression(arg))
+            .collect::<Vec<_>>();
+
+        let class = match class {
+            Some(class) if self.classes.contains(&class) => class,
+            _ => return ValueType::Unknown,
+        };
+        let full_name = format!("{}.{}", class, name);
+        let signature = match self.classes.get(&class, name) {
+            Some(signature) => signature.clone(),
+            None => {
+                self.error(anchor, format!("unknown subroutine `{}`", full_name));
+                return ValueType::Unknown;
+            }
+        };
+
+        let internal = matches!(call, SubroutineCall::InternalCall(..));
+        let message = match (signature.kind, method) {
+            (SubroutineKind::Method, false) => Some(format!(
+                "method {} needs an object to be called on",
+                full_name
+            )),
+            (SubroutineKind::Method, true) if internal && self.is_function() => Some(format!(
+                "method {} cannot be called from function {}",
+                full_name,
+                self.full_name()
+            )),
+            (_, true) if signature.kind != SubroutineKind::Method => Some(format!(
+                "{} is not a method and must be called as `{}`",
+                full_name, full_name
+            )),
+            _ => None,
+        };
+        if let Some(message) = message {
+            self.error(anchor, message);
+        }
+        if signature.params.len() != args.len() {
+            let message = format!(
+                "{} takes {} argument(s) but {} were given",
+                full_name,
+                signature.params.len(),
+                args.len()
+            );
+            self.error(anchor, message);
+        } else {
+            for (i, (found, param)) in found.iter().zip(signature.params.iter()).enumerate() {
+                let expected = ValueType::from(param);
+                if !found.fits(&expected) {
+                    let message = format!(
+                        "argument {} of {} should be {}, found {}",
+                        i + 1,
+                        full_name,
+                        expected,
+                        found
+                    );
+                    self.mismatch(anchor, message);
+                }
+            }
+        }
+        if as_value && signature.return_type == SubroutineType::Void {
+            let message = format!("{} is void and has no value to use", full_name);
+            self.error(anchor, message);
+        }
+        (&signature.return_type).into()
+    }
+}
+
+fn returns_this(value: &Expression) -> bool {
+    value.extras.items.is_empty() && *value.term == Term::Constant(Box::new(Constant::This))
+}
+
+/// Whether every path through `statements` ends in a `return`.
+fn always_returns(statements: &Statements) -> bool {
+    match statements.statements.items.last().map(|s| &**s) {
+        Some(Statement::Return(_)) => true,
+        Some(Statement::If(if_)) => {
+            always_returns(&if_.then_body)
+                && (*if_.else_body)
+                    .as_ref()
+                    .is_some_and(|body| always_returns(body))
+        }
+        _ => false,
+    }
+}
+
+/// Checks one class of a program against `classes`, which should include it,
+/// checking the types of values as well unless `type_check` is `Off`.
+pub fn check(class: &Class, classes: &ClassTable, type_check: TypeCheck) -> Vec<SemanticError> {
+    let mut checker = Checker {
+        classes,
+        class_name: class.class_name.0.clone(),
+        fields: HashMap::new(),
+        subroutine: None,
+        anchors: 0,
+        type_check,
+        errors: Vec::new(),
+    };
+    checker.check_class(class);
+    let mut errors = checker.errors;
+    errors.sort_by_key(|error| error.anchor);
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::tokenize_located;
+
+    fn errors(sources: &[&str]) -> Vec<String> {
+        errors_with(sources, TypeCheck::Off)
+    }
+
+    fn errors_with(sources: &[&str], type_check: TypeCheck) -> Vec<String> {
+        let parsed = sources
+            .iter()
+            .map(|source| {
+                let tokens = tokenize_located(source).unwrap();
+                let items = tokens.iter().map(|t| t.item.clone()).collect::<Vec<_>>();
+                (tokens, parse_class(&items).unwrap())
+            })
+            .collect::<Vec<_>>();
+        let classes = parsed.iter().map(|(_, class)| &**class).collect::<Vec<_>>();
+        let table = ClassTable::new(&classes, true);
+        check(classes[0], &table, type_check)
+            .iter()
+            .map(|error| {
+                let diagnostic = error.diagnostic(&parsed[0].0, sources[0]);
+                format!(
+                    "{}:{}: {}",
+                    diagnostic.span.line, diagnostic.span.col, diagnostic.message
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_accepts_valid_program() {
+        let main = "class Main {
+    function void main() {
+        var Point p;
+        var Array a;
+        let p = Point.new(1, 2);
+        let a = Array.new(p.x());
+        let a[0] = Math.max(a[0], 3);
+        do p.move(a[0]);
+        do Output.printString(\"done\");
+        return;
+    }
+}";
+        let point = "class Point {
+    field int x, y;
+    static int count;
+    constructor Point new(int ax, int ay) {
+        let x = ax;
+        let y = ay;
+        let count = count + 1;
+        return this;
+    }
+    method int x() { return x; }
+    method void move(int dx) {
+        let x = x + dx;
+        do draw();
+        return;
+    }
+    method void draw() {
+        if (x < 0) { return; } else { do Screen.drawPixel(x, y); }
+        return;
+    }
+    function int sign(int v) {
+        if (v < 0) { return -1; } else { return 1; }
+    }
+}";
+        assert_eq!(errors(&[main, point]), Vec::<String>::new());
+        assert_eq!(errors(&[point, main]), Vec::<String>::new());
+        assert_eq!(
+            errors_with(&[main, point], TypeCheck::Error),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            errors_with(&[point, main], TypeCheck::Error),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn test_reports_errors() {
+        let main = "class Main {
+    field int size;
+    function void main() {
+        var int n, n;
+        let total = 1;
+        let n = size;
+        do draw();
+        let n = Main.draw() + Output.printInt(n);
+        do Point.new();
+        return this;
+    }
+    method void draw() {
+        if (true) { return; }
+    }
+}";
+        let point = "class Point {
+    constructor Point new(int x) { return 0; }
+}";
+        assert_eq!(
+            errors(&[main, point]),
+            vec![
+                "4:20: `n` is declared twice in Main.main",
+                "5:13: undeclared variable `total` in Main.main",
+                "6:17: field `size` cannot be used in function Main.main",
+                "7:12: method Main.draw cannot be called from function Main.main",
+                "8:22: method Main.draw needs an object to be called on",
+                "8:22: Main.draw is void and has no value to use",
+                "8:38: Output.printInt is void and has no value to use",
+                "9:18: Point.new takes 1 argument(s) but 0 were given",
+                "10:9: Main.main is void and cannot return a value",
+                "10:16: `this` cannot be used in function Main.main",
+                "12:17: Main.draw can end without `return`",
+            ]
+        );
+        assert_eq!(errors(&[point]), vec!["2:36: Point.new must return `this`"]);
+    }
+
+    #[test]
+    fn test_reports_type_mismatches() {
+        let main = "class Main {
+    function int main(boolean done) {
+        var int n;
+        var char c;
+        var Array a;
+        var String s;
+        let n = done;
+        let c = 65 + n;
+        let a = Memory.alloc(3);
+        let a[done] = s;
+        let s = null;
+        do Screen.drawPixel(done, n);
+        do Output.printString(n);
+        if (n) { let done = ~n; }
+        while (done & (n < 0)) { let n = -done; }
+        let done = (n = s) | n;
+        let s = n[0];
+        return s.charAt(0) = c;
+    }
+}";
+        assert_eq!(
+            errors_with(&[main], TypeCheck::Error),
+            vec![
+                "7:15: cannot assign boolean to `n` of type int",
+                "10:13: index into `a` should be int, found boolean",
+                "12:19: argument 1 of Screen.drawPixel should be int, found boolean",
+                "13:19: argument 1 of Output.printString should be String, found int",
+                "14:9: `if` condition should be boolean, found int",
+                "14:27: cannot assign int to `done` of type boolean",
+                "15:42: `-` cannot be applied to boolean",
+                "16:23: `=` cannot combine int and String",
+                "16:28: `|` cannot combine boolean and int",
+                "17:17: `n` is int and cannot be indexed",
+                "18:9: Main.main returns int, found boolean",
+            ]
+        );
+
+        let warnings = "class Main {
+    function void main() {
+        do Output.printInt(true);
+        return;
+    }
+}";
+        let table = ClassTable::new(&[], true);
+        let tokens = tokenize(warnings).unwrap();
+        let class = parse_class(&tokens).unwrap();
+        assert_eq!(check(&class, &table, TypeCheck::Off), vec![]);
+        assert_eq!(
+            check(&class, &table, TypeCheck::Warn),
+            vec![SemanticError {
+                anchor: 3,
+                message: "argument 1 of Output.printInt should be int, found boolean".to_string(),
+                warning: true,
+            }]
+        );
+    }
+}
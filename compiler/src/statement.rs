@@ -1,5 +1,3 @@
-use anyhow::*;
-
 use super::expression::*;
 use super::foundation::*;
 use super::token::Token;
@@ -8,11 +6,12 @@ use super::token::Token;
 pub struct ReturnStatement(pub Box<Optional<Expression>>);
 
 impl Parsable for ReturnStatement {
-    fn parse(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
-        let (_, rem) = token_parser(Token::Return)(tokens)?;
-        let (e, rem) = Optional::<Expression>::parse(rem)?;
-        let (_, rem) = token_parser(Token::Semicolon)(rem)?;
-        Ok((Box::new(Self(e)), rem))
+    fn parse_tracked(tokens: &[Token]) -> Parsed<'_, Self> {
+        let mut failures = Failures::default();
+        let (_, rem) = token_parser(Token::Return)(tokens).track(&mut failures)?;
+        let (e, rem) = Optional::<Expression>::parse_tracked(rem).track(&mut failures)?;
+        let (_, rem) = token_parser(Token::Semicolon)(rem).track(&mut failures)?;
+        Ok((Box::new(Self(e)), rem, failures))
     }
 }
 
@@ -20,11 +19,12 @@ impl Parsable for ReturnStatement {
 pub struct DoStatement(pub Box<SubroutineCall>);
 
 impl Parsable for DoStatement {
-    fn parse(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
-        let (_, rem) = token_parser(Token::Do)(tokens)?;
-        let (s, rem) = SubroutineCall::parse(rem)?;
-        let (_, rem) = token_parser(Token::Semicolon)(rem)?;
-        Ok((Box::new(Self(s)), rem))
+    fn parse_tracked(tokens: &[Token]) -> Parsed<'_, Self> {
+        let mut failures = Failures::default();
+        let (_, rem) = token_parser(Token::Do)(tokens).track(&mut failures)?;
+        let (s, rem) = SubroutineCall::parse_tracked(rem).track(&mut failures)?;
+        let (_, rem) = token_parser(Token::Semicolon)(rem).track(&mut failures)?;
+        Ok((Box::new(Self(s)), rem, failures))
     }
 }
 
@@ -35,14 +35,18 @@ pub struct WhileStatement {
 }
 
 impl Parsable for WhileStatement {
-    fn parse(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
-        let (_, rem) = token_parser(Token::While)(tokens)?;
+    fn parse_tracked(tokens: &[Token]) -> Parsed<'_, Self> {
+        let mut failures = Failures::default();
+        let (_, rem) = token_parser(Token::While)(tokens).track(&mut failures)?;
 
         let (condition, rem) =
-            surround_parser(Expression::parse, Token::LParen, Token::RParen)(rem)?;
-        let (body, rem) = surround_parser(Statements::parse, Token::LBrace, Token::RBrace)(rem)?;
+            surround_parser(Expression::parse_tracked, Token::LParen, Token::RParen)(rem)
+                .track(&mut failures)?;
+        let (body, rem) =
+            surround_parser(Statements::parse_tracked, Token::LBrace, Token::RBrace)(rem)
+                .track(&mut failures)?;
 
-        Ok((Box::new(Self { condition, body }), rem))
+        Ok((Box::new(Self { condition, body }), rem, failures))
     }
 }
 
@@ -54,17 +58,21 @@ pub struct IfStatement {
 }
 
 impl Parsable for IfStatement {
-    fn parse(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
-        let (_, rem) = token_parser(Token::If)(tokens)?;
+    fn parse_tracked(tokens: &[Token]) -> Parsed<'_, Self> {
+        let mut failures = Failures::default();
+        let (_, rem) = token_parser(Token::If)(tokens).track(&mut failures)?;
 
         let (condition, rem) =
-            surround_parser(Expression::parse, Token::LParen, Token::RParen)(rem)?;
+            surround_parser(Expression::parse_tracked, Token::LParen, Token::RParen)(rem)
+                .track(&mut failures)?;
         let (then_body, rem) =
-            surround_parser(Statements::parse, Token::LBrace, Token::RBrace)(rem)?;
+            surround_parser(Statements::parse_tracked, Token::LBrace, Token::RBrace)(rem)
+                .track(&mut failures)?;
         let (else_body, rem) = option_parser(drop1_parser(
             token_parser(Token::Else),
-            surround_parser(Statements::parse, Token::LBrace, Token::RBrace),
-        ))(rem)?;
+            surround_parser(Statements::parse_tracked, Token::LBrace, Token::RBrace),
+        ))(rem)
+        .track(&mut failures)?;
         Ok((
             Box::new(Self {
                 condition,
@@ -72,6 +80,7 @@ impl Parsable for IfStatement {
                 else_body,
             }),
             rem,
+            failures,
         ))
     }
 }
@@ -84,17 +93,19 @@ pub struct LetStatement {
 }
 
 impl Parsable for LetStatement {
-    fn parse(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
-        let (_, rem) = token_parser(Token::Let)(tokens)?;
-        let (var_name, rem) = Identifier::parse(rem)?;
+    fn parse_tracked(tokens: &[Token]) -> Parsed<'_, Self> {
+        let mut failures = Failures::default();
+        let (_, rem) = token_parser(Token::Let)(tokens).track(&mut failures)?;
+        let (var_name, rem) = Identifier::parse_tracked(rem).track(&mut failures)?;
         let (array_size, rem) = option_parser(surround_parser(
-            Expression::parse,
+            Expression::parse_tracked,
             Token::LBracket,
             Token::RBracket,
-        ))(rem)?;
-        let (_, rem) = token_parser(Token::Eq)(rem)?;
-        let (expression, rem) = Expression::parse(rem)?;
-        let (_, rem) = token_parser(Token::Semicolon)(rem)?;
+        ))(rem)
+        .track(&mut failures)?;
+        let (_, rem) = token_parser(Token::Eq)(rem).track(&mut failures)?;
+        let (expression, rem) = Expression::parse_tracked(rem).track(&mut failures)?;
+        let (_, rem) = token_parser(Token::Semicolon)(rem).track(&mut failures)?;
 
         Ok((
             Box::new(Self {
@@ -103,6 +114,7 @@ impl Parsable for LetStatement {
                 expression,
             }),
             rem,
+            failures,
         ))
     }
 }
@@ -116,35 +128,42 @@ pub enum Statement {
     Return(Box<ReturnStatement>),
 }
 impl Statement {
-    pub fn parse_let(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
-        let (s, rem) = LetStatement::parse(tokens)?;
-        Ok((Box::new(Self::Let(s)), rem))
+    pub fn parse_let(tokens: &[Token]) -> Parsed<'_, Self> {
+        let mut failures = Failures::default();
+        let (s, rem) = LetStatement::parse_tracked(tokens).track(&mut failures)?;
+        Ok((Box::new(Self::Let(s)), rem, failures))
     }
-    pub fn parse_if(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
-        let (s, rem) = IfStatement::parse(tokens)?;
-        Ok((Box::new(Self::If(s)), rem))
+    pub fn parse_if(tokens: &[Token]) -> Parsed<'_, Self> {
+        let mut failures = Failures::default();
+        let (s, rem) = IfStatement::parse_tracked(tokens).track(&mut failures)?;
+        Ok((Box::new(Self::If(s)), rem, failures))
     }
-    pub fn parse_while(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
-        let (s, rem) = WhileStatement::parse(tokens)?;
-        Ok((Box::new(Self::While(s)), rem))
+    pub fn parse_while(tokens: &[Token]) -> Parsed<'_, Self> {
+        let mut failures = Failures::default();
+        let (s, rem) = WhileStatement::parse_tracked(tokens).track(&mut failures)?;
+        Ok((Box::new(Self::While(s)), rem, failures))
     }
-    pub fn parse_do(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
-        let (s, rem) = DoStatement::parse(tokens)?;
-        Ok((Box::new(Self::Do(s)), rem))
+    pub fn parse_do(tokens: &[Token]) -> Parsed<'_, Self> {
+        let mut failures = Failures::default();
+        let (s, rem) = DoStatement::parse_tracked(tokens).track(&mut failures)?;
+        Ok((Box::new(Self::Do(s)), rem, failures))
     }
-    pub fn parse_return(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
-        let (s, rem) = ReturnStatement::parse(tokens)?;
-        Ok((Box::new(Self::Return(s)), rem))
+    pub fn parse_return(tokens: &[Token]) -> Parsed<'_, Self> {
+        let mut failures = Failures::default();
+        let (s, rem) = ReturnStatement::parse_tracked(tokens).track(&mut failures)?;
+        Ok((Box::new(Self::Return(s)), rem, failures))
     }
 }
 
 impl Parsable for Statement {
-    fn parse(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
-        Self::parse_let(tokens)
-            .or_else(|_| Self::parse_if(tokens))
-            .or_else(|_| Self::parse_while(tokens))
-            .or_else(|_| Self::parse_do(tokens))
-            .or_else(|_| Self::parse_return(tokens))
+    fn parse_tracked(tokens: &[Token]) -> Parsed<'_, Self> {
+        labelled("a statement", tokens, |tokens| {
+            Self::parse_let(tokens)
+                .otherwise(|| Self::parse_if(tokens))
+                .otherwise(|| Self::parse_while(tokens))
+                .otherwise(|| Self::parse_do(tokens))
+                .otherwise(|| Self::parse_return(tokens))
+        })
     }
 }
 
@@ -154,9 +173,13 @@ pub struct Statements {
 }
 
 impl Parsable for Statements {
-    fn parse(tokens: &[Token]) -> Result<(Box<Self>, &[Token])> {
-        let (statements, rem) = Collection::<Statement>::parse(tokens)?;
-        Ok((Box::new(Self { statements }), rem))
+    fn parse_tracked(tokens: &[Token]) -> Parsed<'_, Self> {
+        let (statements, rem, failures) = recovering_parser(
+            Statement::parse_tracked,
+            &[Token::Let, Token::If, Token::While, Token::Do, Token::Return],
+        )(tokens)?;
+        let statements = Box::new(Collection::new(*statements));
+        Ok((Box::new(Self { statements }), rem, failures))
     }
 }
 
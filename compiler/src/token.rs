@@ -1,3 +1,5 @@
+use std::fmt;
+
 use anyhow::*;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -155,6 +157,59 @@ impl Token {
     }
 }
 
+impl fmt::Display for Token {
+    /// Writes the token as it appears in Jack source.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Self::LParen => "(",
+            Self::RParen => ")",
+            Self::LBrace => "{",
+            Self::RBrace => "}",
+            Self::LBracket => "[",
+            Self::RBracket => "]",
+            Self::Period => ".",
+            Self::Comma => ",",
+            Self::Semicolon => ";",
+            Self::Plus => "+",
+            Self::Minus => "-",
+            Self::Asterisk => "*",
+            Self::Slash => "/",
+            Self::And => "&",
+            Self::Or => "|",
+            Self::Lt => "<",
+            Self::Gt => ">",
+            Self::Eq => "=",
+            Self::Tilde => "~",
+            Self::Class => "class",
+            Self::Constructor => "constructor",
+            Self::Function => "function",
+            Self::Method => "method",
+            Self::Field => "field",
+            Self::Static => "static",
+            Self::Var => "var",
+            Self::Int => "int",
+            Self::Char => "char",
+            Self::Boolean => "boolean",
+            Self::Void => "void",
+            Self::True => "true",
+            Self::False => "false",
+            Self::Null => "null",
+            Self::This => "this",
+            Self::Let => "let",
+            Self::Do => "do",
+            Self::If => "if",
+            Self::Else => "else",
+            Self::While => "while",
+            Self::Return => "return",
+            Self::IntegerConstant(n) => return write!(f, "{}", n),
+            Self::StringConstant(s) => return write!(f, "\"{}\"", s),
+            Self::Identifier(s) => s,
+            Self::EOF => "",
+        };
+        write!(f, "{}", s)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
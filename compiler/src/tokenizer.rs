@@ -3,42 +3,102 @@ use core::result::Result::Ok;
 
 use super::token::Token;
 use super::dump_xml::*;
+use assembler::diagnostic::{Diagnostic, Located, Span};
 
-fn skip_comments(s: &str) -> Result<&str> {
+/// Skips whitespace and comments, or fails with the offset of a `/*` that is
+/// never closed.
+fn skip_comments(s: &str) -> core::result::Result<&str, &str> {
     let s = s.trim_start();
-    let beg = if s.starts_with("//") {
-        s.find('\n').unwrap_or(s.len())
-    } else if s.starts_with("/*") {
-        s.find("*/").with_context(|| "aaaf")? + 2
-    } else {
-        0
-    };
-
-    if 0 < beg {
-        skip_comments(&s[beg..])
+    if let Some(rem) = s.strip_prefix("//") {
+        skip_comments(&rem[rem.find('\n').unwrap_or(rem.len())..])
+    } else if let Some(rem) = s.strip_prefix("/*") {
+        match rem.find("*/") {
+            Some(end) => skip_comments(&rem[end + 2..]),
+            None => Err(s),
+        }
     } else {
         Ok(s)
     }
 }
 
-pub fn tokenize(s: &str) -> Result<Vec<Token>> {
+/// Finds the 1-based line and column of byte `offset` from the offsets where
+/// lines start.
+fn position(line_starts: &[usize], offset: usize) -> (usize, usize) {
+    let line = line_starts.partition_point(|&start| start <= offset);
+    (line, offset - line_starts[line - 1] + 1)
+}
+
+/// Like `tokenize`, but keeps where each token is and reports every
+/// character that does not start a token instead of stopping at the first.
+pub fn tokenize_located(s: &str) -> core::result::Result<Vec<Located<Token>>, Vec<Diagnostic>> {
+    let line_starts = std::iter::once(0)
+        .chain(s.match_indices('\n').map(|(i, _)| i + 1))
+        .collect::<Vec<_>>();
+    let lines = s.lines().collect::<Vec<_>>();
+    let span = |rem: &str, len: usize| {
+        let (line, col) = position(&line_starts, s.len() - rem.len());
+        Span::new(line, col, len)
+    };
+    let source = |span: Span| lines.get(span.line - 1).copied().unwrap_or("");
+
     let mut tokens = vec![];
-    let mut s = skip_comments(s)?;
+    let mut diagnostics = vec![];
+    let mut rem = s;
     loop {
-        let (token, rem) = Token::parse(s)?;
-        if token == Token::EOF {
-            break;
-        }
+        rem = match skip_comments(rem) {
+            Ok(rem) => rem,
+            Err(comment) => {
+                let span = span(comment, 2);
+                diagnostics.push(Diagnostic::new("unterminated comment", span, source(span)));
+                break;
+            }
+        };
+        let c = match rem.chars().next() {
+            Some(c) => c,
+            None => break,
+        };
 
-        tokens.push(token);
-        s = skip_comments(rem)?;
+        // `Token::parse` splits off symbols by byte, so only ASCII is safe.
+        let parsed = if c.is_ascii() {
+            Token::parse(rem)
+        } else {
+            Err(anyhow!("invalid character: {}", c))
+        };
+        let (message, len) = match parsed {
+            Ok((Token::Identifier(name), _)) if name.is_empty() && c == '"' => {
+                let len = rem.find('\n').unwrap_or(rem.len());
+                ("unterminated string constant".to_string(), len)
+            }
+            Ok((Token::Identifier(name), _)) if name.is_empty() => {
+                (format!("invalid character: {}", c), c.len_utf8())
+            }
+            Ok((token, next)) => {
+                tokens.push(Located::new(token, span(rem, rem.len() - next.len())));
+                rem = next;
+                continue;
+            }
+            Err(_) if c.is_ascii_digit() => {
+                let len = rem.find(|c: char| !c.is_ascii_digit()).unwrap_or(rem.len());
+                (format!("integer constant too large: {}", &rem[..len]), len)
+            }
+            Err(e) => (e.to_string(), c.len_utf8()),
+        };
+        let span = span(rem, len);
+        diagnostics.push(Diagnostic::new(message, span, source(span)));
+        rem = &rem[len..];
     }
-    s = skip_comments(s)?;
 
-    if s.is_empty() {
-      Ok(tokens)
+    if diagnostics.is_empty() {
+        Ok(tokens)
     } else {
-        Err(anyhow::anyhow!("invalid token: {}", s))
+        Err(diagnostics)
+    }
+}
+
+pub fn tokenize(s: &str) -> Result<Vec<Token>> {
+    match tokenize_located(s) {
+        Ok(tokens) => Ok(tokens.into_iter().map(|token| token.item).collect()),
+        Err(diagnostics) => Err(anyhow!("{}", diagnostics[0].message)),
     }
 }
 
@@ -100,4 +160,34 @@ foo
             ]
         );
     }
+
+    #[test]
+    fn test_tokenize_located() {
+        let tokens = tokenize_located("class Main {\n  /* x */ let s = \"ab\";\n}").unwrap();
+        let spans = tokens.iter().map(|t| t.span).collect::<Vec<_>>();
+        assert_eq!(tokens[4].item, Token::Identifier("s".to_string()));
+        assert_eq!(
+            spans[3..7],
+            [
+                Span::new(2, 11, 3),
+                Span::new(2, 15, 1),
+                Span::new(2, 17, 1),
+                Span::new(2, 19, 4),
+            ]
+        );
+        assert_eq!(spans[8], Span::new(3, 1, 1));
+
+        let errors = tokenize_located("let x = $ 1;\nlet y = 99999 é \"ab\n/* no end").unwrap_err();
+        let messages = errors.iter().map(|e| (e.span, e.message.as_str())).collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            vec![
+                (Span::new(1, 9, 1), "invalid character: $"),
+                (Span::new(2, 9, 5), "integer constant too large: 99999"),
+                (Span::new(2, 15, 2), "invalid character: é"),
+                (Span::new(2, 18, 3), "unterminated string constant"),
+                (Span::new(3, 1, 2), "unterminated comment"),
+            ]
+        );
+    }
 }
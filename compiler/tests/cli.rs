@@ -0,0 +1,76 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use vm::source_map::SourceMap;
+
+const MAIN: &str = "class Main {
+    function void main() {
+        do Output.printInt(1 + 2);
+        return;
+    }
+}
+";
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("compiler-cli-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("Main.jack"), MAIN).unwrap();
+    dir
+}
+
+fn run(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_compiler"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn arg(path: &Path) -> &str {
+    path.to_str().unwrap()
+}
+
+#[test]
+fn test_image_extension() {
+    let dir = scratch_dir("image");
+    let formats = [("hack", "hack"), ("ihex", "hex"), ("memh", "memh"), ("rust", "rs")];
+    for (format, extension) in formats {
+        let output = run(&["-i", arg(&dir), "-o", "bin", "--image-format", format]);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert!(dir.join("Main").with_extension(extension).is_file(), "{}", format);
+    }
+    assert!(!dir.join("Main.bin").exists());
+}
+
+#[test]
+fn test_asm_vm_mode() {
+    let dir = scratch_dir("asm");
+    for (mode, shared) in [("inline", false), ("shared", true)] {
+        let output = run(&["-i", arg(&dir), "-o", "asm", "--vm-mode", mode]);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        let asm = fs::read_to_string(dir.join("Main.asm")).unwrap();
+        assert_eq!(asm.contains("(VM$CALL)"), shared, "{}", mode);
+        assert_eq!(asm.contains("@VM$CALL"), shared, "{}", mode);
+    }
+}
+
+#[test]
+fn test_source_map_files() {
+    let dir = scratch_dir("map");
+    for optimize in [false, true] {
+        let mut args = vec!["-i", arg(&dir), "-o", "bin"];
+        if optimize {
+            args.push("--optimize");
+        }
+        let output = run(&args);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        let map = SourceMap::parse(&fs::read_to_string(dir.join("Main.map")).unwrap()).unwrap();
+        assert!(!map.entries().is_empty());
+        for entry in map.entries() {
+            let vm = fs::read_to_string(dir.join(&entry.file)).unwrap();
+            let line = vm.lines().nth(entry.line.unwrap() - 1).unwrap();
+            assert_eq!(line, entry.command, "{}", entry);
+        }
+    }
+}
@@ -1,3 +1,5 @@
+mod cli;
+
 mod chapter10 {
     mod ArrayTest_Main;
     mod ArrayTest_MainT;
@@ -1,9 +1,11 @@
+use std::collections::HashSet;
 use std::io::BufRead;
-
-use rand::{distributions::Alphanumeric, Rng};
+use std::str::FromStr;
 
 use super::command::{Command as VmCommand, Segment};
+use super::parser;
 use assembler::command::{Command, Comp, Dest, Jump};
+use assembler::diagnostic::{Diagnostic, Diagnostics, Located, Span};
 
 pub fn parse(lines: &[&str]) -> anyhow::Result<Vec<VmCommand>> {
     lines
@@ -16,34 +18,596 @@ pub fn parse(lines: &[&str]) -> anyhow::Result<Vec<VmCommand>> {
         .collect::<anyhow::Result<Vec<_>>>()
 }
 
-fn random_string(len: usize) -> String {
-    rand::thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(len)
-        .map(char::from)
-        .collect()
+/// Like `parse`, but keeps where each command came from and reports every
+/// bad line instead of stopping at the first.
+pub fn parse_located(lines: &[&str]) -> Result<Vec<Located<VmCommand>>, Vec<Diagnostic>> {
+    parser::parse(&lines.join("\n")).map(parser::commands)
+}
+
+/// Reports jumps to labels that the function they are in does not define,
+/// and starts over for the next function.
+fn check_jumps<'a>(
+    labels: &mut HashSet<&'a str>,
+    jumps: &mut Vec<(usize, &'a str)>,
+    errors: &mut Vec<(usize, String)>,
+) {
+    for (i, label) in jumps.drain(..) {
+        if !labels.contains(label) {
+            errors.push((i, format!("Undefined label: {}", label)));
+        }
+    }
+    labels.clear();
+}
+
+/// Finds what translation cannot express: segment indexes, local counts and
+/// argument counts that do not fit an `@` instruction, `pop constant`,
+/// duplicate functions, and labels that are duplicated,
+/// never defined, or placed before the first `function` of a file that has
+/// functions. Labels are scoped to their function, like `goto` targets.
+/// Returns the index of each offending command with a message.
+pub fn validate(vm_commands: &[VmCommand]) -> Vec<(usize, String)> {
+    let mut errors = Vec::new();
+    let has_functions = vm_commands
+        .iter()
+        .any(|command| matches!(command, VmCommand::Function(..)));
+    let mut functions = HashSet::new();
+    let mut in_function = false;
+    let mut labels = HashSet::new();
+    let mut jumps = Vec::new();
+    for (i, command) in vm_commands.iter().enumerate() {
+        match command {
+            VmCommand::Pop(Segment::Constant, _) => {
+                errors.push((i, "Cannot pop to constant".to_string()));
+            }
+            VmCommand::Push(segment, index) | VmCommand::Pop(segment, index) => {
+                let limit = match segment {
+                    Segment::Temp => 7,
+                    Segment::Pointer => 1,
+                    Segment::Static => continue,
+                    _ => 32767,
+                };
+                if *index > limit {
+                    errors.push((
+                        i,
+                        format!("{} index out of range 0-{}: {}", segment, limit, index),
+                    ));
+                }
+            }
+            VmCommand::Function(name, locals) => {
+                check_jumps(&mut labels, &mut jumps, &mut errors);
+                in_function = true;
+                if !functions.insert(name) {
+                    errors.push((i, format!("Duplicate function: {}", name)));
+                }
+                if *locals > 32767 {
+                    errors.push((i, format!("local count out of range 0-32767: {}", locals)));
+                }
+            }
+            // The inline call sequence loads the argument count plus the
+            // five saved frame words with one `@`.
+            VmCommand::Call(_, args) if *args > 32762 => {
+                errors.push((i, format!("argument count out of range 0-32762: {}", args)));
+            }
+            VmCommand::Label(label) | VmCommand::Goto(label) | VmCommand::IfGoto(label)
+                if has_functions && !in_function =>
+            {
+                errors.push((i, format!("Label outside of a function: {}", label)));
+            }
+            VmCommand::Label(label) => {
+                let defined = !labels.insert(label.as_str());
+                if defined {
+                    errors.push((i, format!("Duplicate label: {}", label)));
+                }
+            }
+            VmCommand::Goto(label) | VmCommand::IfGoto(label) => jumps.push((i, label.as_str())),
+            _ => {}
+        }
+    }
+    check_jumps(&mut labels, &mut jumps, &mut errors);
+
+    errors.sort_by_key(|(i, _)| *i);
+    errors
+}
+
+/// `validate` for located commands, as diagnostics against `lines`.
+pub fn check(program: &[Located<VmCommand>], lines: &[&str]) -> Result<(), Vec<Diagnostic>> {
+    let commands = program.iter().map(|c| c.item.clone()).collect::<Vec<_>>();
+    let diagnostics = validate(&commands)
+        .into_iter()
+        .map(|(i, message)| {
+            let span = program[i].span;
+            Diagnostic::new(message, span, lines[span.line - 1])
+        })
+        .collect::<Vec<_>>();
+    if diagnostics.is_empty() {
+        Ok(())
+    } else {
+        Err(diagnostics)
+    }
+}
+
+/// How call, return and comparisons are translated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mode {
+    /// Expand every command in place: the fastest and the largest code.
+    #[default]
+    Inline,
+    /// Jump into the routines from `runtime` instead, which makes each call
+    /// site a dozen instructions and each return two, at the cost of a few
+    /// extra instructions per call.
+    Shared,
+}
+
+impl FromStr for Mode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "inline" => Ok(Self::Inline),
+            "shared" => Ok(Self::Shared),
+            _ => Err(anyhow::anyhow!("Unknown translation mode: {}", s)),
+        }
+    }
+}
+
+/// Translation state carried from one command to the next.
+#[derive(Debug, Clone, Default)]
+struct Scope {
+    filename: String,
+    function: String,
+    counter: usize,
+    mode: Mode,
+}
+
+impl Scope {
+    fn new(filename: &str, mode: Mode) -> Self {
+        Self {
+            filename: filename.to_string(),
+            mode,
+            ..Self::default()
+        }
+    }
+
+    fn enter(&mut self, function: &str) {
+        self.function = function.to_string();
+        self.counter = 0;
+    }
+
+    /// Fresh labels such as `Main.fibonacci$ret.3`, all sharing one number.
+    /// Numbers count up per function, or per file outside any function.
+    fn labels<const N: usize>(&mut self, names: [&str; N]) -> [String; N] {
+        let scope = if self.function.is_empty() {
+            &self.filename
+        } else {
+            &self.function
+        };
+        let labels = names.map(|name| format!("{}${}.{}", scope, name, self.counter));
+        self.counter += 1;
+        labels
+    }
 }
 
-fn translate_vmcommand(
-    vm_command: &VmCommand,
-    filename: &str,
-    funcname: String,
-) -> (Vec<Command>, String) {
-    let push = [
+/// Pushes D.
+fn push_d() -> [Command; 5] {
+    [
         Command::ASymbol("SP".to_string()),
         Command::C(Dest::A, Comp::M, Jump::None),
         Command::C(Dest::M, Comp::D, Jump::None),
         Command::ASymbol("SP".to_string()),
         Command::C(Dest::M, Comp::M_PLUS_ONE, Jump::None),
-    ];
+    ]
+}
 
-    let pop = [
+/// Pops into A.
+fn pop_a() -> [Command; 5] {
+    [
         Command::ASymbol("SP".to_string()),
         Command::C(Dest::M, Comp::M_MINUS_ONE, Jump::None),
         Command::ASymbol("SP".to_string()),
         Command::C(Dest::A, Comp::M, Jump::None),
         Command::C(Dest::A, Comp::M, Jump::None),
+    ]
+}
+
+/// Pops y and x and pushes true if `x - y` satisfies `jump`, false otherwise.
+fn compare(jump: Jump, true_label: String, end_label: String) -> Vec<Command> {
+    let mut commands = pop_a().to_vec();
+    commands.push(Command::C(Dest::D, Comp::A, Jump::None));
+    commands.extend(pop_a());
+    commands.extend([
+        Command::C(Dest::D, Comp::A_MINUS_D, Jump::None),
+        Command::ASymbol(true_label.clone()),
+        Command::C(Dest::None, Comp::D, jump),
+        Command::ASymbol(end_label.clone()),
+        Command::C(Dest::D, Comp::ZERO, Jump::JMP),
+        Command::L(true_label),
+        Command::C(Dest::D, Comp::MINUS_ONE, Jump::None),
+        Command::L(end_label),
+    ]);
+    commands.extend(push_d());
+    commands
+}
+
+/// Restores the caller's frame and jumps back to it.
+fn return_sequence() -> Vec<Command> {
+    let mut commands = pop_a().to_vec();
+    commands.extend([
+        Command::C(Dest::D, Comp::A, Jump::None),
+        Command::ASymbol("R13".to_string()),
+        Command::C(Dest::M, Comp::D, Jump::None),
+        Command::ASymbol("ARG".to_string()),
+        Command::C(Dest::D, Comp::M, Jump::None),
+        Command::ASymbol("R16".to_string()),
+        Command::C(Dest::M, Comp::D, Jump::None),
+        Command::ASymbol("LCL".to_string()),
+        Command::C(Dest::A, Comp::M, Jump::None),
+        Command::C(Dest::D, Comp::A, Jump::None),
+        Command::ASymbol("SP".to_string()),
+        Command::C(Dest::M, Comp::D, Jump::None),
+    ]);
+    commands.extend(pop_a());
+    commands.extend([
+        Command::C(Dest::D, Comp::A, Jump::None),
+        Command::ASymbol("THAT".to_string()),
+        Command::C(Dest::M, Comp::D, Jump::None),
+    ]);
+    commands.extend(pop_a());
+    commands.extend([
+        Command::C(Dest::D, Comp::A, Jump::None),
+        Command::ASymbol("THIS".to_string()),
+        Command::C(Dest::M, Comp::D, Jump::None),
+    ]);
+    commands.extend(pop_a());
+    commands.extend([
+        Command::C(Dest::D, Comp::A, Jump::None),
+        Command::ASymbol("ARG".to_string()),
+        Command::C(Dest::M, Comp::D, Jump::None),
+    ]);
+    commands.extend(pop_a());
+    commands.extend([
+        Command::C(Dest::D, Comp::A, Jump::None),
+        Command::ASymbol("LCL".to_string()),
+        Command::C(Dest::M, Comp::D, Jump::None),
+    ]);
+    commands.extend(pop_a());
+    commands.extend([
+        Command::C(Dest::D, Comp::A, Jump::None),
+        Command::ASymbol("R14".to_string()),
+        Command::C(Dest::M, Comp::D, Jump::None),
+    ]);
+    commands.extend([
+        Command::ASymbol("R16".to_string()),
+        Command::C(Dest::D, Comp::M, Jump::None),
+        Command::ASymbol("SP".to_string()),
+        Command::C(Dest::M, Comp::D, Jump::None),
+        Command::ASymbol("R13".to_string()),
+        Command::C(Dest::D, Comp::M, Jump::None),
+    ]);
+    commands.extend(push_d());
+    commands.extend([
+        Command::ASymbol("R14".to_string()),
+        Command::C(Dest::A, Comp::M, Jump::None),
+        Command::C(Dest::None, Comp::ZERO, Jump::JMP),
+    ]);
+    commands
+}
+
+/// Pushes LCL, ARG, THIS and THAT.
+fn save_frame() -> Vec<Command> {
+    let mut commands = vec![];
+    for register in ["LCL", "ARG", "THIS", "THAT"] {
+        commands.extend([
+            Command::ASymbol(register.to_string()),
+            Command::C(Dest::D, Comp::M, Jump::None),
+        ]);
+        commands.extend(push_d());
+    }
+    commands
+}
+
+/// Jumps to a shared routine with the return address in D.
+fn call_routine(scope: &mut Scope, routine: &str) -> Vec<Command> {
+    let [return_address] = scope.labels(["ret"]);
+    vec![
+        Command::ASymbol(return_address.clone()),
+        Command::C(Dest::D, Comp::A, Jump::None),
+        Command::ASymbol(routine.to_string()),
+        Command::C(Dest::None, Comp::ZERO, Jump::JMP),
+        Command::L(return_address),
+    ]
+}
+
+fn translate_compare(scope: &mut Scope, name: &str, jump: Jump) -> Vec<Command> {
+    match scope.mode {
+        Mode::Inline => {
+            let [true_label, end_label] =
+                scope.labels([&format!("{}_TRUE", name), &format!("{}_END", name)]);
+            compare(jump, true_label, end_label)
+        }
+        Mode::Shared => call_routine(scope, &format!("VM${}", name)),
+    }
+}
+
+/// Names a routine's labels `{name}_{suffix}`, scoped like other generated
+/// labels when inlined or prefixed with `VM$` in the shared runtime.
+fn routine_labels<const N: usize>(
+    scope: Option<&mut Scope>,
+    name: &str,
+    suffixes: [&str; N],
+) -> [String; N] {
+    let names = suffixes.map(|suffix| format!("{}_{}", name, suffix));
+    match scope {
+        Some(scope) => scope.labels(names.each_ref().map(|name| name.as_str())),
+        None => names.map(|name| format!("VM${}", name)),
+    }
+}
+
+const MULTIPLY_LABELS: [&str; 3] = ["LOOP", "SKIP", "END"];
+const SHIFT_RIGHT_LABELS: [&str; 2] = ["LOOP", "NO_CARRY"];
+const DIVIDE_LABELS: [&str; 8] = [
+    "ABS_Y", "ABS_X", "LOOP", "NO_CARRY", "SUBTRACT", "NEXT", "SIGN_X", "SIGN_Y",
+];
+
+/// Points A at x when the stack ends with x and y.
+fn address_x() -> [Command; 3] {
+    [
+        Command::ASymbol("SP".to_string()),
+        Command::C(Dest::A, Comp::M_MINUS_ONE, Jump::None),
+        Command::C(Dest::A, Comp::A_MINUS_ONE, Jump::None),
+    ]
+}
+
+/// Replaces x and y with the value in D.
+fn replace_operands() -> [Command; 4] {
+    [
+        Command::ASymbol("SP".to_string()),
+        Command::C(Dest::M, Comp::M_MINUS_ONE, Jump::None),
+        Command::C(Dest::A, Comp::M_MINUS_ONE, Jump::None),
+        Command::C(Dest::M, Comp::D, Jump::None),
+    ]
+}
+
+/// Pops y and x and pushes `x * y` by shift and add, using only additions so
+/// that it runs on the standard CPU. Uses R14, R15 and the word above the
+/// stack.
+fn multiply([loop_label, skip_label, end_label]: [String; 3]) -> Vec<Command> {
+    // R14 holds the bits of y still to be added, R15 the current bit and the
+    // word above the stack the product; x doubles in place.
+    let mut commands = vec![
+        Command::ASymbol("SP".to_string()),
+        Command::C(Dest::A, Comp::M_MINUS_ONE, Jump::None),
+        Command::C(Dest::D, Comp::M, Jump::None),
+        Command::ASymbol("R14".to_string()),
+        Command::C(Dest::M, Comp::D, Jump::None),
+        Command::ASymbol("SP".to_string()),
+        Command::C(Dest::A, Comp::M, Jump::None),
+        Command::C(Dest::M, Comp::ZERO, Jump::None),
+        Command::ASymbol("R15".to_string()),
+        Command::C(Dest::M, Comp::ONE, Jump::None),
+        Command::L(loop_label.clone()),
+        Command::ASymbol("R14".to_string()),
+        Command::C(Dest::D, Comp::M, Jump::None),
+        Command::ASymbol(end_label.clone()),
+        Command::C(Dest::None, Comp::D, Jump::JEQ),
+        Command::ASymbol("R15".to_string()),
+        Command::C(Dest::D, Comp::D_AND_M, Jump::None),
+        Command::ASymbol(skip_label.clone()),
+        Command::C(Dest::None, Comp::D, Jump::JEQ),
+        Command::ASymbol("R15".to_string()),
+        Command::C(Dest::D, Comp::M, Jump::None),
+        Command::ASymbol("R14".to_string()),
+        Command::C(Dest::M, Comp::M_MINUS_D, Jump::None),
+    ];
+    commands.extend(address_x());
+    commands.extend([
+        Command::C(Dest::D, Comp::M, Jump::None),
+        Command::ASymbol("SP".to_string()),
+        Command::C(Dest::A, Comp::M, Jump::None),
+        Command::C(Dest::M, Comp::D_PLUS_M, Jump::None),
+        Command::L(skip_label),
+    ]);
+    commands.extend(address_x());
+    commands.extend([
+        Command::C(Dest::D, Comp::M, Jump::None),
+        Command::C(Dest::M, Comp::D_PLUS_M, Jump::None),
+        Command::ASymbol("R15".to_string()),
+        Command::C(Dest::D, Comp::M, Jump::None),
+        Command::C(Dest::M, Comp::D_PLUS_M, Jump::None),
+        Command::ASymbol(loop_label),
+        Command::C(Dest::None, Comp::ZERO, Jump::JMP),
+        Command::L(end_label),
+        Command::ASymbol("SP".to_string()),
+        Command::C(Dest::A, Comp::M, Jump::None),
+        Command::C(Dest::D, Comp::M, Jump::None),
+    ]);
+    commands.extend(replace_operands());
+    commands
+}
+
+/// Pops y and x and pushes `x / y` truncated towards zero, or `x % y` with
+/// the sign of x when `remainder` is set. Works on the magnitudes with long
+/// division and fixes the signs afterwards; dividing by zero gives garbage.
+/// Uses R14, R15 and the two words above the stack.
+fn divide(remainder: bool, labels: [String; 8]) -> Vec<Command> {
+    let [abs_y, abs_x, loop_label, no_carry, subtract, next, sign_x, sign_y] = labels;
+    // R14 holds |x|, which shifts out at the top while the quotient shifts
+    // in at the bottom, R15 the partial remainder, the word above the stack
+    // |y| and the one above that the bits left.
+    let mut commands = vec![
+        Command::ASymbol("SP".to_string()),
+        Command::C(Dest::A, Comp::M_MINUS_ONE, Jump::None),
+        Command::C(Dest::D, Comp::M, Jump::None),
+        Command::ASymbol(abs_y.clone()),
+        Command::C(Dest::None, Comp::D, Jump::JGE),
+        Command::C(Dest::D, Comp::MINUS_D, Jump::None),
+        Command::L(abs_y),
+        Command::ASymbol("SP".to_string()),
+        Command::C(Dest::A, Comp::M, Jump::None),
+        Command::C(Dest::M, Comp::D, Jump::None),
+    ];
+    commands.extend(address_x());
+    commands.extend([
+        Command::C(Dest::D, Comp::M, Jump::None),
+        Command::ASymbol(abs_x.clone()),
+        Command::C(Dest::None, Comp::D, Jump::JGE),
+        Command::C(Dest::D, Comp::MINUS_D, Jump::None),
+        Command::L(abs_x),
+        Command::ASymbol("R14".to_string()),
+        Command::C(Dest::M, Comp::D, Jump::None),
+        Command::ASymbol("R15".to_string()),
+        Command::C(Dest::M, Comp::ZERO, Jump::None),
+        Command::AImm(16),
+        Command::C(Dest::D, Comp::A, Jump::None),
+        Command::ASymbol("SP".to_string()),
+        Command::C(Dest::A, Comp::M_PLUS_ONE, Jump::None),
+        Command::C(Dest::M, Comp::D, Jump::None),
+        // Shift the top bit of R14 into R15.
+        Command::L(loop_label.clone()),
+        Command::ASymbol("R15".to_string()),
+        Command::C(Dest::D, Comp::M, Jump::None),
+        Command::C(Dest::M, Comp::D_PLUS_M, Jump::None),
+        Command::ASymbol("R14".to_string()),
+        Command::C(Dest::D, Comp::M, Jump::None),
+        Command::C(Dest::M, Comp::D_PLUS_M, Jump::None),
+        Command::ASymbol(no_carry.clone()),
+        Command::C(Dest::None, Comp::D, Jump::JGE),
+        Command::ASymbol("R15".to_string()),
+        Command::C(Dest::M, Comp::M_PLUS_ONE, Jump::None),
+        Command::L(no_carry),
+        // The remainder is below 2|y| <= 0x10000, so it is at least |y| when
+        // its top bit is set, and otherwise when the signed difference is not
+        // negative, which also covers |y| = 0x8000.
+        Command::ASymbol("R15".to_string()),
+        Command::C(Dest::D, Comp::M, Jump::None),
+        Command::ASymbol(subtract.clone()),
+        Command::C(Dest::None, Comp::D, Jump::JLT),
+        Command::ASymbol("SP".to_string()),
+        Command::C(Dest::A, Comp::M, Jump::None),
+        Command::C(Dest::D, Comp::D_MINUS_M, Jump::None),
+        Command::ASymbol(next.clone()),
+        Command::C(Dest::None, Comp::D, Jump::JLT),
+        Command::L(subtract),
+        Command::ASymbol("SP".to_string()),
+        Command::C(Dest::A, Comp::M, Jump::None),
+        Command::C(Dest::D, Comp::M, Jump::None),
+        Command::ASymbol("R15".to_string()),
+        Command::C(Dest::M, Comp::M_MINUS_D, Jump::None),
+        Command::ASymbol("R14".to_string()),
+        Command::C(Dest::M, Comp::M_PLUS_ONE, Jump::None),
+        Command::L(next),
+        Command::ASymbol("SP".to_string()),
+        Command::C(Dest::A, Comp::M_PLUS_ONE, Jump::None),
+        Command::C(Dest::MD, Comp::M_MINUS_ONE, Jump::None),
+        Command::ASymbol(loop_label),
+        Command::C(Dest::None, Comp::D, Jump::JGT),
+    ]);
+
+    // The quotient is negated once for each negative operand, the remainder
+    // only for a negative x.
+    let result = if remainder { "R15" } else { "R14" };
+    commands.extend(address_x());
+    commands.extend([
+        Command::C(Dest::D, Comp::M, Jump::None),
+        Command::ASymbol(sign_x.clone()),
+        Command::C(Dest::None, Comp::D, Jump::JGE),
+        Command::ASymbol(result.to_string()),
+        Command::C(Dest::M, Comp::MINUS_M, Jump::None),
+        Command::L(sign_x),
+    ]);
+    if !remainder {
+        commands.extend([
+            Command::ASymbol("SP".to_string()),
+            Command::C(Dest::A, Comp::M_MINUS_ONE, Jump::None),
+            Command::C(Dest::D, Comp::M, Jump::None),
+            Command::ASymbol(sign_y.clone()),
+            Command::C(Dest::None, Comp::D, Jump::JGE),
+            Command::ASymbol(result.to_string()),
+            Command::C(Dest::M, Comp::MINUS_M, Jump::None),
+            Command::L(sign_y),
+        ]);
+    }
+    commands.extend([
+        Command::ASymbol(result.to_string()),
+        Command::C(Dest::D, Comp::M, Jump::None),
+    ]);
+    commands.extend(replace_operands());
+    commands
+}
+
+/// Replaces x with `x >> 1`, shifting in a zero. The standard CPU cannot
+/// shift right, so this shifts the top 15 bits of x into R15 one at a time,
+/// the way `divide` does. Uses R14, R15 and the word above the stack.
+fn shift_right([loop_label, no_carry]: [String; 2]) -> Vec<Command> {
+    let mut commands = vec![
+        Command::ASymbol("SP".to_string()),
+        Command::C(Dest::A, Comp::M_MINUS_ONE, Jump::None),
+        Command::C(Dest::D, Comp::M, Jump::None),
+        Command::ASymbol("R14".to_string()),
+        Command::C(Dest::M, Comp::D, Jump::None),
+        Command::ASymbol("R15".to_string()),
+        Command::C(Dest::M, Comp::ZERO, Jump::None),
+        Command::AImm(15),
+        Command::C(Dest::D, Comp::A, Jump::None),
+        Command::ASymbol("SP".to_string()),
+        Command::C(Dest::A, Comp::M, Jump::None),
+        Command::C(Dest::M, Comp::D, Jump::None),
+        Command::L(loop_label.clone()),
+        Command::ASymbol("R15".to_string()),
+        Command::C(Dest::D, Comp::M, Jump::None),
+        Command::C(Dest::M, Comp::D_PLUS_M, Jump::None),
+        Command::ASymbol("R14".to_string()),
+        Command::C(Dest::D, Comp::M, Jump::None),
+        Command::C(Dest::M, Comp::D_PLUS_M, Jump::None),
+        Command::ASymbol(no_carry.clone()),
+        Command::C(Dest::None, Comp::D, Jump::JGE),
+        Command::ASymbol("R15".to_string()),
+        Command::C(Dest::M, Comp::M_PLUS_ONE, Jump::None),
+        Command::L(no_carry),
+        Command::ASymbol("SP".to_string()),
+        Command::C(Dest::A, Comp::M, Jump::None),
+        Command::C(Dest::MD, Comp::M_MINUS_ONE, Jump::None),
+        Command::ASymbol(loop_label),
+        Command::C(Dest::None, Comp::D, Jump::JGT),
+        Command::ASymbol("R15".to_string()),
+        Command::C(Dest::D, Comp::M, Jump::None),
     ];
+    commands.extend(modify_top(Comp::D));
+    commands
+}
+
+fn translate_shift_right(scope: &mut Scope) -> Vec<Command> {
+    match scope.mode {
+        Mode::Inline => shift_right(routine_labels(Some(scope), "SHR", SHIFT_RIGHT_LABELS)),
+        Mode::Shared => call_routine(scope, "VM$SHR"),
+    }
+}
+
+fn translate_multiply(scope: &mut Scope) -> Vec<Command> {
+    match scope.mode {
+        Mode::Inline => multiply(routine_labels(Some(scope), "MUL", MULTIPLY_LABELS)),
+        Mode::Shared => call_routine(scope, "VM$MUL"),
+    }
+}
+
+fn translate_divide(scope: &mut Scope, name: &str, remainder: bool) -> Vec<Command> {
+    match scope.mode {
+        Mode::Inline => divide(remainder, routine_labels(Some(scope), name, DIVIDE_LABELS)),
+        Mode::Shared => call_routine(scope, &format!("VM${}", name)),
+    }
+}
+
+/// Applies `comp`, which reads M, to the top of the stack in place.
+fn modify_top(comp: Comp) -> Vec<Command> {
+    vec![
+        Command::ASymbol("SP".to_string()),
+        Command::C(Dest::A, Comp::M_MINUS_ONE, Jump::None),
+        Command::C(Dest::M, comp, Jump::None),
+    ]
+}
+
+fn translate_vmcommand(vm_command: &VmCommand, scope: &mut Scope) -> anyhow::Result<Vec<Command>> {
+    let push = push_d();
+    let pop = pop_a();
 
     let swap = [
         Command::C(Dest::D, Comp::D_PLUS_A, Jump::None),
@@ -70,21 +634,6 @@ fn translate_vmcommand(
         result
     };
 
-    fn assign_imm_if(jump: Jump, true_value: Comp, false_value: Comp) -> Vec<Command> {
-        let l0 = random_string(12);
-        let l1 = random_string(12);
-        vec![
-            Command::C(Dest::D, Comp::A_MINUS_D, Jump::None),
-            Command::ASymbol(l0.clone()),
-            Command::C(Dest::None, Comp::D, jump),
-            Command::ASymbol(l1.clone()),
-            Command::C(Dest::D, false_value, Jump::JMP),
-            Command::L(l0.clone()),
-            Command::C(Dest::D, true_value, Jump::None),
-            Command::L(l1.clone()),
-        ]
-    }
-
     let calc_address = |symbol_name: String, index: u16| -> Vec<Command> {
         vec![
             Command::AImm(index),
@@ -110,7 +659,6 @@ fn translate_vmcommand(
         commands
     };
 
-    let mut funcname = funcname;
     let commands = match vm_command {
         VmCommand::Push(segment, index) => match segment {
             Segment::Argument => load_segment(calc_address("ARG".to_string(), *index)),
@@ -119,9 +667,10 @@ fn translate_vmcommand(
             Segment::That => load_segment(calc_address("THAT".to_string(), *index)),
             Segment::Pointer => load_segment(vec![Command::AImm(*index + 3)]),
             Segment::Temp => load_segment(vec![Command::AImm(*index + 5)]),
-            Segment::Static => {
-                load_segment(vec![Command::ASymbol(format!("{}.{}", filename, index))])
-            }
+            Segment::Static => load_segment(vec![Command::ASymbol(format!(
+                "{}.{}",
+                scope.filename, index
+            ))]),
             Segment::Constant => {
                 let mut commands = vec![
                     Command::AImm(*index),
@@ -130,7 +679,6 @@ fn translate_vmcommand(
                 commands.extend(push.clone());
                 commands
             }
-            _ => panic!("unsupported segment for push: {:?}", segment),
         },
         VmCommand::Pop(segment, index) => match segment {
             Segment::Argument => store_segment(calc_address("ARG".to_string(), *index)),
@@ -139,16 +687,11 @@ fn translate_vmcommand(
             Segment::That => store_segment(calc_address("THAT".to_string(), *index)),
             Segment::Pointer => store_segment(vec![Command::AImm(*index + 3)]),
             Segment::Temp => store_segment(vec![Command::AImm(*index + 5)]),
-            Segment::Static => {
-                store_segment(vec![Command::ASymbol(format!("{}.{}", filename, index))])
-            }
-            Segment::Constant => {
-                vec![
-                    Command::ASymbol("SP".to_string()),
-                    Command::C(Dest::M, Comp::M_MINUS_ONE, Jump::None),
-                ]
-            }
-            _ => panic!("unsupported segment for pop: {:?}", segment),
+            Segment::Static => store_segment(vec![Command::ASymbol(format!(
+                "{}.{}",
+                scope.filename, index
+            ))]),
+            Segment::Constant => anyhow::bail!("Cannot pop to constant"),
         },
 
         VmCommand::Add => {
@@ -158,15 +701,9 @@ fn translate_vmcommand(
             translate_binary_command(vec![Command::C(Dest::D, Comp::A_MINUS_D, Jump::None)])
         }
         VmCommand::Neg => translate_unary_command(Command::C(Dest::D, Comp::MINUS_D, Jump::None)),
-        VmCommand::Eq => {
-            translate_binary_command(assign_imm_if(Jump::JEQ, Comp::MINUS_ONE, Comp::ZERO))
-        }
-        VmCommand::Gt => {
-            translate_binary_command(assign_imm_if(Jump::JGT, Comp::MINUS_ONE, Comp::ZERO))
-        }
-        VmCommand::Lt => {
-            translate_binary_command(assign_imm_if(Jump::JLT, Comp::MINUS_ONE, Comp::ZERO))
-        }
+        VmCommand::Eq => translate_compare(scope, "EQ", Jump::JEQ),
+        VmCommand::Gt => translate_compare(scope, "GT", Jump::JGT),
+        VmCommand::Lt => translate_compare(scope, "LT", Jump::JLT),
         VmCommand::And => {
             translate_binary_command(vec![Command::C(Dest::D, Comp::D_AND_A, Jump::None)])
         }
@@ -174,11 +711,50 @@ fn translate_vmcommand(
             translate_binary_command(vec![Command::C(Dest::D, Comp::D_OR_A, Jump::None)])
         }
         VmCommand::Not => translate_unary_command(Command::C(Dest::D, Comp::INV_D, Jump::None)),
+        VmCommand::Mul => translate_multiply(scope),
+        VmCommand::Div => translate_divide(scope, "DIV", false),
+        VmCommand::Mod => translate_divide(scope, "MOD", true),
+        VmCommand::Shl => vec![
+            Command::ASymbol("SP".to_string()),
+            Command::C(Dest::A, Comp::M_MINUS_ONE, Jump::None),
+            Command::C(Dest::D, Comp::M, Jump::None),
+            Command::C(Dest::M, Comp::D_PLUS_M, Jump::None),
+        ],
+        VmCommand::Shr => translate_shift_right(scope),
+        VmCommand::Lte => translate_compare(scope, "LTE", Jump::JLE),
+        VmCommand::Gte => translate_compare(scope, "GTE", Jump::JGE),
+        VmCommand::Neq => translate_compare(scope, "NEQ", Jump::JNE),
+        VmCommand::Inc => modify_top(Comp::M_PLUS_ONE),
+        VmCommand::Dec => modify_top(Comp::M_MINUS_ONE),
+        VmCommand::Dup => {
+            let mut commands = vec![
+                Command::ASymbol("SP".to_string()),
+                Command::C(Dest::A, Comp::M_MINUS_ONE, Jump::None),
+                Command::C(Dest::D, Comp::M, Jump::None),
+            ];
+            commands.extend(push.clone());
+            commands
+        }
+        VmCommand::Swap => {
+            // x' = x + y, y' = x' - y = x, x'' = x' - y' = y
+            vec![
+                Command::ASymbol("SP".to_string()),
+                Command::C(Dest::A, Comp::M_MINUS_ONE, Jump::None),
+                Command::C(Dest::D, Comp::M, Jump::None),
+                Command::C(Dest::A, Comp::A_MINUS_ONE, Jump::None),
+                Command::C(Dest::M, Comp::D_PLUS_M, Jump::None),
+                Command::C(Dest::D, Comp::M_MINUS_D, Jump::None),
+                Command::C(Dest::A, Comp::A_PLUS_ONE, Jump::None),
+                Command::C(Dest::M, Comp::D, Jump::None),
+                Command::C(Dest::A, Comp::A_MINUS_ONE, Jump::None),
+                Command::C(Dest::M, Comp::M_MINUS_D, Jump::None),
+            ]
+        }
         VmCommand::Label(ref label) => {
-            vec![Command::L(format!("{}.{}", funcname, label))]
+            vec![Command::L(format!("{}${}", scope.function, label))]
         }
         VmCommand::Function(ref label, k) => {
-            funcname = label.clone();
+            scope.enter(label);
             vec![
                 Command::L(label.clone()),
                 Command::ASymbol("SP".to_string()),
@@ -191,97 +767,37 @@ fn translate_vmcommand(
                 Command::C(Dest::M, Comp::D, Jump::None),
             ]
         }
-        VmCommand::Return => {
-            let mut commands = pop.clone().to_vec();
-            commands.extend([
-                Command::C(Dest::D, Comp::A, Jump::None),
-                Command::ASymbol("R13".to_string()),
-                Command::C(Dest::M, Comp::D, Jump::None),
-                Command::ASymbol("ARG".to_string()),
-                Command::C(Dest::D, Comp::M, Jump::None),
-                Command::ASymbol("R16".to_string()),
-                Command::C(Dest::M, Comp::D, Jump::None),
-                Command::ASymbol("LCL".to_string()),
-                Command::C(Dest::A, Comp::M, Jump::None),
-                Command::C(Dest::D, Comp::A, Jump::None),
-                Command::ASymbol("SP".to_string()),
-                Command::C(Dest::M, Comp::D, Jump::None),
-            ]);
-            commands.extend(pop.clone());
-            commands.extend([
-                Command::C(Dest::D, Comp::A, Jump::None),
-                Command::ASymbol("THAT".to_string()),
-                Command::C(Dest::M, Comp::D, Jump::None),
-            ]);
-            commands.extend(pop.clone());
-            commands.extend([
-                Command::C(Dest::D, Comp::A, Jump::None),
-                Command::ASymbol("THIS".to_string()),
-                Command::C(Dest::M, Comp::D, Jump::None),
-            ]);
-            commands.extend(pop.clone());
-            commands.extend([
-                Command::C(Dest::D, Comp::A, Jump::None),
-                Command::ASymbol("ARG".to_string()),
-                Command::C(Dest::M, Comp::D, Jump::None),
-            ]);
-            commands.extend(pop.clone());
-            commands.extend([
-                Command::C(Dest::D, Comp::A, Jump::None),
-                Command::ASymbol("LCL".to_string()),
-                Command::C(Dest::M, Comp::D, Jump::None),
-            ]);
-            commands.extend(pop.clone());
-            commands.extend([
+        VmCommand::Return => match scope.mode {
+            Mode::Inline => return_sequence(),
+            Mode::Shared => vec![
+                Command::ASymbol("VM$RETURN".to_string()),
+                Command::C(Dest::None, Comp::ZERO, Jump::JMP),
+            ],
+        },
+
+        VmCommand::Call(ref label, k) if scope.mode == Mode::Shared => {
+            let mut commands = vec![
+                Command::AImm(*k),
                 Command::C(Dest::D, Comp::A, Jump::None),
                 Command::ASymbol("R14".to_string()),
                 Command::C(Dest::M, Comp::D, Jump::None),
-            ]);
-            commands.extend([
-                Command::ASymbol("R16".to_string()),
-                Command::C(Dest::D, Comp::M, Jump::None),
-                Command::ASymbol("SP".to_string()),
+                Command::ASymbol(label.clone()),
+                Command::C(Dest::D, Comp::A, Jump::None),
+                Command::ASymbol("R15".to_string()),
                 Command::C(Dest::M, Comp::D, Jump::None),
-                Command::ASymbol("R13".to_string()),
-                Command::C(Dest::D, Comp::M, Jump::None),
-            ]);
-            commands.extend(push.clone());
-            commands.extend([
-                Command::ASymbol("R14".to_string()),
-                Command::C(Dest::A, Comp::M, Jump::None),
-                Command::C(Dest::None, Comp::ZERO, Jump::JMP),
-            ]);
+            ];
+            commands.extend(call_routine(scope, "VM$CALL"));
             commands
         }
-
         VmCommand::Call(ref label, k) => {
-            let return_address = random_string(12);
+            let [return_address] = scope.labels(["ret"]);
             let mut commands = vec![];
             commands.extend([
                 Command::ASymbol(return_address.clone()),
                 Command::C(Dest::D, Comp::A, Jump::None),
             ]);
             commands.extend(push.clone());
-            commands.extend([
-                Command::ASymbol("LCL".to_string()),
-                Command::C(Dest::D, Comp::M, Jump::None),
-            ]);
-            commands.extend(push.clone());
-            commands.extend([
-                Command::ASymbol("ARG".to_string()),
-                Command::C(Dest::D, Comp::M, Jump::None),
-            ]);
-            commands.extend(push.clone());
-            commands.extend([
-                Command::ASymbol("THIS".to_string()),
-                Command::C(Dest::D, Comp::M, Jump::None),
-            ]);
-            commands.extend(push.clone());
-            commands.extend([
-                Command::ASymbol("THAT".to_string()),
-                Command::C(Dest::D, Comp::M, Jump::None),
-            ]);
-            commands.extend(push.clone());
+            commands.extend(save_frame());
             commands.extend([
                 Command::ASymbol("SP".to_string()),
                 Command::C(Dest::D, Comp::M, Jump::None),
@@ -299,14 +815,14 @@ fn translate_vmcommand(
             commands.extend([
                 Command::ASymbol(label.clone()),
                 Command::C(Dest::None, Comp::ZERO, Jump::JMP),
-                Command::L(return_address.clone())
+                Command::L(return_address.clone()),
             ]);
             commands
         }
 
         VmCommand::Goto(ref label) => {
             vec![
-                Command::ASymbol(format!("{}.{}", funcname, label)),
+                Command::ASymbol(format!("{}${}", scope.function, label)),
                 Command::C(Dest::None, Comp::ZERO, Jump::JMP),
             ]
         }
@@ -315,14 +831,13 @@ fn translate_vmcommand(
             commands.extend(pop.clone());
             commands.extend([
                 Command::C(Dest::D, Comp::A, Jump::None),
-                Command::ASymbol(format!("{}.{}", funcname, label)),
+                Command::ASymbol(format!("{}${}", scope.function, label)),
                 Command::C(Dest::None, Comp::D, Jump::JNE),
             ]);
             commands
         }
-        _ => panic!("unsupported command"),
     };
-    (commands, funcname)
+    Ok(commands)
 }
 
 pub fn bootstrap(sp: u16) -> Vec<Command> {
@@ -336,17 +851,485 @@ pub fn bootstrap(sp: u16) -> Vec<Command> {
     ]
 }
 
+/// The book's bootstrap: sets SP and then does `call Sys.init 0`, so that
+/// `Sys.init` starts with a full frame below it instead of being jumped to.
+pub fn bootstrap_call(sp: u16, mode: Mode) -> Vec<Command> {
+    let mut commands = vec![
+        Command::AImm(sp),
+        Command::C(Dest::D, Comp::A, Jump::None),
+        Command::ASymbol("SP".to_string()),
+        Command::C(Dest::M, Comp::D, Jump::None),
+    ];
+    let call = VmCommand::Call("Sys.init".to_string(), 0);
+    let mut scope = Scope::new("Bootstrap", mode);
+    commands.extend(translate_vmcommand(&call, &mut scope).expect("call always translates"));
+    commands
+}
+
+/// What a translated program starts with: the book's bootstrap when `sp` is
+/// given and, in `Mode::Shared`, the runtime that the translated code jumps
+/// into. Without a bootstrap, execution jumps over the runtime.
+pub fn prelude(sp: Option<u16>, mode: Mode) -> Vec<Command> {
+    let mut commands = sp.map(|sp| bootstrap_call(sp, mode)).unwrap_or_default();
+    if mode == Mode::Shared {
+        let start = "VM$START".to_string();
+        if sp.is_none() {
+            commands.extend([
+                Command::ASymbol(start.clone()),
+                Command::C(Dest::None, Comp::ZERO, Jump::JMP),
+            ]);
+        }
+        commands.extend(runtime());
+        if sp.is_none() {
+            commands.push(Command::L(start));
+        }
+    }
+    commands
+}
+
+/// The routines that `Mode::Shared` code jumps into, each returning to the
+/// address in D.
+fn runtime() -> Vec<Command> {
+    // D holds the return address, R14 the argument count and R15 the callee.
+    let mut commands = vec![Command::L("VM$CALL".to_string())];
+    commands.extend(push_d());
+    commands.extend(save_frame());
+    commands.extend([
+        Command::ASymbol("SP".to_string()),
+        Command::C(Dest::D, Comp::M, Jump::None),
+        Command::ASymbol("R14".to_string()),
+        Command::C(Dest::D, Comp::D_MINUS_M, Jump::None),
+        Command::AImm(5),
+        Command::C(Dest::D, Comp::D_MINUS_A, Jump::None),
+        Command::ASymbol("ARG".to_string()),
+        Command::C(Dest::M, Comp::D, Jump::None),
+        Command::ASymbol("SP".to_string()),
+        Command::C(Dest::D, Comp::M, Jump::None),
+        Command::ASymbol("LCL".to_string()),
+        Command::C(Dest::M, Comp::D, Jump::None),
+        Command::ASymbol("R15".to_string()),
+        Command::C(Dest::A, Comp::M, Jump::None),
+        Command::C(Dest::None, Comp::ZERO, Jump::JMP),
+    ]);
+
+    commands.push(Command::L("VM$RETURN".to_string()));
+    commands.extend(return_sequence());
+
+    let comparisons = [
+        ("EQ", Jump::JEQ),
+        ("GT", Jump::JGT),
+        ("LT", Jump::JLT),
+        ("LTE", Jump::JLE),
+        ("GTE", Jump::JGE),
+        ("NEQ", Jump::JNE),
+    ];
+    for (name, jump) in comparisons {
+        commands.extend([
+            Command::L(format!("VM${}", name)),
+            Command::ASymbol("R13".to_string()),
+            Command::C(Dest::M, Comp::D, Jump::None),
+        ]);
+        commands.extend(compare(
+            jump,
+            format!("VM${}_TRUE", name),
+            format!("VM${}_END", name),
+        ));
+        commands.extend([
+            Command::ASymbol("R13".to_string()),
+            Command::C(Dest::A, Comp::M, Jump::None),
+            Command::C(Dest::None, Comp::ZERO, Jump::JMP),
+        ]);
+    }
+
+    let arithmetic = [
+        (
+            "MUL",
+            multiply(routine_labels(None, "MUL", MULTIPLY_LABELS)),
+        ),
+        (
+            "DIV",
+            divide(false, routine_labels(None, "DIV", DIVIDE_LABELS)),
+        ),
+        (
+            "MOD",
+            divide(true, routine_labels(None, "MOD", DIVIDE_LABELS)),
+        ),
+        (
+            "SHR",
+            shift_right(routine_labels(None, "SHR", SHIFT_RIGHT_LABELS)),
+        ),
+    ];
+    for (name, body) in arithmetic {
+        commands.extend([
+            Command::L(format!("VM${}", name)),
+            Command::ASymbol("R13".to_string()),
+            Command::C(Dest::M, Comp::D, Jump::None),
+        ]);
+        commands.extend(body);
+        commands.extend([
+            Command::ASymbol("R13".to_string()),
+            Command::C(Dest::A, Comp::M, Jump::None),
+            Command::C(Dest::None, Comp::ZERO, Jump::JMP),
+        ]);
+    }
+    commands
+}
+
+/// Translates `vm_commands`, handing each Hack command to `emit` as soon as
+/// it is generated instead of collecting the whole program.
+pub fn translate_with(
+    vm_commands: &[VmCommand],
+    filename: Option<&str>,
+    mode: Mode,
+    mut emit: impl FnMut(Command) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    translate_indexed(vm_commands, filename, mode, |_, command| emit(command))
+}
+
+/// Like `translate_with`, but also tells `emit` the index of the VM command
+/// that each Hack command came from.
+///
+/// The commands carry no spans, so problems found by `validate` are reported
+/// against the commands as `dump`ed one per line, the way the compiler writes
+/// `.vm` files. Use `check` first to report them against the real source.
+pub fn translate_indexed(
+    vm_commands: &[VmCommand],
+    filename: Option<&str>,
+    mode: Mode,
+    mut emit: impl FnMut(usize, Command) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let errors = validate(vm_commands);
+    if !errors.is_empty() {
+        let diagnostics = errors
+            .into_iter()
+            .map(|(i, message)| {
+                let line = vm_commands[i].dump();
+                Diagnostic::new(message, Span::new(i + 1, 1, line.len()), &line)
+            })
+            .collect();
+        let name = filename.map_or("<vm>".to_string(), |name| format!("{}.vm", name));
+        anyhow::bail!("{}", Diagnostics::new(&name, diagnostics));
+    }
+
+    let mut scope = Scope::new(filename.unwrap_or("__STATIC"), mode);
+    for (i, vm_command) in vm_commands.iter().enumerate() {
+        for command in translate_vmcommand(vm_command, &mut scope)? {
+            emit(i, command)?;
+        }
+    }
+    Ok(())
+}
+
 pub fn translate(
     vm_commands: &[VmCommand],
     filename: Option<&str>,
 ) -> anyhow::Result<Vec<Command>> {
     let mut result = Vec::new();
-    let filename = filename.unwrap_or("__STATIC");
-    let mut funcname = String::default();
-    for vm_command in vm_commands {
-        let (c, f2) = translate_vmcommand(vm_command, filename, funcname);
-        funcname = f2;
-        result.extend(c);
-    }
+    translate_with(vm_commands, filename, Mode::Inline, |command| {
+        result.push(command);
+        Ok(())
+    })?;
     Ok(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs Hack code on the standard CPU with RAM in `ram` until it reaches
+    /// an `@L; 0;JMP` loop onto itself, and returns false if that takes more
+    /// than `max_steps`. Panics on the extended CPU's instructions.
+    fn run_hack(words: &[u16], ram: &mut [u16], max_steps: usize) -> bool {
+        let (mut a, mut d, mut pc) = (0u16, 0u16, 0usize);
+        for _ in 0..max_steps {
+            let word = match words.get(pc) {
+                Some(word) => *word,
+                None => return false,
+            };
+            if word & 0x8000 == 0 {
+                a = word;
+                pc += 1;
+                continue;
+            }
+
+            let comp = (word >> 6) & 0x7f;
+            let y = if comp & 0x40 != 0 { ram[a as usize] } else { a };
+            assert_eq!(word >> 13, 0b111, "extended instruction at {}", pc);
+            let x = if comp & 0x20 != 0 { 0 } else { d };
+            let x = if comp & 0x10 != 0 { !x } else { x };
+            let y = if comp & 0x08 != 0 { 0 } else { y };
+            let y = if comp & 0x04 != 0 { !y } else { y };
+            let out = if comp & 0x02 != 0 {
+                x.wrapping_add(y)
+            } else {
+                x & y
+            };
+            let out = if comp & 0x01 != 0 { !out } else { out };
+            let value = out as i16;
+            let jump = (word & 0x4 != 0 && value < 0)
+                || (word & 0x2 != 0 && value == 0)
+                || (word & 0x1 != 0 && value > 0);
+
+            let target = a as usize;
+            if word & 0x08 != 0 {
+                ram[target] = out;
+            }
+            if word & 0x10 != 0 {
+                d = out;
+            }
+            if word & 0x20 != 0 {
+                a = out;
+            }
+            if !jump {
+                pc += 1;
+            } else if target + 1 == pc && words[target] as usize == target {
+                return true;
+            } else {
+                pc = target;
+            }
+        }
+        false
+    }
+
+    /// Translates `source` as file `Sys` after the prelude and runs it, then
+    /// returns SP and the first `statics` static variables.
+    fn run_vm(source: &str, mode: Mode, statics: u16) -> Vec<u16> {
+        let vm_commands = parse(&source.lines().collect::<Vec<_>>()).unwrap();
+        let mut program = prelude(Some(256), mode);
+        translate_with(&vm_commands, Some("Sys"), mode, |command| {
+            program.push(command);
+            Ok(())
+        })
+        .unwrap();
+        let (words, symbols) = assembler::code::assemble_with_symbols(&program).unwrap();
+
+        let mut ram = vec![0; 0x8000];
+        assert!(run_hack(&words, &mut ram, 1_000_000));
+        let mut values = vec![ram[0]];
+        values.extend(
+            (0..statics).map(|i| ram[symbols.get(&format!("Sys.{}", i)).unwrap() as usize]),
+        );
+        values
+    }
+
+    #[test]
+    fn test_shared_mode_runs_like_inline() {
+        let source = "function Sys.init 0\npush constant 7\npush constant 3\ncall Sys.f 2\npop static 0\npush constant 3\npush constant 7\ncall Sys.f 2\npop static 1\npush constant 5\npush constant 5\neq\npop static 2\npush constant 2\npush constant 9\nlt\npop static 3\npush constant 2\npush constant 9\ngt\npop static 4\nlabel HALT\ngoto HALT\nfunction Sys.f 1\npush argument 0\npush argument 1\nsub\npop local 0\npush local 0\npush argument 1\ngt\nif-goto BIG\npush local 0\nreturn\nlabel BIG\npush local 0\npush constant 100\nadd\nreturn";
+        let inline = run_vm(source, Mode::Inline, 5);
+        assert_eq!(inline, vec![261, 104, (-4i16) as u16, 0xffff, 0xffff, 0]);
+        assert_eq!(run_vm(source, Mode::Shared, 5), inline);
+    }
+
+    #[test]
+    fn test_labels_are_deterministic() {
+        let source = "function Main.main 0\npush constant 1\npush constant 2\neq\ncall Main.f 0\nlt\ncall Main.f 0\nreturn\nfunction Main.f 0\nlabel LOOP\ncall Main.main 0\nif-goto LOOP\nreturn";
+        let vm_commands = parse(&source.lines().collect::<Vec<_>>()).unwrap();
+        let commands = translate(&vm_commands, Some("Main")).unwrap();
+        assert_eq!(commands, translate(&vm_commands, Some("Main")).unwrap());
+
+        let labels = commands
+            .iter()
+            .filter_map(|command| match command {
+                Command::L(label) => Some(label.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            labels,
+            vec![
+                "Main.main",
+                "Main.main$EQ_TRUE.0",
+                "Main.main$EQ_END.0",
+                "Main.main$ret.1",
+                "Main.main$LT_TRUE.2",
+                "Main.main$LT_END.2",
+                "Main.main$ret.3",
+                "Main.f",
+                "Main.f$LOOP",
+                "Main.f$ret.0",
+            ]
+        );
+        assert!(commands.contains(&Command::ASymbol("Main.f$LOOP".to_string())));
+    }
+
+    #[test]
+    fn test_shared_mode() {
+        let source =
+            "function Main.main 0\npush constant 1\npush constant 2\nlt\ncall Main.main 1\nreturn";
+        let vm_commands = parse(&source.lines().collect::<Vec<_>>()).unwrap();
+        let inline = translate(&vm_commands, Some("Main")).unwrap();
+        let mut shared = vec![];
+        translate_with(&vm_commands, Some("Main"), Mode::Shared, |command| {
+            shared.push(command);
+            Ok(())
+        })
+        .unwrap();
+        assert!(shared.len() * 2 < inline.len());
+
+        shared.extend(runtime());
+        let (_, symbols) = assembler::code::assemble_with_symbols(&shared).unwrap();
+        assert!(symbols
+            .variables()
+            .all(|(name, _)| !name.starts_with("VM$")));
+        assert!(symbols.get("VM$CALL").is_some());
+    }
+
+    #[test]
+    fn test_shared_mode_link_reports_missing_callee() {
+        use assembler::object::{link, Object};
+
+        let source = "function Main.main 0\ncall Sys.halt 0\nreturn";
+        let vm_commands = parse(&source.lines().collect::<Vec<_>>()).unwrap();
+        let mut main = vec![];
+        translate_with(&vm_commands, Some("Main"), Mode::Shared, |command| {
+            main.push(command);
+            Ok(())
+        })
+        .unwrap();
+        let main = Object::assemble("Main", &main).unwrap();
+        assert!(main.imports().contains("Sys.halt"));
+
+        let runtime = Object::assemble("Runtime", &runtime()).unwrap();
+        let err = link(&[runtime.clone(), main.clone()]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Undefined symbol: Sys.halt (referenced from Main)"
+        );
+
+        let sys = parse(&["function Sys.halt 0", "label LOOP", "goto LOOP"]).unwrap();
+        let sys = Object::assemble("Sys", &translate(&sys, Some("Sys")).unwrap()).unwrap();
+        let image = link(&[runtime, main, sys]).unwrap();
+        assert!(image
+            .symbols
+            .variables()
+            .all(|(name, _)| name != "Sys.halt"));
+    }
+
+    #[test]
+    fn test_extended_commands() {
+        let source = "function Main.main 0\npush constant 6\npush constant 7\nmul\npush constant 3\nmul\npush constant 4\ndiv\npush constant 5\nmod\nlte";
+        let vm_commands = parse(&source.lines().collect::<Vec<_>>()).unwrap();
+
+        // Every inlined routine gets its own labels and leaves no variables.
+        let inline = translate(&vm_commands, Some("Main")).unwrap();
+        let (_, symbols) = assembler::code::assemble_with_symbols(&inline).unwrap();
+        assert_eq!(symbols.variables().count(), 0);
+        assert!(symbols.get("Main.main$MUL_LOOP.0").is_some());
+        assert!(symbols.get("Main.main$MUL_LOOP.1").is_some());
+        assert!(symbols.get("Main.main$MOD_SIGN_X.3").is_some());
+
+        let mut shared = vec![];
+        translate_with(&vm_commands, Some("Main"), Mode::Shared, |command| {
+            shared.push(command);
+            Ok(())
+        })
+        .unwrap();
+        shared.extend(runtime());
+        let (_, symbols) = assembler::code::assemble_with_symbols(&shared).unwrap();
+        assert!(symbols
+            .variables()
+            .all(|(name, _)| !name.starts_with("VM$")));
+        for routine in ["VM$MUL", "VM$DIV", "VM$MOD", "VM$SHR", "VM$LTE"] {
+            assert!(symbols.get(routine).is_some());
+        }
+    }
+
+    #[test]
+    fn test_extended_arithmetic_runs() {
+        let values: [i16; 7] = [0, 1, -1, 32767, -32767, 16384, -16384];
+        let push = |value: i16| match value {
+            v if v < 0 => format!("push constant {}\nneg", -v),
+            v => format!("push constant {}", v),
+        };
+
+        let mut source = vec!["function Sys.init 0".to_string()];
+        let mut expected = vec![];
+        for x in values {
+            for (op, result) in [("shl", (x as u16) << 1), ("shr", (x as u16) >> 1)] {
+                let slot = expected.len();
+                source.extend([push(x), format!("{}\npop static {}", op, slot)]);
+                expected.push(result);
+            }
+            for y in values {
+                let mut results = vec![("mul", x.wrapping_mul(y))];
+                if y != 0 {
+                    results.push(("div", x.wrapping_div(y)));
+                    results.push(("mod", x.wrapping_rem(y)));
+                }
+                for (op, result) in results {
+                    let slot = expected.len();
+                    source.extend([push(x), push(y), format!("{}\npop static {}", op, slot)]);
+                    expected.push(result as u16);
+                }
+            }
+        }
+        source.push("label HALT\ngoto HALT".to_string());
+        let source = source.join("\n");
+
+        for mode in [Mode::Inline, Mode::Shared] {
+            let results = run_vm(&source, mode, expected.len() as u16);
+            assert_eq!(results[0], 261);
+            assert_eq!(&results[1..], &expected[..], "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let source = "push temp 8\npush pointer 1\npop constant 0\npush constant 32768\nlabel TOP\nfunction Main.f 0\nlabel L\nlabel L\ngoto M\nfunction Main.g 0\ngoto L\nfunction Main.f 0";
+        let lines = source.lines().collect::<Vec<_>>();
+        let vm_commands = parse(&lines).unwrap();
+        let errors = validate(&vm_commands)
+            .into_iter()
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        assert_eq!(errors, vec![0, 2, 3, 4, 7, 8, 10, 11]);
+
+        let err = translate(&vm_commands, Some("Main"))
+            .unwrap_err()
+            .to_string();
+        let mut err = err.lines();
+        assert_eq!(
+            err.next(),
+            Some("Main.vm:1:1: error: temp index out of range 0-7: 8")
+        );
+        assert_eq!(err.next(), Some("    push temp 8"));
+        assert!(err.any(|line| line == "Main.vm:9:1: error: Undefined label: M"));
+
+        let program = parse_located(&lines).unwrap();
+        let diagnostics = check(&program, &lines).unwrap_err();
+        assert_eq!(diagnostics[5].span, Span::new(9, 1, 6));
+        assert_eq!(diagnostics[5].message, "Undefined label: M");
+
+        let diagnostics = parse_located(&["push constant 1", "  bogus 1 // comment"]).unwrap_err();
+        assert_eq!(diagnostics[0].span, Span::new(2, 3, 7));
+
+        // Files without functions, like the book's BasicLoop, may use labels.
+        assert!(validate(&parse(&["label LOOP", "goto LOOP"]).unwrap()).is_empty());
+
+        let source = [
+            "function Main.f 32768",
+            "push local 32768",
+            "pop argument 40000",
+            "push that 32767",
+            "call Main.f 32762",
+            "call Main.f 32763",
+            "call Main.f 65535",
+            "push static 40000",
+        ];
+        let errors = validate(&parse(&source).unwrap());
+        assert_eq!(
+            errors,
+            vec![
+                (0, "local count out of range 0-32767: 32768".to_string()),
+                (1, "local index out of range 0-32767: 32768".to_string()),
+                (2, "argument index out of range 0-32767: 40000".to_string()),
+                (5, "argument count out of range 0-32762: 32763".to_string()),
+                (6, "argument count out of range 0-32762: 65535".to_string()),
+            ]
+        );
+        let err = translate(&parse(&source).unwrap(), Some("Main")).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Main.vm:7:1: error: argument count out of range 0-32762: 65535"));
+    }
+}
@@ -60,6 +60,21 @@ pub enum Command {
     And,
     Or,
     Not,
+    // Extensions to the book's instruction set, which all translate for the
+    // standard Hack CPU. `shl` and `shr` shift by one bit, `shr` shifting in
+    // a zero.
+    Mul,
+    Div,
+    Mod,
+    Shl,
+    Shr,
+    Lte,
+    Gte,
+    Neq,
+    Inc,
+    Dec,
+    Dup,
+    Swap,
     Function(String, u16),
     Call(String, u16),
     Return,
@@ -69,49 +84,54 @@ pub enum Command {
 }
 
 impl Command {
-    fn parse_segment_and_index(s: &str) -> anyhow::Result<(Segment, u16)> {
-        let (segment, s) = Segment::parse(s)?;
-
-        let s = s.trim();
-        let end = s.find(|c| c == ' ' || c == '\t').unwrap_or(s.len());
-        let (index, _) = s.split_at(end);
-        let index = index.parse::<u16>()?;
-        Ok((segment, index))
-    }
-
-    fn parse_label(s: &str) -> anyhow::Result<(String, &str)> {
-        let s = s.trim();
-        let end = s.find(|c| c == ' ' || c == '\t').unwrap_or(s.len());
-        let (label, s) = s.split_at(end);
-        Ok((label.to_string(), s))
+    fn parse_index(s: &str) -> anyhow::Result<u16> {
+        s.parse::<u16>()
+            .map_err(|_| anyhow::anyhow!("Invalid number: {}", s))
     }
 
-    fn parse_function_args(s: &str) -> anyhow::Result<(String, u16)> {
-        let s = s.trim();
-        let end = s
-            .find(|c| c == ' ' || c == '\t')
-            .ok_or(anyhow::anyhow!("No space found in {} 1", s))?;
-        let (name, s) = s.split_at(end);
-        let s = s.trim();
-        let end = s.find(|c| c == ' ' || c == '\t').unwrap_or(s.len());
-        let (arg_count, _) = s.split_at(end);
-        let arg_count = arg_count.parse::<u16>()?;
-        Ok((name.to_string(), arg_count))
+    /// Number of arguments `name` takes, or `None` for an unknown command.
+    fn arity(name: &str) -> Option<usize> {
+        match name {
+            "push" | "pop" | "function" | "call" => Some(2),
+            "label" | "goto" | "if-goto" => Some(1),
+            "add" | "sub" | "neg" | "eq" | "gt" | "lt" | "and" | "or" | "not" | "mul" | "div"
+            | "mod" | "shl" | "shr" | "lte" | "gte" | "neq" | "inc" | "dec" | "dup" | "swap"
+            | "return" => Some(0),
+            _ => None,
+        }
     }
 
+    /// Parses one command. A trailing `//` comment is ignored, but anything
+    /// else after the command's arguments is an error.
     pub fn parse(s: &str) -> anyhow::Result<Self> {
-        let s = s.trim();
-        let end = s.find(|c| c == ' ' || c == '\t').unwrap_or(s.len());
-        let (command, s) = s.split_at(end);
+        let code = s.split("//").next().unwrap_or("");
+        let tokens = code.split_whitespace().collect::<Vec<_>>();
+        let (&command, args) = tokens
+            .split_first()
+            .ok_or_else(|| anyhow::anyhow!("Missing command"))?;
+        let arity = Command::arity(command)
+            .ok_or_else(|| anyhow::anyhow!("Unknown command: {}", command))?;
+        if args.len() < arity {
+            anyhow::bail!(
+                "Missing arguments: {} takes {}, found {}",
+                command,
+                arity,
+                args.len()
+            );
+        }
+        if let Some(extra) = args.get(arity) {
+            anyhow::bail!("Unexpected token after {}: {}", command, extra);
+        }
+
         match command {
-            "push" => {
-                let (segment, index) = Command::parse_segment_and_index(s)?;
-                Ok(Command::Push(segment, index))
-            }
-            "pop" => {
-                let (segment, index) = Command::parse_segment_and_index(s)?;
-                Ok(Command::Pop(segment, index))
-            }
+            "push" => Ok(Command::Push(
+                Segment::parse(args[0])?.0,
+                Command::parse_index(args[1])?,
+            )),
+            "pop" => Ok(Command::Pop(
+                Segment::parse(args[0])?.0,
+                Command::parse_index(args[1])?,
+            )),
             "add" => Ok(Command::Add),
             "sub" => Ok(Command::Sub),
             "neg" => Ok(Command::Neg),
@@ -121,28 +141,31 @@ impl Command {
             "and" => Ok(Command::And),
             "or" => Ok(Command::Or),
             "not" => Ok(Command::Not),
-            "function" => {
-                let (name, arg_count) = Command::parse_function_args(s)?;
-                Ok(Command::Function(name, arg_count))
-            }
-            "call" => {
-                let (name, arg_count) = Command::parse_function_args(s)?;
-                Ok(Command::Call(name, arg_count))
-            }
+            "mul" => Ok(Command::Mul),
+            "div" => Ok(Command::Div),
+            "mod" => Ok(Command::Mod),
+            "shl" => Ok(Command::Shl),
+            "shr" => Ok(Command::Shr),
+            "lte" => Ok(Command::Lte),
+            "gte" => Ok(Command::Gte),
+            "neq" => Ok(Command::Neq),
+            "inc" => Ok(Command::Inc),
+            "dec" => Ok(Command::Dec),
+            "dup" => Ok(Command::Dup),
+            "swap" => Ok(Command::Swap),
+            "function" => Ok(Command::Function(
+                args[0].to_string(),
+                Command::parse_index(args[1])?,
+            )),
+            "call" => Ok(Command::Call(
+                args[0].to_string(),
+                Command::parse_index(args[1])?,
+            )),
             "return" => Ok(Command::Return),
-            "label" => {
-                let (s, _) = Command::parse_label(s)?;
-                Ok(Command::Label(s))
-            }
-            "goto" => {
-                let (s, _) = Command::parse_label(s)?;
-                Ok(Command::Goto(s))
-            }
-            "if-goto" => {
-                let (s, _) = Command::parse_label(s)?;
-                Ok(Command::IfGoto(s))
-            }
-            _ => Err(anyhow::anyhow!("Unknown command: {}", command)),
+            "label" => Ok(Command::Label(args[0].to_string())),
+            "goto" => Ok(Command::Goto(args[0].to_string())),
+            "if-goto" => Ok(Command::IfGoto(args[0].to_string())),
+            _ => unreachable!("every command with an arity is parsed"),
         }
     }
 
@@ -159,6 +182,18 @@ impl Command {
             Command::And => "and".to_string(),
             Command::Or => "or".to_string(),
             Command::Not => "not".to_string(),
+            Command::Mul => "mul".to_string(),
+            Command::Div => "div".to_string(),
+            Command::Mod => "mod".to_string(),
+            Command::Shl => "shl".to_string(),
+            Command::Shr => "shr".to_string(),
+            Command::Lte => "lte".to_string(),
+            Command::Gte => "gte".to_string(),
+            Command::Neq => "neq".to_string(),
+            Command::Inc => "inc".to_string(),
+            Command::Dec => "dec".to_string(),
+            Command::Dup => "dup".to_string(),
+            Command::Swap => "swap".to_string(),
             Command::Function(name, arg_count) => {
                 format!("function {} {}", name, arg_count)
             }
@@ -192,5 +227,27 @@ mod test {
 
         let command = Command::parse("add //foo").unwrap();
         assert_eq!(command, Command::Add);
+
+        for name in [
+            "mul", "div", "mod", "shl", "shr", "lte", "gte", "neq", "inc", "dec", "dup", "swap",
+        ] {
+            assert_eq!(Command::parse(name).unwrap().dump(), name);
+        }
+
+        let command = Command::parse("call Math.multiply 2//foo").unwrap();
+        assert_eq!(command, Command::Call("Math.multiply".to_string(), 2));
+
+        for bad in [
+            "push argument 1 garbage",
+            "push argument",
+            "add 1",
+            "label",
+            "goto A B",
+            "pop local -1",
+            "function Main.main x",
+            "",
+        ] {
+            assert!(Command::parse(bad).is_err(), "{}", bad);
+        }
     }
 }
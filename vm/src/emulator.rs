@@ -0,0 +1,473 @@
+use std::collections::HashMap;
+use std::ops::{Index, IndexMut};
+
+use super::command::{Command, Segment};
+
+const SP: usize = 0;
+const LCL: usize = 1;
+const ARG: usize = 2;
+const THIS: usize = 3;
+const THAT: usize = 4;
+const TEMP: usize = 5;
+const STATIC: u16 = 16;
+const RAM_SIZE: usize = 0x8000;
+
+const TRUE: u16 = 0xffff;
+const FALSE: u16 = 0;
+
+/// A command together with what it refers to, resolved when loading.
+#[derive(Debug, Clone)]
+struct Instruction {
+    command: Command,
+    /// Index of the file the command came from, for the static segment.
+    file: usize,
+    /// Destination of `goto`, `if-goto` and `call`.
+    target: usize,
+}
+
+/// RAM and the stack operations on it.
+#[derive(Debug, Clone)]
+struct Memory(Vec<u16>);
+
+impl Index<usize> for Memory {
+    type Output = u16;
+
+    fn index(&self, address: usize) -> &u16 {
+        &self.0[address]
+    }
+}
+
+impl IndexMut<usize> for Memory {
+    fn index_mut(&mut self, address: usize) -> &mut u16 {
+        &mut self.0[address]
+    }
+}
+
+impl Memory {
+    fn read(&self, address: u16) -> anyhow::Result<u16> {
+        self.0
+            .get(address as usize)
+            .copied()
+            .ok_or_else(|| anyhow::anyhow!("Address out of range: {}", address))
+    }
+
+    fn write(&mut self, address: u16, value: u16) -> anyhow::Result<()> {
+        match self.0.get_mut(address as usize) {
+            Some(word) => {
+                *word = value;
+                Ok(())
+            }
+            None => anyhow::bail!("Address out of range: {}", address),
+        }
+    }
+
+    fn push(&mut self, value: u16) -> anyhow::Result<()> {
+        let sp = self[SP];
+        self.write(sp, value)?;
+        self[SP] = sp.wrapping_add(1);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> anyhow::Result<u16> {
+        let sp = self[SP].wrapping_sub(1);
+        self[SP] = sp;
+        self.read(sp)
+    }
+
+    fn address(
+        &self,
+        segment: Segment,
+        index: u16,
+        file: usize,
+        statics: &HashMap<(usize, u16), u16>,
+    ) -> anyhow::Result<u16> {
+        let base = |register: usize| self[register].wrapping_add(index);
+        match segment {
+            Segment::Local => Ok(base(LCL)),
+            Segment::Argument => Ok(base(ARG)),
+            Segment::This => Ok(base(THIS)),
+            Segment::That => Ok(base(THAT)),
+            Segment::Pointer if index < 2 => Ok(THIS as u16 + index),
+            Segment::Temp if index < 8 => Ok(TEMP as u16 + index),
+            Segment::Static => Ok(statics[&(file, index)]),
+            Segment::Constant => anyhow::bail!("Cannot pop to constant"),
+            _ => anyhow::bail!("Index out of range: {} {}", segment, index),
+        }
+    }
+
+    fn binary(&mut self, f: impl Fn(u16, u16) -> u16) -> anyhow::Result<()> {
+        let y = self.pop()?;
+        let x = self.pop()?;
+        self.push(f(x, y))
+    }
+
+    fn unary(&mut self, f: impl Fn(u16) -> u16) -> anyhow::Result<()> {
+        let x = self.pop()?;
+        self.push(f(x))
+    }
+}
+
+/// Runs VM commands directly, without translating them to Hack.
+///
+/// Memory uses the standard layout: SP, LCL, ARG, THIS and THAT in RAM[0..5],
+/// temp in RAM[5..13] and statics from RAM[16]. Statics are numbered in the
+/// order they appear in the loaded files, so their addresses can differ from
+/// the translated program's, where the assembler also places its own
+/// variables. Return addresses saved in call frames are command indices
+/// rather than ROM addresses.
+#[derive(Debug, Clone)]
+pub struct Emulator {
+    program: Vec<Instruction>,
+    functions: HashMap<String, usize>,
+    statics: HashMap<(usize, u16), u16>,
+    ram: Memory,
+    pc: usize,
+    steps: usize,
+}
+
+impl Emulator {
+    /// Loads several files, each given as a name and its commands.
+    pub fn new(files: &[(&str, &[Command])]) -> anyhow::Result<Self> {
+        let mut program = Vec::new();
+        let mut functions = HashMap::new();
+        let mut labels = HashMap::new();
+        let mut statics = HashMap::new();
+        for (file, (_, commands)) in files.iter().enumerate() {
+            let mut function = String::new();
+            for command in commands.iter() {
+                match command {
+                    Command::Function(name, _) => {
+                        if functions.insert(name.clone(), program.len()).is_some() {
+                            anyhow::bail!("Duplicate function: {}", name);
+                        }
+                        function = name.clone();
+                    }
+                    Command::Label(label) => {
+                        let label = format!("{}${}", function, label);
+                        if labels.insert(label.clone(), program.len()).is_some() {
+                            anyhow::bail!("Duplicate label: {}", label);
+                        }
+                    }
+                    Command::Push(Segment::Static, index)
+                    | Command::Pop(Segment::Static, index) => {
+                        let next = STATIC + statics.len() as u16;
+                        statics.entry((file, *index)).or_insert(next);
+                    }
+                    _ => {}
+                }
+                program.push(Instruction {
+                    command: command.clone(),
+                    file,
+                    target: 0,
+                });
+            }
+        }
+
+        let mut function = String::new();
+        for instruction in program.iter_mut() {
+            instruction.target = match &instruction.command {
+                Command::Function(name, _) => {
+                    function = name.clone();
+                    continue;
+                }
+                Command::Goto(label) | Command::IfGoto(label) => {
+                    let label = format!("{}${}", function, label);
+                    *labels
+                        .get(&label)
+                        .ok_or_else(|| anyhow::anyhow!("Undefined label: {}", label))?
+                }
+                Command::Call(name, _) => *functions
+                    .get(name)
+                    .ok_or_else(|| anyhow::anyhow!("Undefined function: {}", name))?,
+                _ => continue,
+            };
+        }
+
+        Ok(Self {
+            program,
+            functions,
+            statics,
+            ram: Memory(vec![0; RAM_SIZE]),
+            pc: 0,
+            steps: 0,
+        })
+    }
+
+    /// Sets SP to 256 and jumps to `Sys.init`, like `code::bootstrap(256)`.
+    pub fn bootstrap(&mut self) -> anyhow::Result<()> {
+        self.pc = *self
+            .functions
+            .get("Sys.init")
+            .ok_or_else(|| anyhow::anyhow!("Undefined function: Sys.init"))?;
+        self.ram[SP] = 256;
+        Ok(())
+    }
+
+    pub fn ram(&self) -> &[u16] {
+        &self.ram.0
+    }
+
+    pub fn ram_mut(&mut self) -> &mut [u16] {
+        &mut self.ram.0
+    }
+
+    /// Index of the next command to run.
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// Number of commands run so far.
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    /// The command `pc` points at, if any.
+    pub fn current(&self) -> Option<&Command> {
+        self.program
+            .get(self.pc)
+            .map(|instruction| &instruction.command)
+    }
+
+    /// Address of a static variable, once a command in `file` has used it.
+    pub fn static_address(&self, file: usize, index: u16) -> Option<u16> {
+        self.statics.get(&(file, index)).copied()
+    }
+
+    /// Whether execution ran off the end of the program or is stuck in a
+    /// `goto` to itself, the usual way to end a VM program.
+    pub fn is_halted(&self) -> bool {
+        match self.program.get(self.pc) {
+            None => true,
+            Some(instruction) => match instruction.command {
+                Command::Goto(_) if instruction.target <= self.pc => self.program
+                    [instruction.target..self.pc]
+                    .iter()
+                    .all(|i| matches!(i.command, Command::Label(_))),
+                _ => false,
+            },
+        }
+    }
+
+    /// Runs one command.
+    pub fn step(&mut self) -> anyhow::Result<()> {
+        let instruction = self
+            .program
+            .get(self.pc)
+            .ok_or_else(|| anyhow::anyhow!("No command at {}", self.pc))?;
+        let ram = &mut self.ram;
+        let compare = |f: fn(i16, i16) -> bool| {
+            move |x: u16, y: u16| if f(x as i16, y as i16) { TRUE } else { FALSE }
+        };
+
+        let mut next = self.pc + 1;
+        match instruction.command {
+            Command::Push(Segment::Constant, value) => ram.push(value)?,
+            Command::Push(segment, index) => {
+                let address = ram.address(segment, index, instruction.file, &self.statics)?;
+                let value = ram.read(address)?;
+                ram.push(value)?;
+            }
+            Command::Pop(segment, index) => {
+                let address = ram.address(segment, index, instruction.file, &self.statics)?;
+                let value = ram.pop()?;
+                ram.write(address, value)?;
+            }
+            Command::Add => ram.binary(|x, y| x.wrapping_add(y))?,
+            Command::Sub => ram.binary(|x, y| x.wrapping_sub(y))?,
+            Command::Neg => ram.unary(|x| x.wrapping_neg())?,
+            Command::Eq => ram.binary(compare(|x, y| x == y))?,
+            Command::Gt => ram.binary(compare(|x, y| x > y))?,
+            Command::Lt => ram.binary(compare(|x, y| x < y))?,
+            Command::And => ram.binary(|x, y| x & y)?,
+            Command::Or => ram.binary(|x, y| x | y)?,
+            Command::Not => ram.unary(|x| !x)?,
+            Command::Mul => ram.binary(|x, y| (x as i16).wrapping_mul(y as i16) as u16)?,
+            Command::Div | Command::Mod => {
+                let y = ram.pop()? as i16;
+                let x = ram.pop()? as i16;
+                if y == 0 {
+                    anyhow::bail!("Division by zero");
+                }
+                let value = if instruction.command == Command::Div {
+                    x.wrapping_div(y)
+                } else {
+                    x.wrapping_rem(y)
+                };
+                ram.push(value as u16)?;
+            }
+            Command::Shl => ram.unary(|x| x << 1)?,
+            Command::Shr => ram.unary(|x| x >> 1)?,
+            Command::Lte => ram.binary(compare(|x, y| x <= y))?,
+            Command::Gte => ram.binary(compare(|x, y| x >= y))?,
+            Command::Neq => ram.binary(compare(|x, y| x != y))?,
+            Command::Inc => ram.unary(|x| x.wrapping_add(1))?,
+            Command::Dec => ram.unary(|x| x.wrapping_sub(1))?,
+            Command::Dup => {
+                let x = ram.pop()?;
+                ram.push(x)?;
+                ram.push(x)?;
+            }
+            Command::Swap => {
+                let y = ram.pop()?;
+                let x = ram.pop()?;
+                ram.push(y)?;
+                ram.push(x)?;
+            }
+            Command::Label(_) => {}
+            Command::Goto(_) => next = instruction.target,
+            Command::IfGoto(_) => {
+                if ram.pop()? != 0 {
+                    next = instruction.target;
+                }
+            }
+            Command::Function(_, locals) => {
+                for _ in 0..locals {
+                    ram.push(0)?;
+                }
+            }
+            Command::Call(_, args) => {
+                ram.push(next as u16)?;
+                for register in [LCL, ARG, THIS, THAT] {
+                    ram.push(ram[register])?;
+                }
+                let sp = ram[SP];
+                ram[ARG] = sp.wrapping_sub(args + 5);
+                ram[LCL] = sp;
+                next = instruction.target;
+            }
+            Command::Return => {
+                let frame = ram[LCL];
+                let return_address = ram.read(frame.wrapping_sub(5))?;
+                let value = ram.pop()?;
+                let arg = ram[ARG];
+                ram.write(arg, value)?;
+                ram[SP] = arg.wrapping_add(1);
+                for (offset, register) in [THAT, THIS, ARG, LCL].into_iter().enumerate() {
+                    ram[register] = ram.read(frame.wrapping_sub(offset as u16 + 1))?;
+                }
+                next = return_address as usize;
+            }
+        }
+        self.pc = next;
+        self.steps += 1;
+        Ok(())
+    }
+
+    /// Steps until the program halts or `max_steps` commands have run, and
+    /// returns the number of commands run.
+    pub fn run(&mut self, max_steps: usize) -> anyhow::Result<usize> {
+        let start = self.steps;
+        while !self.is_halted() && self.steps - start < max_steps {
+            self.step()?;
+        }
+        Ok(self.steps - start)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::code::parse;
+
+    fn load(files: &[(&str, &str)]) -> Emulator {
+        let commands = files
+            .iter()
+            .map(|(_, source)| parse(&source.lines().collect::<Vec<_>>()).unwrap())
+            .collect::<Vec<_>>();
+        let files = files
+            .iter()
+            .zip(commands.iter())
+            .map(|((name, _), commands)| (*name, commands.as_slice()))
+            .collect::<Vec<_>>();
+        Emulator::new(&files).unwrap()
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let mut emulator = load(&[(
+            "Main",
+            "push constant 17\npush constant 17\neq\npush constant 3\npush constant 5\ngt\npush constant 32767\nneg\npush constant 1\nsub\npush constant 3\nlt\npush constant 57\npush constant 31\nand\nnot",
+        )]);
+        emulator.ram_mut()[SP] = 256;
+        assert_eq!(emulator.run(100).unwrap(), 16);
+        assert!(emulator.is_halted());
+        assert_eq!(emulator.ram()[SP], 260);
+        assert_eq!(&emulator.ram()[256..260], &[TRUE, FALSE, TRUE, !25]);
+    }
+
+    #[test]
+    fn test_extended_arithmetic() {
+        let mut emulator = load(&[(
+            "Main",
+            "push constant 300\npush constant 300\nmul\npush constant 7\nneg\npush constant 2\ndiv\npush constant 7\nneg\npush constant 2\nmod\npush constant 3\npush constant 4\nlte\npush constant 5\nswap\ndup\ninc\npush constant 32767\nshl\nshr",
+        )]);
+        emulator.ram_mut()[SP] = 256;
+        emulator.run(100).unwrap();
+        assert_eq!(
+            &emulator.ram()[256..emulator.ram()[SP] as usize],
+            &[
+                90000u32 as u16,
+                -3i16 as u16,
+                -1i16 as u16,
+                5,
+                TRUE,
+                0,
+                0x7fff
+            ]
+        );
+
+        let mut emulator = load(&[("Main", "push constant 1\npush constant 0\ndiv")]);
+        emulator.ram_mut()[SP] = 256;
+        assert_eq!(emulator.run(3).unwrap_err().to_string(), "Division by zero");
+    }
+
+    #[test]
+    fn test_segments_and_statics() {
+        let mut emulator = load(&[
+            ("A", "push constant 5\npop static 3\npush constant 6\npop pointer 1\npush constant 7\npop that 2\npush static 3"),
+            ("B", "push constant 9\npop static 3\npush constant 1\npop temp 7"),
+        ]);
+        emulator.ram_mut()[SP] = 256;
+        emulator.run(100).unwrap();
+        assert_eq!(emulator.static_address(0, 3), Some(16));
+        assert_eq!(emulator.static_address(1, 3), Some(17));
+        assert_eq!(&emulator.ram()[16..18], &[5, 9]);
+        assert_eq!(emulator.ram()[THAT], 6);
+        assert_eq!(emulator.ram()[8], 7);
+        assert_eq!(emulator.ram()[12], 1);
+        assert_eq!(emulator.ram()[256], 5);
+        assert_eq!(emulator.ram()[SP], 257);
+    }
+
+    #[test]
+    fn test_call_and_return() {
+        let sys =
+            "function Sys.init 0\npush constant 12\ncall Main.fibonacci 1\nlabel WHILE\ngoto WHILE";
+        let main = "function Main.fibonacci 0\npush argument 0\npush constant 2\nlt\nif-goto IF_TRUE\ngoto IF_FALSE\nlabel IF_TRUE\npush argument 0\nreturn\nlabel IF_FALSE\npush argument 0\npush constant 2\nsub\ncall Main.fibonacci 1\npush argument 0\npush constant 1\nsub\ncall Main.fibonacci 1\nadd\nreturn";
+        let mut emulator = load(&[("Main", main), ("Sys", sys)]);
+        emulator.bootstrap().unwrap();
+        emulator.ram_mut()[LCL..=THAT].copy_from_slice(&[300, 400, 3000, 4000]);
+        emulator.run(100_000).unwrap();
+        assert!(emulator.is_halted());
+        assert_eq!(emulator.ram()[256], 144);
+        assert_eq!(&emulator.ram()[SP..=THAT], &[257, 300, 400, 3000, 4000]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let parse_lines = |s: &str| parse(&s.lines().collect::<Vec<_>>()).unwrap();
+        let err = Emulator::new(&[("Main", &parse_lines("function Main.main 0\ngoto END"))]);
+        assert_eq!(
+            err.unwrap_err().to_string(),
+            "Undefined label: Main.main$END"
+        );
+        assert!(Emulator::new(&[("Main", &parse_lines("call Sys.init 0"))]).is_err());
+
+        let mut emulator = Emulator::new(&[("Main", &parse_lines("push temp 8"))]).unwrap();
+        assert!(emulator.step().is_err());
+        let mut emulator =
+            Emulator::new(&[("Main", &parse_lines("push constant 1\npop constant 0"))]).unwrap();
+        emulator.step().unwrap();
+        assert!(emulator.step().is_err());
+    }
+}
@@ -1,2 +1,7 @@
 pub mod command;
-pub mod code;
\ No newline at end of file
+pub mod code;
+pub mod emulator;
+pub mod optimizer;
+pub mod parser;
+pub mod program;
+pub mod source_map;
\ No newline at end of file
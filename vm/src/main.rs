@@ -0,0 +1,135 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+
+use clap::Parser;
+
+use assembler::assembler::Assembler;
+use assembler::format::Format;
+use assembler::pretty::format_program;
+use vm::code::Mode;
+use vm::program::{load, translate_program_mapped, Options};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    Asm,
+    Image(Format),
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "asm" => Ok(Self::Asm),
+            _ => Ok(Self::Image(s.parse()?)),
+        }
+    }
+}
+
+impl OutputFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Asm => "asm",
+            Self::Image(format) => format.extension(),
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+#[clap(version, about, long_about=None)]
+struct Args {
+    /// A .vm file, or a directory whose .vm files form one program
+    input: PathBuf,
+
+    /// Output file; defaults to X.asm for X.vm and D/D.asm for a directory D
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+
+    /// Output format: asm, or an image format such as hack, bin or ihex
+    #[clap(short, long, default_value = "asm")]
+    format: OutputFormat,
+
+    /// Leave out the bootstrap, which is otherwise emitted when the program
+    /// defines Sys.init
+    #[clap(long)]
+    no_bootstrap: bool,
+
+    /// Translate call, return and comparisons `inline`, or as jumps into
+    /// `shared` routines for smaller programs
+    #[clap(long, default_value = "inline")]
+    mode: Mode,
+
+    /// Optimize the VM commands before translating them
+    #[clap(long)]
+    optimize: bool,
+
+    /// Also write a map from ROM addresses back to VM commands next to the
+    /// output as .map
+    #[clap(long)]
+    source_map: bool,
+}
+
+fn output_path(args: &Args) -> PathBuf {
+    if let Some(output) = &args.output {
+        return output.clone();
+    }
+    let extension = args.format.extension();
+    if args.input.is_dir() {
+        let name = args.input.file_name().unwrap_or_default();
+        args.input.join(name).with_extension(extension)
+    } else {
+        args.input.with_extension(extension)
+    }
+}
+
+fn run(args: &Args) -> anyhow::Result<()> {
+    let sources = load(&args.input)?;
+    let defines_sys_init = sources.iter().any(|source| {
+        source
+            .lines
+            .iter()
+            .any(|line| line.split_whitespace().take(2).eq(["function", "Sys.init"]))
+    });
+    let options = Options {
+        bootstrap: defines_sys_init && !args.no_bootstrap,
+        mode: args.mode,
+        optimize: args.optimize,
+    };
+
+    let (program, source_map) = match translate_program_mapped(&sources, options) {
+        Ok(translated) => translated,
+        Err(errors) => {
+            for diagnostics in errors.iter() {
+                eprintln!("{}", diagnostics);
+            }
+            process::exit(1);
+        }
+    };
+
+    let bytes = match args.format {
+        OutputFormat::Asm => format_program(&program).into_bytes(),
+        OutputFormat::Image(format) => {
+            let mut assembler = Assembler::new();
+            for command in program {
+                assembler.push_command(command)?;
+            }
+            assembler.finish()?.write(format)
+        }
+    };
+    let path = output_path(args);
+    fs::write(&path, bytes)?;
+    if args.source_map {
+        fs::write(path.with_extension("map"), source_map.dump())?;
+    }
+    Ok(())
+}
+
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(&args) {
+        eprintln!("error: {}", e);
+        process::exit(2);
+    }
+}
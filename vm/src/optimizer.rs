@@ -0,0 +1,644 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use super::command::{Command, Segment};
+
+type Pass = fn(&mut Vec<Command>) -> usize;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    pub commands_before: usize,
+    pub commands_after: usize,
+    pub passes: Vec<(&'static str, usize)>,
+}
+
+impl Report {
+    pub fn saved(&self) -> usize {
+        self.commands_before - self.commands_after
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} -> {} commands ({} saved)",
+            self.commands_before,
+            self.commands_after,
+            self.saved()
+        )?;
+        for (name, count) in self.passes.iter() {
+            write!(f, "\n    {:<20}{}", name, count)?;
+        }
+        Ok(())
+    }
+}
+
+fn remove(commands: &mut Vec<Command>, removed: &HashSet<usize>) -> usize {
+    let mut i = 0;
+    commands.retain(|_| {
+        i += 1;
+        !removed.contains(&(i - 1))
+    });
+    removed.len()
+}
+
+/// Numbers each command by the function it belongs to, so that labels can be
+/// keyed by `(scope, name)`. Commands before the first function are scope 0.
+fn scopes(commands: &[Command]) -> Vec<usize> {
+    let mut scope = 0;
+    commands
+        .iter()
+        .map(|command| {
+            if let Command::Function(..) = command {
+                scope += 1;
+            }
+            scope
+        })
+        .collect()
+}
+
+/// The value pushed by `push constant c`, optionally followed by `neg` or
+/// `not`, starting at `commands[i]`, and the number of commands it takes.
+fn constant_at(commands: &[Command], i: usize) -> Option<(i16, usize)> {
+    let value = match commands.get(i)? {
+        Command::Push(Segment::Constant, value) => *value as i16,
+        _ => return None,
+    };
+    match commands.get(i + 1) {
+        Some(Command::Neg) => Some((value.wrapping_neg(), 2)),
+        Some(Command::Not) => Some((!value, 2)),
+        _ => Some((value, 1)),
+    }
+}
+
+/// The shortest commands that push `value`.
+fn push_constant(value: i16) -> Vec<Command> {
+    if value >= 0 {
+        vec![Command::Push(Segment::Constant, value as u16)]
+    } else {
+        vec![
+            Command::Push(Segment::Constant, !value as u16),
+            Command::Not,
+        ]
+    }
+}
+
+fn fold_unary(command: &Command, x: i16) -> Option<i16> {
+    match command {
+        Command::Neg => Some(x.wrapping_neg()),
+        Command::Not => Some(!x),
+        Command::Inc => Some(x.wrapping_add(1)),
+        Command::Dec => Some(x.wrapping_sub(1)),
+        Command::Shl => Some(x << 1),
+        Command::Shr => Some(((x as u16) >> 1) as i16),
+        _ => None,
+    }
+}
+
+fn fold_binary(command: &Command, x: i16, y: i16) -> Option<i16> {
+    let boolean = |b: bool| if b { -1 } else { 0 };
+    // The translated comparisons look at the sign of `x - y`, so leave the
+    // ones that overflow to run as written.
+    let compare = |f: fn(i16, i16) -> bool| x.checked_sub(y).map(|_| boolean(f(x, y)));
+    match command {
+        Command::Add => Some(x.wrapping_add(y)),
+        Command::Sub => Some(x.wrapping_sub(y)),
+        Command::And => Some(x & y),
+        Command::Or => Some(x | y),
+        Command::Mul => Some(x.wrapping_mul(y)),
+        Command::Div if y != 0 => Some(x.wrapping_div(y)),
+        Command::Mod if y != 0 => Some(x.wrapping_rem(y)),
+        Command::Eq => Some(boolean(x == y)),
+        Command::Neq => Some(boolean(x != y)),
+        Command::Gt => compare(|x, y| x > y),
+        Command::Lt => compare(|x, y| x < y),
+        Command::Gte => compare(|x, y| x >= y),
+        Command::Lte => compare(|x, y| x <= y),
+        _ => None,
+    }
+}
+
+/// Evaluates arithmetic on constants, and drops `not; not` and `neg; neg`.
+fn constant_folding(commands: &mut Vec<Command>) -> usize {
+    let mut count = 0;
+    let mut result = Vec::with_capacity(commands.len());
+    let mut i = 0;
+    while i < commands.len() {
+        let folded = constant_at(commands, i).and_then(|(x, x_len)| {
+            if let Some(value) = commands
+                .get(i + x_len)
+                .and_then(|command| fold_unary(command, x))
+            {
+                return Some((value, x_len + 1));
+            }
+            let (y, y_len) = constant_at(commands, i + x_len)?;
+            let value = fold_binary(commands.get(i + x_len + y_len)?, x, y)?;
+            Some((value, x_len + y_len + 1))
+        });
+        match folded {
+            Some((value, len)) if push_constant(value).len() < len => {
+                result.extend(push_constant(value));
+                i += len;
+                count += 1;
+            }
+            _ if matches!(
+                (&commands[i], commands.get(i + 1)),
+                (Command::Not, Some(Command::Not)) | (Command::Neg, Some(Command::Neg))
+            ) =>
+            {
+                i += 2;
+                count += 1;
+            }
+            _ => {
+                result.push(commands[i].clone());
+                i += 1;
+            }
+        }
+    }
+    *commands = result;
+    count
+}
+
+/// Drops `push x; pop x`, which stores a value back where it came from.
+fn push_pop_pairs(commands: &mut Vec<Command>) -> usize {
+    let mut removed = HashSet::new();
+    for i in 0..commands.len().saturating_sub(1) {
+        if let (Command::Push(segment, index), Command::Pop(to_segment, to_index)) =
+            (&commands[i], &commands[i + 1])
+        {
+            if segment == to_segment && index == to_index {
+                removed.extend([i, i + 1]);
+            }
+        }
+    }
+    remove(commands, &removed)
+}
+
+/// Turns `if-goto` on a constant into `goto` or nothing, drops a `goto` to
+/// the label right after it, and points jumps at a label whose only command
+/// is `goto L` straight at `L`.
+fn branches(commands: &mut Vec<Command>) -> usize {
+    let scopes = scopes(commands);
+    let mut forwards = HashMap::new();
+    for (i, command) in commands.iter().enumerate() {
+        if let Command::Label(label) = command {
+            let next = commands[i + 1..]
+                .iter()
+                .find(|c| !matches!(c, Command::Label(_)));
+            if let Some(Command::Goto(target)) = next {
+                forwards.insert((scopes[i], label.clone()), target.clone());
+            }
+        }
+    }
+    let resolve = |scope: usize, label: &String| {
+        let mut seen = HashSet::new();
+        let mut label = label;
+        while let Some(target) = forwards.get(&(scope, label.clone())) {
+            if !seen.insert(label) {
+                break;
+            }
+            label = target;
+        }
+        label.clone()
+    };
+
+    let mut count = 0;
+    let mut result = Vec::with_capacity(commands.len());
+    let mut i = 0;
+    while i < commands.len() {
+        if let Some((value, len)) = constant_at(commands, i) {
+            if let Some(Command::IfGoto(label)) = commands.get(i + len) {
+                if value != 0 {
+                    result.push(Command::Goto(label.clone()));
+                }
+                i += len + 1;
+                count += 1;
+                continue;
+            }
+        }
+
+        match &commands[i] {
+            Command::Goto(label)
+                if commands[i + 1..]
+                    .iter()
+                    .take_while(|c| matches!(c, Command::Label(_)))
+                    .any(|c| *c == Command::Label(label.clone())) =>
+            {
+                count += 1;
+            }
+            Command::Goto(label) | Command::IfGoto(label) => {
+                let target = resolve(scopes[i], label);
+                if target != *label {
+                    count += 1;
+                }
+                result.push(match commands[i] {
+                    Command::Goto(_) => Command::Goto(target),
+                    _ => Command::IfGoto(target),
+                });
+            }
+            command => result.push(command.clone()),
+        }
+        i += 1;
+    }
+    *commands = result;
+    count
+}
+
+/// How many values `command` pops and pushes.
+fn stack_effect(command: &Command) -> (usize, usize) {
+    match command {
+        Command::Push(..) => (0, 1),
+        Command::Pop(..) | Command::IfGoto(_) => (1, 0),
+        Command::Neg | Command::Not | Command::Shl | Command::Shr | Command::Inc | Command::Dec => {
+            (1, 1)
+        }
+        Command::Add
+        | Command::Sub
+        | Command::Eq
+        | Command::Gt
+        | Command::Lt
+        | Command::And
+        | Command::Or
+        | Command::Mul
+        | Command::Div
+        | Command::Mod
+        | Command::Lte
+        | Command::Gte
+        | Command::Neq => (2, 1),
+        Command::Dup => (1, 2),
+        Command::Swap => (2, 2),
+        Command::Call(_, args) => (*args as usize, 1),
+        Command::Function(..) | Command::Return | Command::Label(_) | Command::Goto(_) => (0, 0),
+    }
+}
+
+/// Where the straight-line code that computes the value on top of the stack
+/// after `commands` starts, if it all lies within `commands`.
+fn operand_start(commands: &[Command]) -> Option<usize> {
+    let mut needed = 1;
+    for (i, command) in commands.iter().enumerate().rev() {
+        let (pops, pushes) = stack_effect(command);
+        if pushes > needed {
+            return None;
+        }
+        needed = needed - pushes + pops;
+        if needed == 0 {
+            return Some(i);
+        }
+    }
+    None
+}
+
+/// Whether the value on top of the stack after `commands` is always 0 or -1:
+/// a comparison, `false` or `true`, or `not`, `and` and `or` of those.
+fn is_boolean(commands: &[Command]) -> bool {
+    match commands.split_last() {
+        Some((
+            Command::Eq | Command::Gt | Command::Lt | Command::Lte | Command::Gte | Command::Neq,
+            _,
+        )) => true,
+        Some((Command::Push(Segment::Constant, 0), _)) => true,
+        Some((Command::Not, operand)) => is_boolean(operand),
+        Some((Command::And | Command::Or, operands)) => match operand_start(operands) {
+            Some(start) => is_boolean(&operands[..start]) && is_boolean(&operands[start..]),
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+/// Rewrites the `while` loops the compiler emits,
+///
+/// ```text
+/// label S; <condition>; not; if-goto E; <body>; goto S; label E
+/// ```
+///
+/// into `goto S; label S_BODY; <body>; label S; <condition>; if-goto S_BODY;
+/// label E`, which tests the condition without `not` and runs one jump fewer
+/// per iteration. The condition must be straight-line code whose value is
+/// always 0 or -1, since `not` maps any other nonzero value to nonzero too,
+/// and `S` must be the target of that one `goto` only.
+fn loop_rotation(commands: &mut Vec<Command>) -> usize {
+    let scopes = scopes(commands);
+    let mut labels = HashSet::new();
+    let mut references = HashMap::new();
+    for (command, scope) in commands.iter().zip(scopes.iter()) {
+        match command {
+            Command::Label(label) => {
+                labels.insert((*scope, label.clone()));
+            }
+            Command::Goto(label) | Command::IfGoto(label) => {
+                *references.entry((*scope, label.clone())).or_insert(0) += 1;
+            }
+            _ => {}
+        }
+    }
+
+    for i in 0..commands.len() {
+        let start = match &commands[i] {
+            Command::Label(label) => label.clone(),
+            _ => continue,
+        };
+        let scope = scopes[i];
+        if references.get(&(scope, start.clone())) != Some(&1) {
+            continue;
+        }
+        let body_label = format!("{}_BODY", start);
+        if labels.contains(&(scope, body_label.clone())) {
+            continue;
+        }
+
+        let condition_end = match commands[i + 1..].iter().position(|command| {
+            matches!(
+                command,
+                Command::Label(_)
+                    | Command::Goto(_)
+                    | Command::IfGoto(_)
+                    | Command::Function(..)
+                    | Command::Return
+            )
+        }) {
+            Some(offset) => i + 1 + offset,
+            None => continue,
+        };
+        let end = match (
+            commands.get(condition_end.wrapping_sub(1)),
+            commands.get(condition_end),
+        ) {
+            (Some(Command::Not), Some(Command::IfGoto(end)))
+                if is_boolean(&commands[i + 1..condition_end - 1]) =>
+            {
+                end.clone()
+            }
+            _ => continue,
+        };
+        let back_edge = commands[condition_end + 1..]
+            .windows(2)
+            .position(|pair| {
+                pair[0] == Command::Goto(start.clone()) && pair[1] == Command::Label(end.clone())
+            })
+            .map(|offset| condition_end + 1 + offset);
+        let back_edge = match back_edge {
+            Some(back_edge) if scopes[back_edge] == scope => back_edge,
+            _ => continue,
+        };
+
+        let mut rotated = vec![
+            Command::Goto(start.clone()),
+            Command::Label(body_label.clone()),
+        ];
+        rotated.extend_from_slice(&commands[condition_end + 1..back_edge]);
+        rotated.push(Command::Label(start));
+        rotated.extend_from_slice(&commands[i + 1..condition_end - 1]);
+        rotated.push(Command::IfGoto(body_label));
+        commands.splice(i..back_edge + 1, rotated);
+        return 1;
+    }
+    0
+}
+
+/// Drops everything between a `goto` or `return` and the next label or
+/// function.
+fn dead_code(commands: &mut Vec<Command>) -> usize {
+    let mut removed = HashSet::new();
+    let mut reachable = true;
+    for (i, command) in commands.iter().enumerate() {
+        match command {
+            Command::Label(_) | Command::Function(..) => reachable = true,
+            _ if !reachable => {
+                removed.insert(i);
+            }
+            Command::Goto(_) | Command::Return => reachable = false,
+            _ => {}
+        }
+    }
+    remove(commands, &removed)
+}
+
+/// Drops labels that nothing in their function jumps to.
+fn unused_labels(commands: &mut Vec<Command>) -> usize {
+    let scopes = scopes(commands);
+    let referenced = commands
+        .iter()
+        .zip(scopes.iter())
+        .filter_map(|(command, scope)| match command {
+            Command::Goto(label) | Command::IfGoto(label) => Some((*scope, label.as_str())),
+            _ => None,
+        })
+        .collect::<HashSet<_>>();
+    let removed = commands
+        .iter()
+        .enumerate()
+        .filter(|(i, command)| match command {
+            Command::Label(label) => !referenced.contains(&(scopes[*i], label.as_str())),
+            _ => false,
+        })
+        .map(|(i, _)| i)
+        .collect::<HashSet<_>>();
+    remove(commands, &removed)
+}
+
+/// Drops the functions that `Sys.init` can never call, and returns the
+/// number of commands removed. Programs without `Sys.init` are left alone,
+/// since any of their functions may be an entry point.
+fn unused_functions(files: &mut [Vec<Command>]) -> usize {
+    let mut calls: HashMap<&str, Vec<&str>> = HashMap::new();
+    for commands in files.iter() {
+        let mut current = None;
+        for command in commands.iter() {
+            match command {
+                Command::Function(name, _) => {
+                    calls.entry(name).or_default();
+                    current = Some(name.as_str());
+                }
+                Command::Call(name, _) => {
+                    if let Some(function) = current {
+                        calls.entry(function).or_default().push(name);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    if !calls.contains_key("Sys.init") {
+        return 0;
+    }
+
+    let mut used = HashSet::new();
+    let mut pending = vec!["Sys.init"];
+    while let Some(name) = pending.pop() {
+        if used.insert(name) {
+            pending.extend(calls.get(name).into_iter().flatten());
+        }
+    }
+    let used = used.into_iter().map(String::from).collect::<HashSet<_>>();
+
+    let mut count = 0;
+    for commands in files.iter_mut() {
+        let mut keep = true;
+        let before = commands.len();
+        commands.retain(|command| {
+            if let Command::Function(name, _) = command {
+                keep = used.contains(name);
+            }
+            keep
+        });
+        count += before - commands.len();
+    }
+    count
+}
+
+const PASSES: [(&str, Pass); 6] = [
+    ("constant folding", constant_folding),
+    ("push/pop pairs", push_pop_pairs),
+    ("branches", branches),
+    ("loop rotation", loop_rotation),
+    ("dead code", dead_code),
+    ("unused labels", unused_labels),
+];
+
+fn run_passes(commands: &mut Vec<Command>, counts: &mut [usize]) {
+    loop {
+        let mut changed = false;
+        for (count, (_, pass)) in counts.iter_mut().zip(PASSES.iter()) {
+            let n = pass(commands);
+            *count += n;
+            changed |= n > 0;
+        }
+        if !changed {
+            break;
+        }
+    }
+}
+
+fn report(
+    before: usize,
+    after: usize,
+    counts: &[usize],
+    extra: Option<(&'static str, usize)>,
+) -> Report {
+    Report {
+        commands_before: before,
+        commands_after: after,
+        passes: PASSES
+            .iter()
+            .zip(counts.iter())
+            .map(|((name, _), count)| (*name, *count))
+            .chain(extra)
+            .collect(),
+    }
+}
+
+/// Runs the peephole passes over the commands of one file to a fixed point.
+pub fn optimize(commands: &[Command]) -> (Vec<Command>, Report) {
+    let mut commands = commands.to_vec();
+    let mut counts = [0; PASSES.len()];
+    let before = commands.len();
+    run_passes(&mut commands, &mut counts);
+    let report = report(before, commands.len(), &counts, None);
+    (commands, report)
+}
+
+/// Optimizes every file of a program and then drops the functions that are
+/// unreachable from `Sys.init`.
+pub fn optimize_program(files: &[Vec<Command>]) -> (Vec<Vec<Command>>, Report) {
+    let mut files = files.to_vec();
+    let mut counts = [0; PASSES.len()];
+    let before = files.iter().map(|commands| commands.len()).sum();
+    for commands in files.iter_mut() {
+        run_passes(commands, &mut counts);
+    }
+    let removed = unused_functions(&mut files);
+    let after = files.iter().map(|commands| commands.len()).sum();
+    let report = report(before, after, &counts, Some(("unused functions", removed)));
+    (files, report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::code::parse;
+
+    fn to_commands(vm: &str) -> Vec<Command> {
+        parse(&vm.lines().collect::<Vec<_>>()).unwrap()
+    }
+
+    #[test]
+    fn test_constant_folding() {
+        let program = to_commands("push constant 2\npush constant 3\nadd\npush constant 4\nsub\npush local 0\nnot\nnot\npush constant 7\nneg\npush constant 1\ngt\npush constant 0\nnot");
+        let (optimized, report) = optimize(&program);
+        assert_eq!(
+            optimized,
+            to_commands("push constant 1\npush local 0\npush constant 0\npush constant 0\nnot")
+        );
+        assert_eq!(report.saved(), 9);
+
+        // -32768 > 1 depends on how the comparison overflows.
+        let program = to_commands("push constant 32767\nnot\npush constant 1\ngt");
+        assert_eq!(optimize(&program).0, program);
+    }
+
+    #[test]
+    fn test_branches_and_dead_code() {
+        let program = to_commands("function Main.main 0\npush local 0\npop local 0\npush constant 0\nif-goto A\npush constant 0\nnot\nif-goto B\nlabel A\npush constant 1\nlabel B\ngoto C\nlabel C\ngoto D\npush constant 2\nlabel D\npush constant 3\nreturn\npush constant 4");
+        let (optimized, _) = optimize(&program);
+        assert_eq!(
+            optimized,
+            to_commands("function Main.main 0\npush constant 3\nreturn")
+        );
+    }
+
+    #[test]
+    fn test_loop_rotation() {
+        let program = to_commands("function Main.main 0\nlabel S\npush local 0\npush constant 5\nlt\nnot\nif-goto E\npush local 0\npush constant 1\nadd\npop local 0\ngoto S\nlabel E\npush constant 0\nreturn");
+        let (optimized, _) = optimize(&program);
+        assert_eq!(
+            optimized,
+            to_commands("function Main.main 0\ngoto S\nlabel S_BODY\npush local 0\npush constant 1\nadd\npop local 0\nlabel S\npush local 0\npush constant 5\nlt\nif-goto S_BODY\npush constant 0\nreturn")
+        );
+
+        // `while (true)` loses its test altogether.
+        let program = to_commands("function Main.main 0\nlabel S\npush constant 0\nnot\nnot\nif-goto E\ncall Main.f 0\npop temp 0\ngoto S\nlabel E\npush constant 0\nreturn");
+        let (optimized, _) = optimize(&program);
+        assert_eq!(
+            optimized,
+            to_commands("function Main.main 0\nlabel S\ncall Main.f 0\npop temp 0\ngoto S")
+        );
+
+        // `while (x)` exits only when x is -1, since `not x` is nonzero for
+        // any other nonzero x, so it must keep its `not`.
+        let program = to_commands("function Main.main 0\nlabel S\npush local 0\nnot\nif-goto E\npush local 0\npush constant 1\nadd\npop local 0\ngoto S\nlabel E\npush constant 0\nreturn");
+        assert_eq!(optimize(&program).0, program);
+
+        let program = to_commands("function Main.main 0\nlabel S\npush local 0\npush constant 5\nlt\npush local 1\nand\nnot\nif-goto E\ncall Main.f 0\npop temp 0\ngoto S\nlabel E\npush constant 0\nreturn");
+        assert_eq!(optimize(&program).0, program);
+    }
+
+    #[test]
+    fn test_is_boolean() {
+        let condition =
+            to_commands("push local 0\npush constant 5\nlt\npush constant 0\nnot\nor\nnot");
+        assert!(is_boolean(&condition));
+        assert!(is_boolean(&to_commands("call Main.f 2\npush local 0\neq")));
+        assert!(!is_boolean(&to_commands("push constant 1")));
+        assert!(!is_boolean(&to_commands("push local 0\npush local 1\nand")));
+        assert!(!is_boolean(&to_commands(
+            "push local 0\npush constant 0\nnot\nand"
+        )));
+    }
+
+    #[test]
+    fn test_unused_functions() {
+        let sys = to_commands("function Sys.init 0\ncall Main.main 0\nlabel HALT\ngoto HALT");
+        let main = to_commands("function Main.main 0\ncall Main.used 0\nreturn\nfunction Main.unused 0\ncall Main.used 0\nreturn\nfunction Main.used 0\npush constant 0\nreturn");
+        let (optimized, report) = optimize_program(&[main.clone(), sys.clone()]);
+        assert_eq!(optimized[0], [&main[..3], &main[6..]].concat());
+        assert_eq!(optimized[1], sys);
+        assert_eq!(report.passes.last(), Some(&("unused functions", 3)));
+
+        // Without Sys.init every function may be called from outside.
+        let (optimized, _) = optimize_program(std::slice::from_ref(&main));
+        assert_eq!(optimized[0], main);
+    }
+}
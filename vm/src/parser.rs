@@ -0,0 +1,217 @@
+use assembler::diagnostic::{Diagnostic, Located, Span};
+
+use super::command::Command;
+
+/// A `//` comment. Comments of the form `// @name value` are annotations,
+/// which let tools attach data to the commands that follow, such as the Jack
+/// source line a compiler generated them from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Comment {
+    Text(String),
+    Annotation(String, String),
+}
+
+impl Comment {
+    /// Parses the text after `//`.
+    pub fn parse(s: &str) -> Self {
+        let s = s.trim();
+        if let Some(annotation) = s.strip_prefix('@') {
+            let (name, value) = annotation
+                .split_once(char::is_whitespace)
+                .unwrap_or((annotation, ""));
+            if !name.is_empty() {
+                return Comment::Annotation(name.to_string(), value.trim().to_string());
+            }
+        }
+        Comment::Text(s.to_string())
+    }
+
+    pub fn dump(&self) -> String {
+        match self {
+            Comment::Text(text) if text.is_empty() => "//".to_string(),
+            Comment::Text(text) => format!("// {}", text),
+            Comment::Annotation(name, value) if value.is_empty() => format!("// @{}", name),
+            Comment::Annotation(name, value) => format!("// @{} {}", name, value),
+        }
+    }
+}
+
+/// One line of a `.vm` file: a command, a comment, both or neither.
+///
+/// Parsed lines remember their text, so `dump` writes them back byte for
+/// byte unless the command or comment has been changed since.
+#[derive(Debug, Clone, Default)]
+pub struct Line {
+    pub command: Option<Command>,
+    pub comment: Option<Comment>,
+    text: Option<String>,
+}
+
+impl PartialEq for Line {
+    fn eq(&self, other: &Self) -> bool {
+        self.command == other.command && self.comment == other.comment
+    }
+}
+
+impl Eq for Line {}
+
+impl Line {
+    pub fn command(command: Command) -> Self {
+        Self {
+            command: Some(command),
+            ..Self::default()
+        }
+    }
+
+    pub fn comment(comment: Comment) -> Self {
+        Self {
+            comment: Some(comment),
+            ..Self::default()
+        }
+    }
+
+    pub fn dump(&self) -> String {
+        if let Some(text) = &self.text {
+            if parse_line(text, 1).is_ok_and(|line| line.item == *self) {
+                return text.clone();
+            }
+        }
+        match (&self.command, &self.comment) {
+            (Some(command), Some(comment)) => format!("{} {}", command.dump(), comment.dump()),
+            (Some(command), None) => command.dump(),
+            (None, Some(comment)) => comment.dump(),
+            (None, None) => String::new(),
+        }
+    }
+}
+
+/// Parses a whole `.vm` file, keeping blank lines and comments so that
+/// `dump` can write it back out. Each line is located at its command, or at
+/// its comment when it has none, and every bad line is reported.
+pub fn parse(source: &str) -> Result<Vec<Located<Line>>, Vec<Diagnostic>> {
+    let mut lines = Vec::new();
+    let mut diagnostics = Vec::new();
+    for (i, text) in source.split_inclusive('\n').enumerate() {
+        match parse_line(text.strip_suffix('\n').unwrap_or(text), i + 1) {
+            Ok(line) => lines.push(line),
+            Err(diagnostic) => diagnostics.push(diagnostic),
+        }
+    }
+    if diagnostics.is_empty() {
+        Ok(lines)
+    } else {
+        Err(diagnostics)
+    }
+}
+
+fn parse_line(text: &str, number: usize) -> Result<Located<Line>, Diagnostic> {
+    let (code, comment) = match text.split_once("//") {
+        Some((code, comment)) => (code, Some(comment)),
+        None => (text, None),
+    };
+    let trimmed = code.trim();
+    let span = if trimmed.is_empty() {
+        let col = text.len() - text.trim_start().len() + 1;
+        Span::new(number, col, text.trim().len())
+    } else {
+        let col = code.len() - code.trim_start().len() + 1;
+        Span::new(number, col, trimmed.len())
+    };
+
+    let command = if trimmed.is_empty() {
+        None
+    } else {
+        let command =
+            Command::parse(trimmed).map_err(|e| Diagnostic::new(e.to_string(), span, text))?;
+        Some(command)
+    };
+    let line = Line {
+        command,
+        comment: comment.map(Comment::parse),
+        text: Some(text.to_string()),
+    };
+    Ok(Located::new(line, span))
+}
+
+/// The commands of parsed lines, without the comments.
+pub fn commands(lines: Vec<Located<Line>>) -> Vec<Located<Command>> {
+    lines
+        .into_iter()
+        .filter_map(|line| {
+            let span = line.span;
+            line.item.command.map(|command| Located::new(command, span))
+        })
+        .collect()
+}
+
+/// Writes lines back out as a `.vm` file, ending every line with a newline.
+pub fn dump<'a>(lines: impl IntoIterator<Item = &'a Line>) -> String {
+    lines.into_iter().map(|line| line.dump() + "\n").collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::Segment;
+
+    #[test]
+    fn test_round_trip() {
+        let text = "// Computes 7 + 8\n// @source Main.jack:3 do Output.printInt(7 + 8);\npush constant 7\npush constant 8 // second\n\nadd\n// @generated\n";
+        let lines = parse(text).unwrap();
+        assert_eq!(dump(lines.iter().map(|line| &line.item)), text);
+
+        assert_eq!(
+            lines[1].item.comment,
+            Some(Comment::Annotation(
+                "source".to_string(),
+                "Main.jack:3 do Output.printInt(7 + 8);".to_string()
+            ))
+        );
+        assert_eq!(
+            lines[3].item,
+            Line {
+                command: Some(Command::Push(Segment::Constant, 8)),
+                comment: Some(Comment::Text("second".to_string())),
+                ..Line::default()
+            }
+        );
+        assert_eq!(
+            lines[6].item,
+            Line::comment(Comment::Annotation("generated".to_string(), String::new()))
+        );
+
+        let commands = commands(lines);
+        assert_eq!(commands.len(), 3);
+        assert_eq!(commands[2].item, Command::Add);
+        assert_eq!(commands[2].span, Span::new(6, 1, 3));
+    }
+
+    #[test]
+    fn test_round_trip_keeps_spacing() {
+        let text = "  push   constant 7\t//  seven \r\n\t\nadd//sum\n//   @source  Main.jack:3\n";
+        let mut lines = parse(text).unwrap();
+        assert_eq!(dump(lines.iter().map(|line| &line.item)), text);
+        assert_eq!(
+            lines[0].item.comment,
+            Some(Comment::Text("seven".to_string()))
+        );
+        assert_eq!(lines[0].span, Span::new(1, 3, 17));
+
+        lines[0].item.command = Some(Command::Push(Segment::Constant, 8));
+        lines[2].item.comment = None;
+        assert_eq!(
+            dump(lines.iter().map(|line| &line.item)),
+            "push constant 8 // seven\n\t\nadd\n//   @source  Main.jack:3\n"
+        );
+    }
+
+    #[test]
+    fn test_reports_bad_lines() {
+        let errors =
+            parse("push constant 1\n  push argument 1 garbage\nadd\nbogus // x").unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].span, Span::new(2, 3, 23));
+        assert!(errors[0].message.contains("garbage"));
+        assert_eq!(errors[1].span, Span::new(4, 1, 5));
+    }
+}
@@ -0,0 +1,226 @@
+use std::fs;
+use std::path::Path;
+
+use assembler::command::Command;
+use assembler::diagnostic::Diagnostics;
+
+use super::code::{check, parse_located, prelude, translate_indexed, Mode};
+use super::optimizer::optimize_program;
+use super::source_map::{Mapper, SourceMap};
+
+/// One `.vm` file of a program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Source {
+    /// File stem, which names the file's statics (`Main.0`, `Main.1`, ...).
+    pub name: String,
+    /// Path shown in diagnostics.
+    pub filename: String,
+    pub lines: Vec<String>,
+}
+
+impl Source {
+    pub fn new(name: &str, filename: &str, text: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            filename: filename.to_string(),
+            lines: text.lines().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let name = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or_else(|| anyhow::anyhow!("Invalid file name: {}", path.display()))?;
+        let text = fs::read_to_string(path)?;
+        Ok(Self::new(name, &path.display().to_string(), &text))
+    }
+}
+
+/// Reads a `.vm` file, or every `.vm` file in a directory ordered by name.
+pub fn load(path: &Path) -> anyhow::Result<Vec<Source>> {
+    if path.is_file() {
+        return Ok(vec![Source::read(path)?]);
+    }
+    if !path.is_dir() {
+        anyhow::bail!("{} is not a file or directory", path.display());
+    }
+
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension().is_some_and(|e| e == "vm") {
+            paths.push(path);
+        }
+    }
+    if paths.is_empty() {
+        anyhow::bail!("No .vm files in {}", path.display());
+    }
+    paths.sort();
+    paths.iter().map(|path| Source::read(path)).collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Options {
+    /// Start with `bootstrap_call`, which needs a `Sys.init`.
+    pub bootstrap: bool,
+    pub mode: Mode,
+    /// Run `optimize_program` over the VM commands before translating them.
+    pub optimize: bool,
+}
+
+/// Translates every source into one Hack program, reporting the problems in
+/// all of them rather than stopping at the first bad file.
+///
+/// The program starts with `code::prelude`, which holds the bootstrap and,
+/// with `Mode::Shared`, the runtime.
+pub fn translate_program(
+    sources: &[Source],
+    options: Options,
+) -> Result<Vec<Command>, Vec<Diagnostics>> {
+    translate_program_mapped(sources, options).map(|(program, _)| program)
+}
+
+/// Like `translate_program`, but also maps the ROM addresses of the result
+/// back to the VM commands. The map refers to the program as translated, so
+/// it no longer applies once the Hack code is optimized.
+pub fn translate_program_mapped(
+    sources: &[Source],
+    options: Options,
+) -> Result<(Vec<Command>, SourceMap), Vec<Diagnostics>> {
+    let mut program = prelude(options.bootstrap.then_some(256), options.mode);
+
+    let mut files = Vec::new();
+    let mut lines = Vec::new();
+    let mut errors = Vec::new();
+    for source in sources.iter() {
+        let text = source.lines.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+        let checked = parse_located(&text).and_then(|located| {
+            check(&located, &text)?;
+            Ok(located)
+        });
+        let located = match checked {
+            Ok(located) => located,
+            Err(diagnostics) => {
+                errors.push(Diagnostics::new(&source.filename, diagnostics));
+                continue;
+            }
+        };
+
+        lines.push(
+            located
+                .iter()
+                .map(|c| Some(c.span.line))
+                .collect::<Vec<_>>(),
+        );
+        files.push(located.into_iter().map(|c| c.item).collect::<Vec<_>>());
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    if options.optimize {
+        files = optimize_program(&files).0;
+        lines = files
+            .iter()
+            .map(|commands| vec![None; commands.len()])
+            .collect();
+    }
+
+    let mut mapper = Mapper::new();
+    for command in program.iter() {
+        mapper.skip(command);
+    }
+    for ((source, commands), lines) in sources.iter().zip(files.iter()).zip(lines.iter()) {
+        translate_indexed(commands, Some(&source.name), options.mode, |i, command| {
+            mapper.record(i, &command);
+            program.push(command);
+            Ok(())
+        })
+        .expect("checked programs always translate");
+        mapper.finish_file(&source.filename, commands, lines);
+    }
+
+    Ok((program, mapper.finish()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::code::bootstrap;
+    use assembler::code::assemble_with_symbols;
+
+    const SYS: &str = "function Sys.init 0\npush constant 8\npop static 0\ncall Main.main 0\nlabel HALT\ngoto HALT";
+    const MAIN: &str = "function Main.main 0\npush static 0\npop static 1\npush constant 0\nreturn";
+
+    #[test]
+    fn test_statics_are_per_file() {
+        let sources = [
+            Source::new("Main", "Main.vm", MAIN),
+            Source::new("Sys", "Sys.vm", SYS),
+        ];
+        for mode in [Mode::Inline, Mode::Shared] {
+            let options = Options {
+                bootstrap: true,
+                mode,
+                ..Default::default()
+            };
+            let program = translate_program(&sources, options).unwrap();
+            assert_eq!(&program[..4], &bootstrap(256)[..4]);
+
+            let (_, symbols) = assemble_with_symbols(&program).unwrap();
+            let statics = symbols
+                .variables()
+                .filter(|(name, _)| name.ends_with(".0") || name.ends_with(".1"))
+                .map(|(name, _)| name)
+                .collect::<Vec<_>>();
+            assert_eq!(statics, vec!["Main.0", "Main.1", "Sys.0"]);
+        }
+    }
+
+    #[test]
+    fn test_source_map() {
+        let sources = [
+            Source::new("Main", "Main.vm", MAIN),
+            Source::new("Sys", "Sys.vm", SYS),
+        ];
+        let options = Options {
+            bootstrap: true,
+            ..Default::default()
+        };
+        let (program, source_map) = translate_program_mapped(&sources, options).unwrap();
+        let (binary, symbols) = assemble_with_symbols(&program).unwrap();
+
+        let main = symbols.get("Main.main").unwrap();
+        assert_eq!(
+            source_map.lookup(main).unwrap().to_string(),
+            "`function Main.main 0` in Main.vm:1 (Main.main)"
+        );
+        assert_eq!(source_map.lookup(0), None);
+        let last = source_map.entries().last().unwrap();
+        assert_eq!(last.addresses.end as usize, binary.len());
+        assert_eq!(last.to_string(), "`goto HALT` in Sys.vm:6 (Sys.init)");
+
+        let options = Options {
+            optimize: true,
+            ..options
+        };
+        let (_, source_map) = translate_program_mapped(&sources, options).unwrap();
+        assert!(source_map
+            .entries()
+            .iter()
+            .all(|entry| entry.line.is_none()));
+    }
+
+    #[test]
+    fn test_reports_every_file() {
+        let sources = [
+            Source::new("Main", "Main.vm", "function Main.main 0\ngoto NOWHERE"),
+            Source::new("Sys", "Sys.vm", "function Sys.init 0\npush temp 9\nbogus"),
+        ];
+        let errors = translate_program(&sources, Options::default()).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].filename, "Main.vm");
+        assert_eq!(errors[1].items[0].span.line, 3);
+    }
+}
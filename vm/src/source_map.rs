@@ -0,0 +1,236 @@
+use std::fmt;
+use std::ops::Range;
+
+use assembler::command::Command;
+
+use super::command::Command as VmCommand;
+
+/// The Hack instructions produced by one VM command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub addresses: Range<u16>,
+    pub file: String,
+    /// Line in `file`, unknown once the optimizer has rewritten the commands.
+    pub line: Option<usize>,
+    pub function: Option<String>,
+    pub command: String,
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "`{}` in {}", self.command, self.file)?;
+        if let Some(line) = self.line {
+            write!(f, ":{}", line)?;
+        }
+        if let Some(function) = &self.function {
+            write!(f, " ({})", function)?;
+        }
+        Ok(())
+    }
+}
+
+/// Maps ROM addresses of a translated program back to the VM commands they
+/// came from. Bootstrap and runtime code has no entries.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    entries: Vec<Entry>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an entry, which must start at or after the end of the last one.
+    pub fn push(&mut self, entry: Entry) {
+        debug_assert!(self
+            .entries
+            .last()
+            .is_none_or(|last| last.addresses.end <= entry.addresses.start));
+        self.entries.push(entry);
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// Finds the VM command that `pc` belongs to.
+    pub fn lookup(&self, pc: u16) -> Option<&Entry> {
+        let i = self
+            .entries
+            .partition_point(|entry| entry.addresses.end <= pc);
+        self.entries
+            .get(i)
+            .filter(|entry| entry.addresses.contains(&pc))
+    }
+
+    /// Text form: one `START END FILE[:LINE] FUNCTION COMMAND` line per entry,
+    /// with `-` for a missing function. Fields are separated by tabs, so file
+    /// names and commands may contain spaces.
+    pub fn dump(&self) -> String {
+        self.entries
+            .iter()
+            .map(|entry| {
+                let location = match entry.line {
+                    Some(line) => format!("{}:{}", entry.file, line),
+                    None => entry.file.clone(),
+                };
+                format!(
+                    "0x{:04x}\t0x{:04x}\t{}\t{}\t{}",
+                    entry.addresses.start,
+                    entry.addresses.end,
+                    location,
+                    entry.function.as_deref().unwrap_or("-"),
+                    entry.command
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let mut map = Self::new();
+        for line in s.lines() {
+            if line.trim().is_empty() || line.trim_start().starts_with("//") {
+                continue;
+            }
+
+            let fields = line.splitn(5, '\t').collect::<Vec<_>>();
+            if fields.len() != 5 {
+                anyhow::bail!("Invalid source map entry: {}", line);
+            }
+            let start = parse_address(fields[0])?;
+            let end = parse_address(fields[1])?;
+            if end < start
+                || map
+                    .entries
+                    .last()
+                    .is_some_and(|last| last.addresses.end > start)
+            {
+                anyhow::bail!("Overlapping source map entry: {}", line);
+            }
+            let location = fields[2]
+                .rsplit_once(':')
+                .map(|(file, number)| (file, number.parse::<usize>()));
+            let (file, line_number) = match location {
+                Some((file, Ok(number))) => (file, Some(number)),
+                _ => (fields[2], None),
+            };
+            map.entries.push(Entry {
+                addresses: start..end,
+                file: file.to_string(),
+                line: line_number,
+                function: Some(fields[3])
+                    .filter(|function| *function != "-")
+                    .map(String::from),
+                command: fields[4].to_string(),
+            });
+        }
+        Ok(map)
+    }
+}
+
+/// Builds a `SourceMap` while a program is emitted one Hack command at a
+/// time, keeping track of the ROM address the next command lands on.
+#[derive(Debug, Default)]
+pub struct Mapper {
+    source_map: SourceMap,
+    address: u16,
+    /// Addresses taken by each VM command of the current file so far.
+    pending: Vec<(usize, Range<u16>)>,
+}
+
+impl Mapper {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts `command` without mapping it, as for bootstrap and runtime code.
+    pub fn skip(&mut self, command: &Command) {
+        if takes_rom(command) {
+            self.address += 1;
+        }
+    }
+
+    /// Records that `command` came from the VM command at `index` of the
+    /// current file. Consecutive commands from the same one share an entry.
+    pub fn record(&mut self, index: usize, command: &Command) {
+        if !takes_rom(command) {
+            return;
+        }
+        match self.pending.last_mut() {
+            Some((i, addresses)) if *i == index && addresses.end == self.address => {
+                addresses.end += 1;
+            }
+            _ => self.pending.push((index, self.address..self.address + 1)),
+        }
+        self.address += 1;
+    }
+
+    /// Turns what was recorded since the last call into entries for `file`,
+    /// whose VM commands are `commands`, on lines `lines`.
+    pub fn finish_file(&mut self, file: &str, commands: &[VmCommand], lines: &[Option<usize>]) {
+        let mut function = None;
+        let functions = commands
+            .iter()
+            .map(|command| {
+                if let VmCommand::Function(name, _) = command {
+                    function = Some(name.clone());
+                }
+                function.clone()
+            })
+            .collect::<Vec<_>>();
+        for (index, addresses) in self.pending.drain(..) {
+            self.source_map.push(Entry {
+                addresses,
+                file: file.to_string(),
+                line: lines[index],
+                function: functions[index].clone(),
+                command: commands[index].dump(),
+            });
+        }
+    }
+
+    pub fn finish(self) -> SourceMap {
+        self.source_map
+    }
+}
+
+fn takes_rom(command: &Command) -> bool {
+    !matches!(command, Command::L(_) | Command::Directive(_))
+}
+
+fn parse_address(s: &str) -> anyhow::Result<u16> {
+    let address = match s.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16)?,
+        None => s.parse::<u16>()?,
+    };
+    Ok(address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_and_lookup() {
+        let text = "0x0004\t0x000a\tMain.vm:3\tMain.main\tpush constant 7\n0x000a\t0x003a\tMain.vm:4\tMain.main\tcall Main.fibonacci 1\n0x0040\t0x0042\tMy Games/Sys.vm\t-\tgoto HALT";
+        let map = SourceMap::parse(text).unwrap();
+        assert_eq!(map.dump(), text);
+
+        assert_eq!(map.lookup(3), None);
+        assert_eq!(
+            map.lookup(10).unwrap().to_string(),
+            "`call Main.fibonacci 1` in Main.vm:4 (Main.main)"
+        );
+        assert_eq!(map.lookup(0x3a), None);
+        assert_eq!(
+            map.lookup(0x41).unwrap().to_string(),
+            "`goto HALT` in My Games/Sys.vm"
+        );
+
+        assert!(SourceMap::parse("0x0004\t0x000a\tMain.vm:3\tMain.main").is_err());
+        assert!(SourceMap::parse("0x0004 0x000a Main.vm:3 Main.main push constant 7").is_err());
+        assert!(SourceMap::parse("8\t9\tA.vm\t-\tadd\n4\t10\tA.vm\t-\tadd").is_err());
+    }
+}